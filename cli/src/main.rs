@@ -2,11 +2,21 @@
 
 use std::error::Error;
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 use clap::Parser;
 
+use dcore::batch::Batch;
+use dcore::daemon::{ControlRequest, ControlResponse, Daemon, DaemonOptions};
 use dcore::document::{Document, DocumentNewOptions};
+use dcore::event_log_store::load_resources;
+use dcore::fsck::fsck;
+use dcore::projection::Projection;
+use dcore::search::SearchIndex;
+use dcore::sync_bundle::BundleSync;
+use dcore::sync_git::GitSync;
+use dcore::sync_libp2p::Node;
 use dcore::Identity;
 
 #[derive(clap::Parser)]
@@ -28,13 +38,27 @@ enum DcoreSubCommands {
     DocumentCreate(DocumentCreateArgs),
     DocumentClone(DocumentCloneArgs),
     DocumentSync(DocumentSyncArgs),
+    DocumentCreateHub(DocumentCreateHubArgs),
+    DocumentExportBundle(DocumentExportBundleArgs),
+    DocumentImportBundle(DocumentImportBundleArgs),
+    DocumentQuery(DocumentQueryArgs),
+    DocumentSearch(DocumentSearchArgs),
+    DocumentFsck(DocumentFsckArgs),
 
     ResourceListAll(ResourceListAllArgs),
     ResourceCat(ResourceCatArgs),
     ResourceSet(ResourceSetArgs),
     ResourceAdd(ResourceAddArgs),
+    ResourceSetSchema(ResourceSetSchemaArgs),
+    ResourceApply(ResourceApplyArgs),
+    ResourceJsonPatch(ResourcePatchArgs),
+    ResourceMergePatch(ResourcePatchArgs),
 
     ConfigSetDeviceName(ConfigSetDeviceNameArgs),
+    ConfigAddPeer(ConfigAddPeerArgs),
+    ConfigAddHook(ConfigAddHookArgs),
+
+    Node(NodeArgs),
 }
 
 fn main() {
@@ -46,13 +70,27 @@ fn main() {
         DcoreSubCommands::DocumentCreate(args) => document_create(args),
         DcoreSubCommands::DocumentClone(args) => document_clone(args),
         DcoreSubCommands::DocumentSync(args) => document_sync(args),
+        DcoreSubCommands::DocumentCreateHub(args) => document_create_hub(args),
+        DcoreSubCommands::DocumentExportBundle(args) => document_export_bundle(args),
+        DcoreSubCommands::DocumentImportBundle(args) => document_import_bundle(args),
+        DcoreSubCommands::DocumentQuery(args) => document_query(args),
+        DcoreSubCommands::DocumentSearch(args) => document_search(args),
+        DcoreSubCommands::DocumentFsck(args) => document_fsck(args),
 
         DcoreSubCommands::ResourceListAll(args) => resource_list_all(args),
         DcoreSubCommands::ResourceCat(args) => resource_cat(args),
         DcoreSubCommands::ResourceSet(args) => resource_set(args),
         DcoreSubCommands::ResourceAdd(args) => resource_add(args),
+        DcoreSubCommands::ResourceSetSchema(args) => resource_set_schema(args),
+        DcoreSubCommands::ResourceApply(args) => resource_apply(args),
+        DcoreSubCommands::ResourceJsonPatch(args) => resource_patch(args, false),
+        DcoreSubCommands::ResourceMergePatch(args) => resource_patch(args, true),
 
         DcoreSubCommands::ConfigSetDeviceName(args) => config_set_device_name(args),
+        DcoreSubCommands::ConfigAddPeer(args) => config_add_peer(args),
+        DcoreSubCommands::ConfigAddHook(args) => config_add_hook(args),
+
+        DcoreSubCommands::Node(args) => node(args),
 
 
     };
@@ -131,7 +169,7 @@ struct DocumentCreateArgs {
     #[clap(short, long)]
     document_name: String,
 
-    /// device name
+    /// device name, a unique one is generated if left out
     #[clap(long)]
     device_name: Option<String>,
 
@@ -160,6 +198,7 @@ fn document_create(args: DocumentCreateArgs) -> Result<(), Box<dyn Error>> {
         directory: PathBuf::from(&args.document_name),
         name: args.document_name.clone(),
         identity_fingerprint: String::from(&args.user_id_fingerprint),
+        gpg_home: None,
     };
     let mut doc = Document::new(doc_init_options).expect("Failed to create document");
 
@@ -168,7 +207,7 @@ fn document_create(args: DocumentCreateArgs) -> Result<(), Box<dyn Error>> {
             println!("Setting device name to {}", device_name);
             doc.config_set_local_device(&device_name).expect("Failed to set device name");
         }
-        None => {}
+        None => println!("Generated the device name {}", doc.config_get_local_device()?),
     }
 
     let public_key = {
@@ -209,6 +248,10 @@ fn resource_list_all(args: ResourceListAllArgs) -> Result<(), Box<dyn Error>> {
     let directory = PathBuf::from(&args.document_path);
     let name = directory.file_name().unwrap().to_str().unwrap().to_string();
     println!("List all resources of document with name:  {}.", &name);
+    if let Some(response) = daemon_request(&directory, ControlRequest::ListResources) {
+        print_daemon_response(response);
+        return Ok(());
+    }
 
     let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
         keyring_home_dir: args.keyring_home,
@@ -220,6 +263,7 @@ fn resource_list_all(args: ResourceListAllArgs) -> Result<(), Box<dyn Error>> {
         directory,
         name,
         identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
     };
 
     // todo: we need to be able to load the doc without the identity
@@ -262,6 +306,11 @@ fn resource_cat(args: ResourceCatArgs) -> Result<(), Box<dyn Error>> {
     let directory = PathBuf::from(&args.document_path);
     let name = directory.file_name().unwrap().to_str().unwrap().to_string();
     println!("List all resources of document with name:  {}.", &name);
+    let request = ControlRequest::Cat { resource: args.resource_name.clone() };
+    if let Some(response) = daemon_request(&directory, request) {
+        print_daemon_response(response);
+        return Ok(());
+    }
 
     let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
         keyring_home_dir: args.keyring_home,
@@ -273,6 +322,7 @@ fn resource_cat(args: ResourceCatArgs) -> Result<(), Box<dyn Error>> {
         directory,
         name,
         identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
     };
 
     // todo: we need to be able to load the doc without the identity
@@ -325,6 +375,15 @@ fn resource_set(args: ResourceSetArgs) -> Result<(), Box<dyn Error>> {
         "Set a property with key \"{}\" to value \"{}\" for resource {}.",
         &args.key, &args.value, &args.resource_name
     );
+    let request = ControlRequest::Set {
+        resource: args.resource_name.clone(),
+        key: args.key.clone(),
+        value: args.value.clone(),
+    };
+    if let Some(response) = daemon_request(&directory, request) {
+        print_daemon_response(response);
+        return Ok(());
+    }
 
     let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
         keyring_home_dir: None,
@@ -336,6 +395,7 @@ fn resource_set(args: ResourceSetArgs) -> Result<(), Box<dyn Error>> {
         directory,
         name,
         identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
     };
 
     // todo: we need to be able to load the doc without the identity
@@ -369,6 +429,11 @@ struct ResourceAddArgs {
 fn resource_add(args: ResourceAddArgs) -> Result<(), Box<dyn Error>> {
     let directory = PathBuf::from(&args.document_path);
     let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+    let request = ControlRequest::AddResource { resource: args.resource_name.clone() };
+    if let Some(response) = daemon_request(&directory, request) {
+        print_daemon_response(response);
+        return Ok(());
+    }
 
     let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
         keyring_home_dir: None,
@@ -380,6 +445,7 @@ fn resource_add(args: ResourceAddArgs) -> Result<(), Box<dyn Error>> {
         directory,
         name,
         identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
     };
 
     // todo: we need to be able to load the doc without the identity
@@ -392,6 +458,186 @@ fn resource_add(args: ResourceAddArgs) -> Result<(), Box<dyn Error>> {
     Ok(())
 }
 
+/// Set the JSON Schema of a resource, updates that do not match it are refused
+///
+/// dcore resource-set-schema -u 1234 -d ./my-doc -r tasks -s ./tasks.schema.json
+#[derive(clap::Parser)]
+struct ResourceSetSchemaArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Name of the resource
+    #[clap(short, long)]
+    resource_name: String,
+
+    /// Path to the JSON Schema file
+    #[clap(short, long)]
+    schema_path: String,
+}
+
+fn resource_set_schema(args: ResourceSetSchemaArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+    let schema: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string(&args.schema_path).expect("Failed to read the schema file"),
+    )
+    .expect("The schema file is not valid JSON");
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+
+    let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.load().expect("Failed to load document");
+    if let Err(e) = doc.set_resource_schema(&args.resource_name, &schema) {
+        eprintln!("Failed to set the schema: {}", e);
+        std::process::exit(1);
+    }
+    println!("Set the schema of resource {}.", &args.resource_name);
+
+    Ok(())
+}
+
+/// Apply a list of operations with one signed commit per resource
+///
+/// The file holds a JSON array of operations, e.g.
+/// [{"op": "set", "resource": "notes", "key": "meeting.place", "value": "Zurich"},
+///  {"op": "delete", "resource": "notes", "key": "draft"}]
+///
+/// dcore resource-apply -u 1234 -d ./my-doc -o ./operations.json
+#[derive(clap::Parser)]
+struct ResourceApplyArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Path to the file with the operations
+    #[clap(short, long)]
+    operations_path: String,
+}
+
+fn resource_apply(args: ResourceApplyArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+    let batch = Batch::from_json(
+        &std::fs::read_to_string(&args.operations_path).expect("Failed to read the operations file"),
+    )
+    .expect("The operations file is not a list of operations");
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+
+    let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.load().expect("Failed to load document");
+    match doc.apply_batch(&batch) {
+        Ok(updates) => {
+            let resources: Vec<String> = updates.into_keys().collect();
+            println!("Applied {} operations to {}.", batch.operations().len(), resources.join(", "));
+        }
+        Err(e) => {
+            eprintln!("Failed to apply the operations, nothing was changed: {}", e);
+            std::process::exit(1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Apply a JSON Patch (RFC 6902) or a JSON Merge Patch (RFC 7396) to a resource
+///
+/// The patch is read from the file, or from stdin if no file or - is given.
+///
+/// dcore resource-json-patch -u 1234 -d ./my-doc -r notes -p ./notes.patch.json
+/// dcore resource-merge-patch -u 1234 -d ./my-doc -r notes < ./notes.merge.json
+#[derive(clap::Parser)]
+struct ResourcePatchArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Name of the resource
+    #[clap(short, long)]
+    resource_name: String,
+
+    /// Path to the patch file
+    #[clap(short, long)]
+    patch_path: Option<String>,
+}
+
+fn resource_patch(args: ResourcePatchArgs, merge: bool) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+    let patch = match args.patch_path.as_deref() {
+        None | Some("-") => {
+            let mut patch = String::new();
+            std::io::Read::read_to_string(&mut std::io::stdin(), &mut patch)
+                .expect("Failed to read the patch from stdin");
+            patch
+        }
+        Some(path) => std::fs::read_to_string(path).expect("Failed to read the patch file"),
+    };
+    let patch: serde_json::Value = serde_json::from_str(&patch).expect("The patch is not valid JSON");
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+
+    let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.load().expect("Failed to load document");
+    let result = match merge {
+        true => doc.apply_merge_patch(&args.resource_name, &patch),
+        false => doc.apply_json_patch(&args.resource_name, &patch),
+    };
+    if let Err(e) = result {
+        eprintln!("Failed to apply the patch, nothing was changed: {}", e);
+        std::process::exit(1);
+    }
+    println!("Patched resource {}.", &args.resource_name);
+
+    Ok(())
+}
+
 /// Set the local device name
 ///
 /// dcore device-set
@@ -424,6 +670,7 @@ fn config_set_device_name(args: ConfigSetDeviceNameArgs) -> Result<(), Box<dyn E
         directory,
         name,
         identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
     };
 
     // todo: we need to be able to load the doc without the identity
@@ -432,10 +679,127 @@ fn config_set_device_name(args: ConfigSetDeviceNameArgs) -> Result<(), Box<dyn E
     doc.load().expect("Failed to load document");
     doc.config_set_local_device(&args.name)
         .expect("Failed to update resource");
+    doc.config_register_device().expect("Failed to register the device");
+    Ok(())
+}
+
+
+
+/// Register an executable that runs when resources change
+///
+/// The executable runs after local commits and after remote updates were applied, in the
+/// document directory. It gets the resource, author, device and changed key paths as JSON on stdin.
+///
+/// dcore config-add-hook -u FINGERPRINT -d ./doc --name notify --command ./hooks/notify.sh
+#[derive(clap::Parser)]
+struct ConfigAddHookArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Name of the hook
+    #[clap(short, long)]
+    name: String,
+
+    /// Path to the executable
+    #[clap(short, long)]
+    command: String,
+}
+
+fn config_add_hook(args: ConfigAddHookArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+
+    let doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.config_add_hook(&args.name, &args.command)
+        .expect("Failed to add the hook");
     Ok(())
 }
 
+/// Register the libp2p peer id of a device
+///
+/// Only registered peers can connect to the node of a document.
+/// Without options, the peer id of this device is registered and printed.
+///
+/// dcore config-add-peer -u FINGERPRINT -d ./doc --member FINGERPRINT --device laptop --peer-id 12D3KooW...
+#[derive(clap::Parser)]
+struct ConfigAddPeerArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Fingerprint of the member the device belongs to, default is the user
+    #[clap(long)]
+    member: Option<String>,
+
+    /// Device name, default is the local device
+    #[clap(long)]
+    device: Option<String>,
 
+    /// Peer id of the device, default is the one of the local device
+    #[clap(long)]
+    peer_id: Option<String>,
+}
+
+fn config_add_peer(args: ConfigAddPeerArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+
+    let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.load().expect("Failed to load document");
+
+    let member = args.member.unwrap_or_else(|| identity.fingerprint.clone());
+    let device = match args.device {
+        Some(device) => device,
+        None => doc.config_get_local_device().expect("Failed to get the device name"),
+    };
+    let peer_id = match args.peer_id {
+        Some(peer_id) => peer_id,
+        None => Node::load_or_create_keypair(&doc)
+            .expect("Failed to load the node key")
+            .public()
+            .to_peer_id()
+            .to_base58(),
+    };
+    doc.config_add_peer(&member, &device, &peer_id)
+        .expect("Failed to add peer");
+    println!("Added peer {} for device {} of {}", peer_id, device, member);
+    Ok(())
+}
 
 /// Clone a existing document
 ///
@@ -450,11 +814,11 @@ struct DocumentCloneArgs {
     #[clap(short, long)]
     document_path: String,
 
-    /// Path to the document directory
+    /// Device name, a unique one is generated if left out
     #[clap(long)]
-    device_name: String,
+    device_name: Option<String>,
 
-    /// Remote Document Url
+    /// Remote Document Url, either a git url or the path to a local hub
     #[clap(short, long)]
     remote_url: String,
 }
@@ -473,13 +837,17 @@ fn document_clone(args: DocumentCloneArgs) -> Result<(), Box<dyn Error>> {
         directory,
         name,
         identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
     };
 
     // todo: we need to be able to load the doc without the identity
     //       for the case that a user just want to list them without... makes only sense for unencrypted docs...
     let mut doc = Document::new(doc_init_option).expect("Failed to create document");
 
-    doc.config_set_local_device(&args.device_name).expect("Failed to update resource");
+    if let Some(device_name) = &args.device_name {
+        doc.config_set_local_device(device_name).expect("Failed to update resource");
+    }
+    println!("Cloning as device {}", doc.config_get_local_device()?);
 
     doc.clone(&args.remote_url).expect("Failed to clone document");
     Ok(())
@@ -487,6 +855,9 @@ fn document_clone(args: DocumentCloneArgs) -> Result<(), Box<dyn Error>> {
 
 /// Sync the document with the remote
 ///
+/// Ssh remotes authenticate with the keys of the ssh agent, or with the private key set in the
+/// git config of the document: git --git-dir ./doc/.data config dcore.sshKey ~/.ssh/id_ed25519
+///
 /// dcore document-sync
 #[derive(clap::Parser)]
 struct DocumentSyncArgs {
@@ -503,6 +874,10 @@ struct DocumentSyncArgs {
 fn document_sync(args: DocumentSyncArgs) -> Result<(), Box<dyn Error>> {
     let directory = PathBuf::from(&args.document_path);
     let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+    if let Some(response) = daemon_request(&directory, ControlRequest::Sync) {
+        print_daemon_response(response);
+        return Ok(());
+    }
 
     let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
         keyring_home_dir: None,
@@ -514,6 +889,7 @@ fn document_sync(args: DocumentSyncArgs) -> Result<(), Box<dyn Error>> {
         directory,
         name,
         identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
     };
 
     // todo: we need to be able to load the doc without the identity
@@ -521,6 +897,398 @@ fn document_sync(args: DocumentSyncArgs) -> Result<(), Box<dyn Error>> {
     let mut doc = Document::new(doc_init_option).expect("Failed to create document");
 
     doc.load().expect("Failed to load document");
-    doc.sync().expect("Failed to sync document");
+    let report = doc.sync().expect("Failed to sync document");
+    println!("{}", report);
+    // applies the fetched updates, which runs the hooks and refreshes the indexes
+    doc.load().expect("Failed to load document");
+    for quarantined in &doc.quarantined {
+        eprintln!(
+            "Quarantined update {} of {}: {}",
+            quarantined.position, quarantined.log, quarantined.reason
+        );
+    }
+
+    if !report.is_success() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Create a hub to share documents without a git server
+///
+/// The hub is an empty bare git repository, e.g. on a USB stick or a shared drive.
+/// Its path can be used as remote when setting up or cloning a document.
+///
+/// dcore document-create-hub --path /media/usb/hub.git
+#[derive(clap::Parser)]
+struct DocumentCreateHubArgs {
+    /// Path of the hub directory
+    #[clap(short, long)]
+    path: String,
+}
+
+fn document_create_hub(args: DocumentCreateHubArgs) -> Result<(), Box<dyn Error>> {
+    GitSync::create_hub(&PathBuf::from(&args.path)).expect("Failed to create hub");
+    println!("Created hub {}", GitSync::normalize_remote(&args.path));
+    Ok(())
+}
+
+/// Export the document to a bundle file for offline sync
+///
+/// dcore document-export-bundle --file ./doc.bundle --since ./last.bundle
+#[derive(clap::Parser)]
+struct DocumentExportBundleArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Path of the bundle file to write
+    #[clap(short, long)]
+    file: String,
+
+    /// An earlier bundle, only the changes since that bundle are exported
+    #[clap(long)]
+    since: Option<String>,
+}
+
+fn document_export_bundle(args: DocumentExportBundleArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+
+    let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.load().expect("Failed to load document");
+
+    let since = args.since.as_ref().map(PathBuf::from);
+    let report = BundleSync::export(&doc, &PathBuf::from(&args.file), since.as_deref())
+        .expect("Failed to export bundle");
+    println!("{}", report);
+    Ok(())
+}
+
+/// Import a bundle file into the document
+///
+/// The signatures of all new updates are verified before the logs are merged.
+///
+/// dcore document-import-bundle --file ./doc.bundle
+#[derive(clap::Parser)]
+struct DocumentImportBundleArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Path of the bundle file to read
+    #[clap(short, long)]
+    file: String,
+}
+
+fn document_import_bundle(args: DocumentImportBundleArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+
+    let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.load().expect("Failed to load document");
+
+    let report = BundleSync::import(&mut doc, &PathBuf::from(&args.file))
+        .expect("Failed to import bundle");
+    println!("{}", report);
+
+    if !report.is_success() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Query the contents of all resources with SQL
+///
+/// The query runs on the table `entries` with the columns resource, key_path, value, author
+/// and updated_at. The table is created and filled on the first query, after that it is kept
+/// up to date by every dcore command that changes or loads the document.
+///
+/// dcore document-query -u FINGERPRINT -d ./doc --query "SELECT resource, value FROM entries WHERE key_path = 'status'"
+#[derive(clap::Parser)]
+struct DocumentQueryArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// SQL query, only reading queries are allowed
+    #[clap(short, long)]
+    query: String,
+}
+
+fn document_query(args: DocumentQueryArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+    let projection_path = Projection::path(&directory.join(".data"));
+
+    if !projection_path.exists() {
+        let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+            keyring_home_dir: None,
+            fingerprint: args.user_id_fingerprint,
+        })
+            .expect("Failed to get identity with the provided fingerprint");
+
+        let doc_init_option = DocumentNewOptions {
+            directory,
+            name,
+            identity_fingerprint: identity.fingerprint.clone(),
+            gpg_home: None,
+        };
+        let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+        doc.enable_projection().expect("Failed to create the projection");
+    }
+
+    let projection = Projection::open_read_only(&projection_path).expect("Failed to open the projection");
+    for row in projection.query(&args.query).expect("Failed to run the query") {
+        println!("{}", row);
+    }
+    Ok(())
+}
+
+/// Search the strings and texts of all resources
+///
+/// Prints the resource, key path and a snippet per match, best matches first. The query uses
+/// the SQLite FTS5 syntax, e.g. budget, "exact phrase" or meet*. The index is created on the
+/// first search, after that it is kept up to date by every dcore command that changes or loads
+/// the document.
+///
+/// dcore document-search -u FINGERPRINT -d ./doc budget
+#[derive(clap::Parser)]
+struct DocumentSearchArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Search query
+    query: String,
+}
+
+fn document_search(args: DocumentSearchArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+    let search_index_path = SearchIndex::path(&directory.join(".data"));
+
+    let search_index = if search_index_path.exists() {
+        SearchIndex::open(&search_index_path).expect("Failed to open the search index")
+    } else {
+        let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+            keyring_home_dir: None,
+            fingerprint: args.user_id_fingerprint,
+        })
+            .expect("Failed to get identity with the provided fingerprint");
+
+        let doc_init_option = DocumentNewOptions {
+            directory,
+            name,
+            identity_fingerprint: identity.fingerprint.clone(),
+            gpg_home: None,
+        };
+        let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+        doc.enable_search_index().expect("Failed to create the search index");
+        doc.search_index.take().unwrap()
+    };
+
+    for hit in search_index.search(&args.query).expect("Failed to search") {
+        println!("{}\t{}\t{}", hit.resource, hit.key_path, hit.snippet);
+    }
+    Ok(())
+}
+
+/// Check the integrity of all event-logs of the document
+///
+/// Prints a JSON report with the problems found: unexpected commit trees, updates that do not
+/// decode, bad signatures, logs signed by another identity, merge commits and diverging heads of
+/// a device. Exits with 1 if there are problems.
+///
+/// dcore document-fsck -u FINGERPRINT -d ./doc
+#[derive(clap::Parser)]
+struct DocumentFsckArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+}
+
+fn document_fsck(args: DocumentFsckArgs) -> Result<(), Box<dyn Error>> {
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+    let mut doc = match Document::open_existing(doc_init_option) {
+        Ok(doc) => doc,
+        Err(e) => {
+            eprintln!("Failed to open document: {}", e);
+            std::process::exit(1);
+        }
+    };
+    // only the resources are read, hooks do not run and nothing is written. A broken log must
+    // not keep fsck from reporting it, without the config only our own identity is trusted though
+    match load_resources(&doc.event_log_store()) {
+        Ok(resources) => doc.resources = resources,
+        Err(e) => eprintln!("Failed to load document: {}", e),
+    }
+
+    let report = fsck(&doc).expect("Failed to check the document");
+    println!("{}", serde_json::to_string_pretty(&report).expect("Failed to print the report"));
+    if !report.is_ok() {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+/// Run a node that keeps documents open
+///
+/// The node syncs the documents with their peers over libp2p and with their git remote on an
+/// interval. While it runs, the resource and sync commands on its documents are sent to it.
+///
+/// dcore node -u FINGERPRINT -d ./doc1 -d ./doc2 --mdns
+#[derive(clap::Parser)]
+struct NodeArgs {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Paths to the document directories
+    #[clap(short, long, required = true)]
+    document_path: Vec<String>,
+
+    /// Address every document node listens on, keep port 0 for several documents
+    #[clap(long, default_value = "/ip4/0.0.0.0/tcp/0")]
+    listen: String,
+
+    /// Seconds between two git syncs, 0 to disable them
+    #[clap(long, default_value_t = 300)]
+    sync_interval: u64,
+
+    /// Discover peers on the local network
+    #[clap(long)]
+    mdns: bool,
+}
+
+fn node(args: NodeArgs) -> Result<(), Box<dyn Error>> {
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let mut documents = Vec::new();
+    for document_path in args.document_path {
+        let directory = PathBuf::from(&document_path);
+        let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+        let doc_init_option = DocumentNewOptions {
+            directory,
+            name,
+            identity_fingerprint: identity.fingerprint.clone(),
+            gpg_home: None,
+        };
+        let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+        doc.load().expect("Failed to load document");
+        documents.push(doc);
+    }
+
+    let options = DaemonOptions {
+        listen_on: args.listen.parse().expect("Invalid listen address"),
+        sync_interval: match args.sync_interval {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds)),
+        },
+        mdns: args.mdns,
+    };
+    Daemon::run(documents, options).expect("Node stopped");
+    Ok(())
+}
+
+/// Sends the request to a running node that has the document open.
+/// None if there is none, then the command works on the document itself.
+fn daemon_request(directory: &Path, request: ControlRequest) -> Option<ControlResponse> {
+    if !Daemon::is_running(directory) {
+        return None;
+    }
+    Some(Daemon::request(directory, &request).expect("Failed to send the request to the node"))
+}
+
+fn print_daemon_response(response: ControlResponse) {
+    match response {
+        ControlResponse::Resources { resources } => {
+            println!("Resources:");
+            for resource in resources {
+                println!("\t- {}", resource);
+            }
+        }
+        ControlResponse::Content { content } => {
+            println!("Resource Content:");
+            println!("{}", content);
+        }
+        ControlResponse::Done => {}
+        ControlResponse::Synced { report, success, quarantined } => {
+            println!("{}", report);
+            for quarantined in quarantined {
+                eprintln!("{}", quarantined);
+            }
+            if !success {
+                std::process::exit(1);
+            }
+        }
+        ControlResponse::Error { message } => {
+            eprintln!("{}", message);
+            std::process::exit(1);
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+use yrs::{Map, PrelimMap, Transaction};
+
+use crate::errors::Error;
+use crate::resource::insert_json;
+
+/// A change of a resource, the key has the same form as in `Document::update_resource_with_key_value`.
+///
+/// As JSON: `{"op": "set", "resource": "notes", "key": "meeting.place", "value": "Zurich"}` or
+/// `{"op": "delete", "resource": "notes", "key": "draft"}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Operation {
+    Set {
+        resource: String,
+        key: String,
+        value: serde_json::Value,
+    },
+    Delete {
+        resource: String,
+        key: String,
+    },
+}
+
+impl Operation {
+    pub fn resource(&self) -> &str {
+        match self {
+            Operation::Set { resource, .. } | Operation::Delete { resource, .. } => resource,
+        }
+    }
+}
+
+/// Changes of one or more resources that `Document::apply_batch` commits together, with one
+/// update and one signature per resource instead of one per change.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Batch {
+    operations: Vec<Operation>,
+}
+
+impl Batch {
+    pub fn new() -> Batch {
+        Batch::default()
+    }
+
+    /// Reads a JSON array of operations, see `Operation`.
+    pub fn from_json(json: &str) -> Result<Batch, Error> {
+        let operations = serde_json::from_str(json)
+            .map_err(|e| Error::DcoreError(format!("Invalid list of operations: {}", e)))?;
+        Ok(Batch { operations })
+    }
+
+    pub fn set(&mut self, resource: &str, key: &str, value: serde_json::Value) -> &mut Batch {
+        self.operations.push(Operation::Set {
+            resource: resource.to_string(),
+            key: key.to_string(),
+            value,
+        });
+        self
+    }
+
+    pub fn delete(&mut self, resource: &str, key: &str) -> &mut Batch {
+        self.operations.push(Operation::Delete {
+            resource: resource.to_string(),
+            key: key.to_string(),
+        });
+        self
+    }
+
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.operations.is_empty()
+    }
+
+    /// The resources the batch changes, in the order they first appear.
+    pub fn resources(&self) -> Vec<&str> {
+        let mut resources: Vec<&str> = Vec::new();
+        for operation in &self.operations {
+            if !resources.contains(&operation.resource()) {
+                resources.push(operation.resource());
+            }
+        }
+        resources
+    }
+}
+
+fn split_key(key: &str) -> (Option<&str>, &str) {
+    match key.rsplit_once('.') {
+        Some((parent_keys, last_key)) => (Some(parent_keys), last_key),
+        None => (None, key),
+    }
+}
+
+/// Sets a JSON value at the key path in the `root` map, missing parent maps are created.
+pub(crate) fn set_json(transaction: &mut Transaction, key: &str, value: &serde_json::Value) {
+    let (parent_keys, last_key) = split_key(key);
+    let mut current_map = transaction.get_map("root");
+    for parent_key in parent_keys.iter().flat_map(|keys| keys.split('.')) {
+        let existing = current_map.get(parent_key).and_then(|value| value.to_ymap());
+        current_map = match existing {
+            Some(map) => map,
+            None => {
+                let next_map = PrelimMap::<i32>::from(HashMap::default());
+                current_map.insert(transaction, parent_key.to_owned(), next_map);
+                current_map.get(parent_key).unwrap().to_ymap().unwrap()
+            }
+        };
+    }
+    insert_json(transaction, &current_map, last_key, value);
+}
+
+/// Removes the key path from the `root` map. Returns false if there was nothing to remove.
+pub(crate) fn remove_key(transaction: &mut Transaction, key: &str) -> bool {
+    let (parent_keys, last_key) = split_key(key);
+    let mut current_map: Option<Map> = Some(transaction.get_map("root"));
+    for parent_key in parent_keys.iter().flat_map(|keys| keys.split('.')) {
+        current_map = current_map
+            .and_then(|map| map.get(parent_key))
+            .and_then(|value| value.to_ymap());
+    }
+    match current_map {
+        Some(map) => map.remove(transaction, last_key).is_some(),
+        None => false,
+    }
+}
+
+/// Applies the operations of the batch on `resource` in one transaction. Returns the keys of the
+/// delete operations that found nothing to remove.
+pub(crate) fn apply_operations(transaction: &mut Transaction, batch: &Batch, resource: &str) -> Vec<String> {
+    let mut missing = Vec::new();
+    for operation in batch.operations().iter().filter(|operation| operation.resource() == resource) {
+        match operation {
+            Operation::Set { key, value, .. } => set_json(transaction, key, value),
+            Operation::Delete { key, .. } => {
+                if !remove_key(transaction, key) {
+                    missing.push(key.clone());
+                }
+            }
+        }
+    }
+    missing
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::batch::{Batch, Operation};
+
+    #[test]
+    fn read_operations() {
+        let batch = Batch::from_json(
+            r#"[
+                {"op": "set", "resource": "notes", "key": "meeting.place", "value": "Zurich"},
+                {"op": "set", "resource": "tasks", "key": "open", "value": 3},
+                {"op": "delete", "resource": "notes", "key": "draft"}
+            ]"#,
+        )
+        .unwrap();
+
+        let mut expected = Batch::new();
+        expected
+            .set("notes", "meeting.place", serde_json::json!("Zurich"))
+            .set("tasks", "open", serde_json::json!(3))
+            .delete("notes", "draft");
+        assert_eq!(batch, expected);
+        assert_eq!(batch.resources(), vec!["notes", "tasks"]);
+        assert_eq!(
+            batch.operations()[2],
+            Operation::Delete {
+                resource: "notes".to_string(),
+                key: "draft".to_string()
+            }
+        );
+        assert!(Batch::from_json(r#"[{"op": "rename", "resource": "notes"}]"#).is_err());
+    }
+}
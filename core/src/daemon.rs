@@ -0,0 +1,399 @@
+use std::io::{BufRead, BufReader, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use async_std::io::{prelude::BufReadExt, BufReader as AsyncBufReader, WriteExt};
+use async_std::os::unix::net::{UnixListener, UnixStream as AsyncUnixStream};
+use futures::channel::{mpsc, oneshot};
+use futures::future::{self, FutureExt};
+use futures::StreamExt;
+use futures_timer::Delay;
+use libp2p::Multiaddr;
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
+use crate::sync_git::{GitSync, SyncReport};
+use crate::sync_libp2p::{Node, NodeEvent};
+use crate::Document;
+
+/// A request sent to a running daemon over its control socket, one JSON object per line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum ControlRequest {
+    ListResources,
+    Cat { resource: String },
+    Set { resource: String, key: String, value: String },
+    AddResource { resource: String },
+    Sync,
+}
+
+/// The answer of the daemon to a `ControlRequest`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "status", rename_all = "kebab-case")]
+pub enum ControlResponse {
+    Resources { resources: Vec<String> },
+    Content { content: String },
+    Done,
+    /// `success` is `SyncReport::is_success`, `quarantined` describes the updates that were not
+    /// applied because they make a resource invalid.
+    Synced {
+        report: String,
+        success: bool,
+        quarantined: Vec<String>,
+    },
+    Error { message: String },
+}
+
+/// Answers a request that a connection task passed to the node of its document.
+type Responder = oneshot::Sender<ControlResponse>;
+type RequestSender = mpsc::UnboundedSender<(ControlRequest, Responder)>;
+
+#[derive(Debug, Clone)]
+pub struct DaemonOptions {
+    /// Every document gets its own libp2p node listening on this address,
+    /// use port 0 when running several documents.
+    pub listen_on: Multiaddr,
+    /// How often the documents are synced with their git remote, None to never do it
+    pub sync_interval: Option<Duration>,
+    pub mdns: bool,
+}
+
+/// A long-running process that keeps documents open.
+///
+/// Each document runs a libp2p `Node`, so updates of peers are verified and persisted as they
+/// arrive, and is synced with its git remote on an interval. Other processes reach a document
+/// through the control socket in its `.data` directory, see `Daemon::request`.
+pub struct Daemon;
+
+impl Daemon {
+    /// The control socket of a document, `{document directory}/.data/node.sock`.
+    pub fn socket_path(document_directory: &Path) -> PathBuf {
+        document_directory.join(".data").join("node.sock")
+    }
+
+    /// Whether a daemon has the document open.
+    pub fn is_running(document_directory: &Path) -> bool {
+        UnixStream::connect(Self::socket_path(document_directory)).is_ok()
+    }
+
+    /// Sends a request to the daemon that has the document open.
+    pub fn request(document_directory: &Path, request: &ControlRequest) -> Result<ControlResponse, Error> {
+        let mut stream = UnixStream::connect(Self::socket_path(document_directory))?;
+        let mut line = serde_json::to_string(request).map_err(|e| Error::Other(e.to_string()))?;
+        line.push('\n');
+        stream.write_all(line.as_bytes())?;
+
+        let mut response = String::new();
+        BufReader::new(stream).read_line(&mut response)?;
+        serde_json::from_str(&response).map_err(|e| Error::Other(e.to_string()))
+    }
+
+    /// Runs the daemon until all documents stopped. A document that fails is reported and does
+    /// not stop the others, the error counts the documents that failed.
+    pub fn run(documents: Vec<Document>, options: DaemonOptions) -> Result<(), Error> {
+        async_std::task::block_on(async {
+            let runs = documents.into_iter().map(|document| {
+                let name = document.name.clone();
+                Self::run_document(document, &options).map(move |result| (name, result))
+            });
+            let mut failed = 0;
+            for (name, result) in future::join_all(runs).await {
+                if let Err(e) = result {
+                    eprintln!("Document {}: stopped: {}", name, e);
+                    failed += 1;
+                }
+            }
+            if failed > 0 {
+                return Err(Error::DcoreError(format!("{} document(s) stopped with an error.", failed)));
+            }
+            Ok(())
+        })
+    }
+
+    async fn run_document(document: Document, options: &DaemonOptions) -> Result<(), Error> {
+        let socket_path = PathBuf::from(document.repository.path()).join("node.sock");
+        // a socket left behind by a daemon that did not shut down cleanly
+        if socket_path.exists() {
+            if UnixStream::connect(&socket_path).is_ok() {
+                return Err(Error::DcoreError(format!(
+                    "A daemon is already running for {}.",
+                    document.name
+                )));
+            }
+            std::fs::remove_file(&socket_path)?;
+        }
+        let listener = UnixListener::bind(&socket_path).await?;
+        let (request_sender, mut requests) = mpsc::unbounded();
+        async_std::task::spawn(Self::accept(listener, request_sender, document.name.clone()));
+
+        let keypair = Node::load_or_create_keypair(&document)?;
+        let mut node = if options.mdns {
+            Node::with_mdns(document, keypair)?
+        } else {
+            Node::new(document, keypair)?
+        };
+        eprintln!("Document {}: peer id {}", node.document.name, node.peer_id());
+        node.listen_on(options.listen_on.clone())?;
+
+        let next_sync = |interval: Option<Duration>| match interval {
+            Some(interval) => Delay::new(interval).left_future(),
+            None => future::pending().right_future(),
+        };
+        let mut sync_timer = next_sync(options.sync_interval).fuse();
+        let (sync_sender, mut syncs) = mpsc::unbounded();
+        // the requests waiting for the running git sync, None if no sync is running
+        let mut sync_waiting: Option<Vec<Responder>> = None;
+
+        loop {
+            futures::select! {
+                event = node.next_event().fuse() => Self::log_event(&node.document, event?),
+                request = requests.next().fuse() => {
+                    if let Some((request, responder)) = request {
+                        match request {
+                            ControlRequest::Sync => match &mut sync_waiting {
+                                Some(waiting) => waiting.push(responder),
+                                None => {
+                                    Self::spawn_sync(&node.document, sync_sender.clone());
+                                    sync_waiting = Some(vec![responder]);
+                                }
+                            },
+                            request => {
+                                // the client may be gone already, nothing to do then
+                                let _ = responder.send(Self::handle_request(&mut node, request));
+                            }
+                        }
+                    }
+                }
+                synced = syncs.next().fuse() => {
+                    if let Some(result) = synced {
+                        let response = result
+                            .and_then(|report| Self::synced(&mut node.document, report))
+                            .unwrap_or_else(|e| ControlResponse::Error { message: e.to_string() });
+                        let waiting = sync_waiting.take().unwrap_or_default();
+                        if waiting.is_empty() {
+                            eprintln!("Document {}: git sync: {:?}", node.document.name, response);
+                        }
+                        for responder in waiting {
+                            let _ = responder.send(response.clone());
+                        }
+                    }
+                }
+                _ = sync_timer => {
+                    if sync_waiting.is_none() {
+                        Self::spawn_sync(&node.document, sync_sender.clone());
+                        sync_waiting = Some(Vec::new());
+                    }
+                    sync_timer = next_sync(options.sync_interval).fuse();
+                }
+            }
+        }
+    }
+
+    /// Accepts control connections and reads their requests in tasks of their own, so a client
+    /// that does not send its request does not hold up the node.
+    async fn accept(listener: UnixListener, requests: RequestSender, name: String) {
+        let mut connections = listener.incoming();
+        while let Some(connection) = connections.next().await {
+            match connection {
+                Ok(stream) => {
+                    let requests = requests.clone();
+                    let name = name.clone();
+                    async_std::task::spawn(async move {
+                        if let Err(e) = Self::serve(stream, requests).await {
+                            eprintln!("Document {}: control request failed: {}", name, e);
+                        }
+                    });
+                }
+                Err(e) => eprintln!("Document {}: could not accept a control connection: {}", name, e),
+            }
+        }
+    }
+
+    async fn serve(stream: AsyncUnixStream, requests: RequestSender) -> Result<(), Error> {
+        let mut line = String::new();
+        AsyncBufReader::new(&stream).read_line(&mut line).await?;
+        let response = match serde_json::from_str(&line) {
+            Ok(request) => {
+                let (responder, response) = oneshot::channel();
+                requests
+                    .unbounded_send((request, responder))
+                    .map_err(|_| Error::DcoreError("The document is not open anymore.".to_string()))?;
+                response
+                    .await
+                    .map_err(|_| Error::DcoreError("The document did not answer.".to_string()))?
+            }
+            Err(e) => ControlResponse::Error {
+                message: format!("Invalid request: {}", e),
+            },
+        };
+        let mut line = serde_json::to_string(&response).map_err(|e| Error::Other(e.to_string()))?;
+        line.push('\n');
+        (&stream).write_all(line.as_bytes()).await?;
+        Ok(())
+    }
+
+    /// Syncs with the git remote on a thread of its own, such that the node keeps serving peers
+    /// and requests meanwhile, see `GitSync::sync_reopened`. The fetched logs are loaded by the
+    /// node when the sync is done, see `synced`.
+    fn spawn_sync(document: &Document, synced: mpsc::UnboundedSender<Result<SyncReport, Error>>) {
+        let options = document.reopen_options();
+        async_std::task::spawn(async move {
+            let result = async_std::task::spawn_blocking(move || GitSync::sync_reopened(options)).await;
+            let _ = synced.unbounded_send(result);
+        });
+    }
+
+    /// Answers the requests that only need the open document. `Sync` talks to the git remote and
+    /// is run on a thread of its own by the node loop, see `spawn_sync`.
+    pub fn handle_request(node: &mut Node, request: ControlRequest) -> ControlResponse {
+        let result = match request {
+            ControlRequest::ListResources => {
+                let mut resources: Vec<String> = node.document.resources.keys().cloned().collect();
+                resources.sort();
+                Ok(ControlResponse::Resources { resources })
+            }
+            ControlRequest::Cat { resource } => match node.document.resources.get(&resource) {
+                Some(resource) => Ok(ControlResponse::Content {
+                    content: resource.get_content(),
+                }),
+                None => Err(Error::DcoreError(format!("Resource {} not found.", resource))),
+            },
+            ControlRequest::Set { resource, key, value } => {
+                if node.document.resources.contains_key(&resource) {
+                    node.document
+                        .update_resource_with_key_value(&resource, &key, &value)
+                        .map(|_| ControlResponse::Done)
+                } else {
+                    Err(Error::DcoreError(format!("Resource {} not found.", resource)))
+                }
+            }
+            ControlRequest::AddResource { resource } => node
+                .document
+                .add_resource(resource)
+                .map(|_| ControlResponse::Done),
+            ControlRequest::Sync => Err(Error::DcoreError(
+                "A sync is answered by the node loop once it is done.".to_string(),
+            )),
+        };
+        result.unwrap_or_else(|e| ControlResponse::Error {
+            message: e.to_string(),
+        })
+    }
+
+    /// Loads what a git sync fetched and answers with its report.
+    fn synced(document: &mut Document, report: SyncReport) -> Result<ControlResponse, Error> {
+        document.load()?;
+        let quarantined = document
+            .quarantined
+            .iter()
+            .map(|quarantined| {
+                format!(
+                    "Quarantined update {} of {}: {}",
+                    quarantined.position, quarantined.log, quarantined.reason
+                )
+            })
+            .collect();
+        Ok(ControlResponse::Synced {
+            report: report.to_string(),
+            success: report.is_success(),
+            quarantined,
+        })
+    }
+
+    fn log_event(document: &Document, event: NodeEvent) {
+        match event {
+            NodeEvent::Listening(address) => {
+                eprintln!("Document {}: listening on {}", document.name, address)
+            }
+            NodeEvent::Synced { peer, merged, rejected } => {
+                let commits: usize = merged.values().sum();
+                eprintln!("Document {}: synced with {}, {} new commit(s)", document.name, peer, commits);
+                for (log, reason) in rejected {
+                    eprintln!("Document {}: rejected {}: {}", document.name, log, reason);
+                }
+            }
+            NodeEvent::SyncFailed { peer, reason } => {
+                eprintln!("Document {}: sync with {} failed: {}", document.name, peer, reason)
+            }
+            NodeEvent::Updated { peer, log, .. } => {
+                eprintln!("Document {}: update of {} from {}", document.name, log, peer)
+            }
+            NodeEvent::UpdateRejected { peer, log, reason } => {
+                eprintln!("Document {}: rejected update of {} from {}: {}", document.name, log, peer, reason)
+            }
+            NodeEvent::PeerSubscribed(peer) => {
+                eprintln!("Document {}: {} subscribed to updates", document.name, peer)
+            }
+            NodeEvent::PeerRejected(peer) => {
+                eprintln!("Document {}: disconnected unknown peer {}", document.name, peer)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use libp2p::identity;
+
+    use crate::daemon::{ControlRequest, ControlResponse, Daemon};
+    use crate::sync_libp2p::Node;
+    use crate::test_utils::create_test_document;
+
+    #[test]
+    fn handle_control_requests() {
+        let doc = create_test_document("./.test/daemon/handle_control_requests/");
+        let mut node = Node::new(doc, identity::Keypair::generate_ed25519()).unwrap();
+
+        let add = ControlRequest::AddResource {
+            resource: "test".to_string(),
+        };
+        assert_eq!(Daemon::handle_request(&mut node, add), ControlResponse::Done);
+        let set = ControlRequest::Set {
+            resource: "test".to_string(),
+            key: "entry".to_string(),
+            value: "1234".to_string(),
+        };
+        assert_eq!(Daemon::handle_request(&mut node, set), ControlResponse::Done);
+
+        let response = Daemon::handle_request(&mut node, ControlRequest::ListResources);
+        assert_eq!(
+            response,
+            ControlResponse::Resources {
+                resources: vec!["config".to_string(), "test".to_string()]
+            }
+        );
+        let cat = ControlRequest::Cat {
+            resource: "test".to_string(),
+        };
+        assert_eq!(
+            Daemon::handle_request(&mut node, cat),
+            ControlResponse::Content {
+                content: "{entry: 1234}".to_string()
+            }
+        );
+
+        // syncs are run on a thread by the node loop, never inline
+        assert!(matches!(
+            Daemon::handle_request(&mut node, ControlRequest::Sync),
+            ControlResponse::Error { .. }
+        ));
+
+        // the requests travel as one JSON object per line
+        let line = serde_json::to_string(&ControlRequest::Sync).unwrap();
+        assert_eq!(line, r#"{"command":"sync"}"#);
+        let synced = ControlResponse::Synced {
+            report: "Pushed logs:".to_string(),
+            success: false,
+            quarantined: Vec::new(),
+        };
+        let line = serde_json::to_string(&synced).unwrap();
+        assert_eq!(serde_json::from_str::<ControlResponse>(&line).unwrap(), synced);
+        assert!(line.contains(r#""success":false"#));
+        let cat = ControlRequest::Cat {
+            resource: "missing".to_string(),
+        };
+        assert!(matches!(Daemon::handle_request(&mut node, cat), ControlResponse::Error { .. }));
+    }
+}
@@ -1,17 +1,26 @@
 use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 
 use git2::{BranchType, Repository, RepositoryInitOptions};
+use rand::RngCore;
 use yrs::updates::decoder::Decode;
 use yrs::{Map, PrelimMap, Update};
 
+use crate::batch::{apply_operations, remove_key, set_json, Batch};
 use crate::document_utils::DocumentUtils;
 use crate::errors::Error;
+use crate::event_log_store::{load_resources, load_resources_with, EventLogStore, GitEventLogStore, Log};
+use crate::format;
+use crate::patch;
 use crate::gpg::{Gpg, Key};
+use crate::hooks::{CommittedUpdate, Hooks, ResourceChange};
+use crate::projection::{changed_key_paths, leaves, AuthorTracker, Projection};
 use crate::resource::Resource;
+use crate::search::SearchIndex;
 use crate::Identity;
-use crate::sync_git::GitSync;
+use crate::sync_git::{GitSync, SyncReport};
+use crate::validation::{JsonSchemaValidator, QuarantinedUpdate, Validator, Validators};
 
 pub struct Document {
     pub name: String,
@@ -19,6 +28,12 @@ pub struct Document {
     pub identity: Identity,
     pub gpg: Gpg,
     pub resources: HashMap<String, Resource>,
+    pub projection: Option<Projection>,
+    pub search_index: Option<SearchIndex>,
+    pub hooks: Hooks,
+    pub validators: Validators,
+    /// The updates of other devices that were not applied by the last `load`, see `Validators`.
+    pub quarantined: Vec<QuarantinedUpdate>,
 }
 
 
@@ -33,7 +48,7 @@ impl Document {
         let mut resource = Resource::new(&p0);
         let update = resource.set_resource_meta(&p0).unwrap();
 
-        let _ = &self.commit_update(&update, &resource);
+        self.commit_update(&update, &resource, Vec::new())?;
         self.resources.insert(p0, resource);
 
         Ok(())
@@ -50,38 +65,173 @@ impl Document {
         Ok(())
     }
 
+    /// The name of this machine in the names of its logs. Set by `Document::new`, see
+    /// `generate_device_id`.
     pub fn config_get_local_device(&self) -> Result<String, Error> {
         let config =  self.repository.config().unwrap().snapshot().unwrap();
 
         match config.get_str("user.device") {
             Ok(device) => Ok(device.to_string()),
-            Err(_) => Ok("device-0".to_string()),
+            Err(_) => Err(Error::DcoreError(
+                "No device name configured, set one with config-set-device-name.".to_string(),
+            )),
         }
     }
 
-    pub fn config_set_remote(&mut self, remote: &str) -> Result<(), Error> {
+    /// The devices a member registered in the config resource: device -> time of registration.
+    pub fn config_get_devices(&self, fingerprint: &str) -> Result<BTreeMap<String, String>, Error> {
+        let mut devices = BTreeMap::new();
+        if !self.resources.contains_key("config") {
+            return Ok(devices);
+        }
+        let config = self.get_config()?;
+        let member_devices = config
+            .get(fingerprint)
+            .and_then(|member| member.to_ymap())
+            .and_then(|member| member.get("devices"))
+            .and_then(|member_devices| member_devices.to_ymap());
+        if let Some(member_devices) = member_devices {
+            for (device, registered) in member_devices.iter() {
+                devices.insert(device.to_string(), registered.to_string());
+            }
+        }
+        Ok(devices)
+    }
+
+    /// Registers the local device of our identity in the config resource, such that other
+    /// machines of the identity do not pick the same name. Does nothing if we are not a member.
+    pub fn config_register_device(&mut self) -> Result<(), Error> {
         let fingerprint = self.identity.get_fingerprint();
-        let key = format!("{}.remote", fingerprint);
-        self.update_resource_with_key_value("config", key.as_str(), remote).unwrap();
+        let device = self.config_get_local_device()?;
+        if !self.resources.contains_key("config")
+            || !self.config_get_members()?.contains_key(&fingerprint)
+            || self.config_get_devices(&fingerprint)?.contains_key(&device)
+        {
+            return Ok(());
+        }
+        let key = format!("{}.devices.{}", fingerprint, device);
+        let registered = chrono::Utc::now().to_rfc3339();
+        self.update_resource_with_key_value("config", key.as_str(), registered.as_str())?;
         Ok(())
     }
 
-    pub fn clone(self, remote: &String) -> Result<(), Error> {
-        self.repository.remote_set_url("origin", remote)?;
-        GitSync::clone(self, remote);
+    /// Fails if another machine of our identity already uses the local device name, i.e. it
+    /// registered the name or there is a log of it.
+    fn check_device_name_unused(&self) -> Result<(), Error> {
+        let fingerprint = self.identity.get_fingerprint();
+        let device = self.config_get_local_device()?;
+        let suffix = format!("/{}/{}", fingerprint, device);
+        let has_log = self
+            .event_log_store()
+            .logs()?
+            .iter()
+            .any(|log| log.reference().ends_with(&suffix));
+        if has_log || self.config_get_devices(&fingerprint)?.contains_key(&device) {
+            return Err(Error::DcoreError(format!(
+                "The device name {} is already used by another machine of {}. \
+                 Choose another one with config-set-device-name, or leave it out to generate one.",
+                device, fingerprint
+            )));
+        }
+        Ok(())
+    }
+
+    /// The id of the document, which is the same in all its clones. `init` stores a random id in
+    /// the config resource, the first one we see is kept in the git config (`dcore.documentId`),
+    /// see `remember_id`, so later changes of the config do not change it. None as long as there
+    /// is no config.
+    pub fn get_id(&self) -> Option<String> {
+        if let Ok(id) = self.repository.config().ok()?.get_string(DOCUMENT_ID_KEY) {
+            return Some(id);
+        }
+        self.recorded_id().or_else(|| self.first_config_commit())
+    }
+
+    /// The id in the `_resource_meta` of the config resource.
+    fn recorded_id(&self) -> Option<String> {
+        self.resources
+            .get("config")
+            .and_then(|config| config.store.transact().get_map("_resource_meta").get("document_id"))
+            .map(|id| id.to_string())
+    }
+
+    /// Keeps the id recorded in the config in the git config, unless an id is kept already.
+    fn remember_id(&self) -> Result<(), Error> {
+        let mut git_config = self.repository.config()?;
+        if git_config.get_string(DOCUMENT_ID_KEY).is_ok() {
+            return Ok(());
+        }
+        if let Some(id) = self.recorded_id() {
+            git_config.set_str(DOCUMENT_ID_KEY, &id)?;
+        }
         Ok(())
     }
 
-    pub fn sync(self) -> Result<(), Error> {
-        GitSync::sync( self)?;
+    /// The id of documents created before `init` recorded one: the oid of the oldest root commit
+    /// of the config logs.
+    fn first_config_commit(&self) -> Option<String> {
+        let mut first_commit: Option<(i64, git2::Oid)> = None;
+        for glob in ["refs/local/config/*", "refs/origin/config/*"] {
+            for log in self.repository.references_glob(glob).ok()?.flatten() {
+                let head = match log.target() {
+                    Some(head) => head,
+                    None => continue,
+                };
+                let mut revwalk = self.repository.revwalk().ok()?;
+                revwalk.push(head).ok()?;
+                for oid in revwalk.flatten() {
+                    let commit = self.repository.find_commit(oid).ok()?;
+                    if commit.parent_count() > 0 {
+                        continue;
+                    }
+                    let candidate = (commit.time().seconds(), oid);
+                    if first_commit.map_or(true, |first| candidate < first) {
+                        first_commit = Some(candidate);
+                    }
+                }
+            }
+        }
+        first_commit.map(|(_, oid)| oid.to_string())
+    }
+
+    /// The remote is either a git url or the path to a hub created with `GitSync::create_hub`.
+    pub fn config_set_remote(&mut self, remote: &str) -> Result<(), Error> {
+        let fingerprint = self.identity.get_fingerprint();
+        let key = format!("{}.remote", fingerprint);
+        let remote = GitSync::normalize_remote(remote);
+        self.update_resource_with_key_value("config", key.as_str(), remote.as_str())?;
         Ok(())
     }
+
+    /// Fetches the document from the remote and registers the local device, which must not be
+    /// used by another machine of our identity yet.
+    pub fn clone(mut self, remote: &String) -> Result<(), Error> {
+        let remote = GitSync::normalize_remote(remote);
+        self.repository.remote_set_url("origin", remote.as_str())?;
+        GitSync::clone(&self, remote.as_str())?;
+        self.load()?;
+        self.check_device_name_unused()?;
+        self.config_register_device()
+    }
+
+    pub fn sync(&self) -> Result<SyncReport, Error> {
+        GitSync::sync(self)
+    }
 }
 
 
 
 unsafe impl Send for Document {}
 
+/// A device name for a new document or clone, e.g. `device-3f9a0c27d41e5b86`. It is random, such
+/// that two machines of the same identity never write to the same logs.
+pub fn generate_device_id() -> String {
+    format!("device-{:016x}", rand::thread_rng().next_u64())
+}
+
+/// The git config key that caches the document id, see `Document::get_id`.
+const DOCUMENT_ID_KEY: &str = "dcore.documentId";
+
 pub struct DocumentInitOptionsIdentity {
     pub fingerprint: String,
 }
@@ -95,6 +245,9 @@ pub struct DocumentNewOptions {
     pub directory: PathBuf,
     pub name: String,
     pub identity_fingerprint: String,
+    /// The gpg home with the secret key of the identity, None for the one in `GNUPGHOME` or the
+    /// default one
+    pub gpg_home: Option<PathBuf>,
 }
 
 impl Document {
@@ -102,22 +255,85 @@ impl Document {
         let data_dir = PathBuf::from(options.directory).join("./.data");
         let repository = Repository::init_opts(&data_dir, &RepositoryInitOptions::new().bare(true))
             .map_err(|e| Error::GitError(e))?;
-        let mut gpg = Gpg::new();
+        // records the format of new documents and upgrades the ones written by older versions
+        format::open(&repository)?;
+        if repository.config()?.get_string("user.device").is_err() {
+            repository.config()?.set_str("user.device", &generate_device_id())?;
+        }
+        let mut gpg = Gpg::in_home(options.gpg_home.as_deref())?;
         let identity = Identity::from_fingerprint(&mut gpg, &options.identity_fingerprint).expect(
             ("Could not find the identity with the provided fingerprint ".to_string()
                 + &options.identity_fingerprint)
                 .as_str(),
         );
 
+        let projection_path = Projection::path(repository.path());
+        let projection = if projection_path.exists() {
+            Some(Projection::open(&projection_path)?)
+        } else {
+            None
+        };
+        let search_index_path = SearchIndex::path(repository.path());
+        let search_index = if search_index_path.exists() {
+            Some(SearchIndex::open(&search_index_path)?)
+        } else {
+            None
+        };
+
         return Ok(Document {
             name: options.name,
             repository,
             identity,
             gpg,
             resources: HashMap::new(),
+            projection,
+            search_index,
+            hooks: Hooks::default(),
+            validators: Validators::default(),
+            quarantined: Vec::new(),
         });
     }
 
+    /// Opens a document as it is, e.g. to check it with `fsck`. Unlike `new` it fails if there is
+    /// no document in the directory, and neither upgrades the format nor sets a device name.
+    /// The projection and the search index are not opened.
+    pub fn open_existing(options: DocumentNewOptions) -> Result<Document, Error> {
+        let data_dir = options.directory.join(".data");
+        if !data_dir.is_dir() {
+            return Err(Error::DcoreError(format!(
+                "There is no document in {}.",
+                options.directory.display()
+            )));
+        }
+        let repository = Repository::open_bare(&data_dir)?;
+        format::check(&repository)?;
+        let mut gpg = Gpg::in_home(options.gpg_home.as_deref())?;
+        let identity = Identity::from_fingerprint(&mut gpg, &options.identity_fingerprint)?;
+
+        Ok(Document {
+            name: options.name,
+            repository,
+            identity,
+            gpg,
+            resources: HashMap::new(),
+            projection: None,
+            search_index: None,
+            hooks: Hooks::default(),
+            validators: Validators::default(),
+            quarantined: Vec::new(),
+        })
+    }
+
+    /// The options that open this document again, e.g. on a thread of its own.
+    pub fn reopen_options(&self) -> DocumentNewOptions {
+        DocumentNewOptions {
+            directory: self.repository.path().parent().map(PathBuf::from).unwrap_or_default(),
+            name: self.name.clone(),
+            identity_fingerprint: self.identity.get_fingerprint(),
+            gpg_home: self.identity.gpg_home().map(PathBuf::from),
+        }
+    }
+
     /// Frist call Document::new(...) then doc.init() to create the config resource
     pub fn init(self, fingerprint: &String, public_key: &String) -> Result<Document, Error> {
         if self.resources.contains_key("config") {
@@ -126,9 +342,19 @@ impl Document {
             ));
         }
         let mut resource = Resource::new(&String::from("config"));
+        let device = self.config_get_local_device()?;
+        let mut rng = rand::thread_rng();
+        let id = format!("{:016x}{:016x}", rng.next_u64(), rng.next_u64());
 
         let update = resource
             .add_local_update(|mut transaction| {
+                let resource_meta = transaction.get_map("_resource_meta");
+                resource_meta.insert(&mut transaction, "document_id".to_owned(), id.as_str());
+                resource_meta.insert(
+                    &mut transaction,
+                    format::RECORDED_VERSION_KEY.to_owned(),
+                    format::FORMAT_VERSION as f64,
+                );
                 let config_root = transaction.get_map("root");
 
                 let public_key = public_key.clone();
@@ -158,12 +384,18 @@ impl Document {
                     "alias".to_string(),
                     fingerprint.as_str().to_owned(),
                 );
+                id_map.insert(
+                    &mut transaction,
+                    "devices".to_string(),
+                    PrelimMap::<String>::from(HashMap::from([(device.clone(), chrono::Utc::now().to_rfc3339())])),
+                );
 
                 transaction
             })
             .unwrap();
 
-        let _ = &self.commit_update(&update, &resource);
+        self.commit_update(&update, &resource, leaves(&resource).into_keys().collect())?;
+        self.repository.config()?.set_str(DOCUMENT_ID_KEY, &id)?;
 
         let mut resources = HashMap::new();
         resources.insert("config".to_string(), resource);
@@ -172,98 +404,219 @@ impl Document {
             name: "name".to_string(),
             repository: self.repository,
             identity: self.identity,
-            gpg: Gpg::new(),
+            gpg: self.gpg,
             resources,
+            projection: self.projection,
+            search_index: self.search_index,
+            hooks: self.hooks,
+            validators: self.validators,
+            quarantined: self.quarantined,
         })
     }
 
-    fn commit_update(&self, update: &Vec<u8>, resource: &Resource) {
-        DocumentUtils::commit_update(&self, resource, update.to_owned())
-            .expect("TODO: panic message");
+    fn commit_update(&self, update: &Vec<u8>, resource: &Resource, touched_key_paths: Vec<String>) -> Result<(), Error> {
+        let head = DocumentUtils::commit_update(&self, resource, update.to_owned(), touched_key_paths)?;
+        self.hooks.committed(&CommittedUpdate {
+            resource: resource.name.clone(),
+            head,
+            update: update.to_owned(),
+        });
+        if let Some(projection) = &self.projection {
+            projection.refresh(resource, &self.identity.get_fingerprint())?;
+        }
+        if let Some(search_index) = &self.search_index {
+            search_index.refresh(resource)?;
+        }
+        self.hooks.local_change(self, resource);
+        Ok(())
+    }
+
+    /// Commits an update that was already applied to the resource, `previous` are the leaves of the
+    /// resource before. If the resource is invalid now, the update is not committed and the resource
+    /// is replayed from its logs again.
+    fn commit_local_update(
+        &mut self,
+        update: &Vec<u8>,
+        resource_name: &str,
+        previous: &BTreeMap<String, serde_json::Value>,
+    ) -> Result<(), Error> {
+        if let Err(reason) = self.validators.check(&self.resources[resource_name]) {
+            let (resource, _) = self.validators.replay_valid(&self.event_log_store(), resource_name)?;
+            self.resources.insert(resource_name.to_string(), resource);
+            return Err(Error::ValidationError(format!(
+                "The update makes {} invalid: {}",
+                resource_name, reason
+            )));
+        }
+        let resource = &self.resources[resource_name];
+        self.commit_update(update, resource, changed_key_paths(previous, &leaves(resource)))
     }
 
+    /// Replays the event-logs of all resources, see `load_resources`, quarantines the updates that
+    /// make a resource invalid, refreshes the projection and the search index, runs the hooks
+    /// for remote changes and keeps the document id, see `get_id`. Documents recorded by a newer
+    /// version of dcore are refused, see `format::check_recorded`.
     pub fn load(&mut self) -> Result<(), Error> {
+        let hooks_active = self.hooks.is_active(self);
+        let mut authors = AuthorTracker::default();
+        let mut resources = if self.projection.is_some() || hooks_active {
+            load_resources_with(&self.event_log_store(), &mut |resource, log| {
+                authors.applied(resource, log)
+            })?
+        } else {
+            load_resources(&self.event_log_store())?
+        };
+        self.quarantined = self
+            .validators
+            .quarantine_invalid(&self.event_log_store(), &mut resources)?;
+        if let Some(projection) = &self.projection {
+            for resource in resources.values() {
+                projection.refresh_loaded(resource, &authors)?;
+            }
+        }
+        if hooks_active {
+            self.hooks.loaded(self, &resources, &authors);
+        }
+        if let Some(search_index) = &self.search_index {
+            for resource in resources.values() {
+                search_index.refresh(resource)?;
+            }
+        }
+        self.resources.extend(resources);
+        if let Some(config) = self.resources.get("config") {
+            format::check_recorded(config)?;
+        }
+        self.remember_id()
+    }
 
-        // load all resources from the repository
-        // all the folders in the refs folder are resources
-
-        let mut resources = HashSet::new();
-        self
-            .repository
-            .references()
-            .map_err(|e| Error::GitError(e))
-            .unwrap()
-            .for_each(|log| {
-                let log_name = log.unwrap().name().unwrap().clone().to_string();
-                // refs/local/{name}
-                let resource_name = log_name.split("/").collect::<Vec<&str>>()[2].to_string();
-
-                if resource_name == "main" || resource_name == "master" {
-                    return;
-                }
-                resources.insert(resource_name);
-            });
+    /// Creates the SQL projection of the resources, see `Projection`, and fills it.
+    /// Applies an update of another device whose commit was already verified and stored in `log`,
+    /// e.g. a live update of a peer. Like `load`, the update is quarantined if it makes the
+    /// resource invalid, the projection and the search index are refreshed and the hooks run.
+    /// The config, which can add members, and resources we do not have yet are loaded completely.
+    pub fn apply_remote_update(&mut self, log: &Log, update: &[u8]) -> Result<(), Error> {
+        let resource = match self.resources.remove(&log.resource) {
+            Some(resource) if log.resource != "config" => resource,
+            Some(resource) => {
+                self.resources.insert(log.resource.clone(), resource);
+                return self.load();
+            }
+            None => return self.load(),
+        };
+        let mut resources = HashMap::new();
+        resources.insert(log.resource.clone(), resource);
+        let result = self.apply_remote_update_to(&mut resources, log, update);
+        // the resource is kept even if the update could not be applied
+        self.resources.extend(resources);
+        result
+    }
 
+    fn apply_remote_update_to(
+        &mut self,
+        resources: &mut HashMap<String, Resource>,
+        log: &Log,
+        update: &[u8],
+    ) -> Result<(), Error> {
+        let update = Update::decode_v2(update)
+            .map_err(|e| Error::Other(format!("Invalid update: {:?}", e)))?;
+        {
+            let mut transaction = resources[&log.resource].store.transact();
+            transaction.apply_update(update);
+            transaction.commit();
+        }
+        for quarantined in self.validators.quarantine_invalid(&self.event_log_store(), resources)? {
+            if !self.quarantined.contains(&quarantined) {
+                self.quarantined.push(quarantined);
+            }
+        }
 
-        for resource_name in resources {
-            //println!("Loading resource: {}", resource_name);
+        // only the keys the update changed differ from the projection and the hooks' last state
+        let mut authors = AuthorTracker::default();
+        authors.applied(&resources[&log.resource], log);
+        if let Some(projection) = &self.projection {
+            projection.refresh_loaded(&resources[&log.resource], &authors)?;
+        }
+        if self.hooks.is_active(self) {
+            self.hooks.loaded(self, resources, &authors);
+        }
+        if let Some(search_index) = &self.search_index {
+            search_index.refresh(&resources[&log.resource])?;
+        }
+        Ok(())
+    }
 
+    pub fn enable_projection(&mut self) -> Result<(), Error> {
+        if self.projection.is_none() {
+            self.projection = Some(Projection::open(&Projection::path(self.repository.path()))?);
+        }
+        self.load()
+    }
 
+    /// Creates the full-text index of the resources, see `SearchIndex`, and fills it.
+    pub fn enable_search_index(&mut self) -> Result<(), Error> {
+        if self.search_index.is_none() {
+            self.search_index = Some(SearchIndex::open(&SearchIndex::path(self.repository.path()))?);
+        }
+        self.load()
+    }
 
-            let mut resource_logs_head_oids = Vec::new();
+    /// Registers a callback that runs after local commits and after remote updates were loaded,
+    /// see `Hooks`.
+    pub fn register_hook(&mut self, callback: impl Fn(&ResourceChange) + Send + 'static) {
+        self.hooks.register(Box::new(callback));
+        self.hooks.remember(&self.resources);
+    }
 
-            for log in self.repository.references().unwrap() {
-                let log = log.unwrap();
-                let log_name = log.name().unwrap();
-                // refs/local/{name}
-                let this_resource_name = log_name.split("/").collect::<Vec<&str>>()[2].to_string();
-                if this_resource_name == resource_name {
-                    resource_logs_head_oids.push(log.target().unwrap());
-                }
-            }
+    /// Registers a callback that gets every update committed to the logs of this identity and
+    /// device, whichever method committed it, e.g. to publish it, see `Node`.
+    pub fn register_commit_hook(&mut self, callback: impl Fn(&CommittedUpdate) + Send + 'static) {
+        self.hooks.register_commit(Box::new(callback));
+    }
 
-            let mut resource = Resource::new(&resource_name);
-            let revwalk = &mut self
-                .repository
-                .revwalk()
-                .map_err(|e| Error::GitError(e))
-                .unwrap();
-            revwalk
-                .set_sorting(git2::Sort::REVERSE)
-                .map_err(|e| Error::GitError(e))
-                .unwrap();
-            let mut t = resource.store.transact();
-            for oid in resource_logs_head_oids {
-                revwalk.push(oid).map_err(|e| Error::GitError(e)).unwrap();
-                //println!("log: {}", oid.to_string());
-                let updates = revwalk
-                    .flat_map(|id| self.repository.find_commit(id.unwrap()))
-                    .map(|commit| commit.tree().unwrap())
-                    .map(|tree| {
-                        tree.get_name("update")
-                            .unwrap()
-                            .to_object(&self.repository)
-                            .unwrap()
-                    })
-                    .map(|object| object.peel_to_blob().unwrap())
-                    .map(|blob| blob.content().to_vec())
-                    .map(|content| Update::decode_v2(content.as_slice()).unwrap());
-
-                // merge_updates leads to an nondeterministic result
-                //let update = Update::merge_updates(updates);
-                //t.apply_update(update);
-
-                for update in updates{
-                    t.apply_update(update);
-                }
-            }
+    /// Registers an executable that runs after local commits and after remote updates were loaded,
+    /// it gets the change as JSON on stdin, see `Hooks`.
+    pub fn config_add_hook(&self, name: &str, command: &str) -> Result<(), Error> {
+        if !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+            return Err(Error::Other(
+                "Hook name can only contain a-z, A-Z, 0-9, -".to_string(),
+            ));
+        }
+        self.repository
+            .config()?
+            .set_str(&format!("hook.{}.command", name), command)?;
+        self.hooks.remember(&self.resources);
+        Ok(())
+    }
 
-            t.commit();
-            self.resources.insert(resource_name, resource);
+    /// Registers a validator of a resource, local updates that make the resource invalid are
+    /// refused and remote ones are quarantined by `load`, see `Validators`.
+    pub fn register_validator(&mut self, resource_name: &str, validator: impl Validator + 'static) {
+        self.validators.register(resource_name, Box::new(validator));
+    }
 
-        }
+    /// Stores a JSON Schema in `_resource_meta.schema` of a resource, so that it is shared with
+    /// all members. Fails if the current content does not match the schema.
+    /// Returns the committed yrs update.
+    pub fn set_resource_schema(&mut self, resource_name: &str, schema: &serde_json::Value) -> Result<Vec<u8>, Error> {
+        JsonSchemaValidator::new(schema)?;
+        let resource = self
+            .resources
+            .get_mut(resource_name)
+            .ok_or_else(|| Error::DcoreError(format!("Resource {} not found.", resource_name)))?;
+        let previous = leaves(resource);
+        let schema = schema.to_string();
+        let update = resource.add_local_update(|transaction| {
+            let resource_meta = transaction.get_map("_resource_meta");
+            resource_meta.insert(transaction, "schema".to_owned(), schema.as_str());
+            transaction
+        })?;
+        self.commit_local_update(&update, resource_name, &previous)?;
+        Ok(update)
+    }
 
-        Ok(())
+    /// The event-logs of the document, kept in its git repository.
+    pub fn event_log_store(&self) -> GitEventLogStore<'_> {
+        GitEventLogStore::new(&self.repository)
     }
 
     /*
@@ -317,51 +670,186 @@ impl Document {
     /// the key can either be the root or a sub key that are separated by a dot
     /// e.g. key = "config.users.fingerprint", value = "1234"
     /// { "config" : { "users" : { "fingerprint" : "1234" } } }
+    /// Returns the committed yrs update.
     pub fn update_resource_with_key_value(
         &mut self,
         resource_name: &str,
         key: &str,
         value: &str,
-    ) -> Result<(), Error> {
+    ) -> Result<Vec<u8>, Error> {
+        self.update_resource_with_json(resource_name, key, &serde_json::Value::String(value.to_string()))
+    }
+
+    /// Sets a JSON value at the key path, the key has the same form as in
+    /// `update_resource_with_key_value`. Returns the committed yrs update.
+    pub fn update_resource_with_json(
+        &mut self,
+        resource_name: &str,
+        key: &str,
+        value: &serde_json::Value,
+    ) -> Result<Vec<u8>, Error> {
+        let resource = self
+            .resources
+            .get_mut(resource_name)
+            .ok_or_else(|| Error::DcoreError(format!("Resource {} not found.", resource_name)))?;
+        let previous = leaves(resource);
+        let update = resource.add_local_update(|transaction| {
+            set_json(transaction, key, value);
+            transaction
+        })?;
+
+        self.commit_local_update(&update, resource_name, &previous)?;
+        Ok(update)
+    }
+
+    /// Applies a JSON Patch (RFC 6902) to the content of a resource in one local update.
+    /// Returns the committed yrs update.
+    pub fn apply_json_patch(&mut self, resource_name: &str, patch: &serde_json::Value) -> Result<Vec<u8>, Error> {
+        let content = self.get_resource_json(resource_name)?;
+        let patched = patch::apply_json_patch(&content, patch)?;
+        self.write_resource_json(resource_name, &content, &patched)
+    }
+
+    /// Applies a JSON Merge Patch (RFC 7396) to the content of a resource in one local update.
+    /// Returns the committed yrs update.
+    pub fn apply_merge_patch(&mut self, resource_name: &str, patch: &serde_json::Value) -> Result<Vec<u8>, Error> {
+        let content = self.get_resource_json(resource_name)?;
+        let patched = patch::apply_merge_patch(&content, patch);
+        self.write_resource_json(resource_name, &content, &patched)
+    }
+
+    fn get_resource_json(&self, resource_name: &str) -> Result<serde_json::Value, Error> {
+        self.resources
+            .get(resource_name)
+            .map(|resource| resource.get_json())
+            .ok_or_else(|| Error::DcoreError(format!("Resource {} not found.", resource_name)))
+    }
+
+    /// Changes the content of a resource from `content` to `patched`, only the entries that
+    /// differ are written, see `patch::write_map_diff`.
+    fn write_resource_json(
+        &mut self,
+        resource_name: &str,
+        content: &serde_json::Value,
+        patched: &serde_json::Value,
+    ) -> Result<Vec<u8>, Error> {
+        let (from, to) = match (content, patched) {
+            (serde_json::Value::Object(from), serde_json::Value::Object(to)) => (from, to),
+            _ => {
+                return Err(Error::DcoreError(format!(
+                    "The content of {} has to stay an object.",
+                    resource_name
+                )))
+            }
+        };
         let resource = self.resources.get_mut(resource_name).unwrap();
-        println!("{}",&resource.get_content());
-        let update = resource
-            .add_local_update(|mut transaction| { // what are the "|" for here?
-                println!("{}",&key);
-                let mut key_parts = key.split("."); // what parts does a key have?
-
-                let root_map = transaction.get_map("root");
-                let mut current_map = root_map.clone();
-
-                while let Some(key) = key_parts.next() {
-                    if key_parts.clone().peekable().peek().is_some() {
-                        // there will be a next key
-                        // check if the current key already exists
-                        //println!("key: {}", key);
-                        match current_map.get(key) {
-                            Some(map) => current_map = map.to_ymap().unwrap().clone(),
-                            None => {
-                                // this does not work correctly at the moment: it overwrites the the value...
-                                // todo: fix nested key
-                                let next_map = PrelimMap::<i32>::from(HashMap::default());
-                                current_map.insert(&mut transaction, key.to_owned(), next_map);
-                                current_map =
-                                    current_map.get(key).unwrap().to_ymap().unwrap().clone();
-                            }
-                        }
-                    } else {
-                        // last key, so we reached the root
-                        current_map.insert(&mut transaction, key.to_owned(), value.to_owned());
-                    }
-                }
+        let previous = leaves(resource);
+        let update = resource.add_local_update(|transaction| {
+            let root = transaction.get_map("root");
+            patch::write_map_diff(transaction, &root, from, to);
+            transaction
+        })?;
+
+        self.commit_local_update(&update, resource_name, &previous)?;
+        Ok(update)
+    }
 
+    /// Applies an update of another yrs client (v1 encoded, the format Yjs sends) to a resource
+    /// and commits it to our log, signed by our identity.
+    /// Returns the committed update, None if the update brought nothing new.
+    pub fn apply_client_update(&mut self, resource_name: &str, update: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        let update = Update::decode_v1(update)
+            .map_err(|e| Error::Other(format!("Invalid update: {:?}", e)))?;
+        let resource = self
+            .resources
+            .get(resource_name)
+            .ok_or_else(|| Error::DcoreError(format!("Resource {} not found.", resource_name)))?;
+        let previous = leaves(resource);
+
+        let mut transaction = resource.store.transact();
+        let empty_update = transaction.encode_update_v2();
+        transaction.apply_update(update);
+        let update = transaction.encode_update_v2();
+        transaction.commit();
+        drop(transaction);
+        if update == empty_update {
+            return Ok(None);
+        }
+
+        self.commit_local_update(&update, resource_name, &previous)?;
+        Ok(Some(update))
+    }
+
+    /// Removes a key from a resource, the key has the same form as in `update_resource_with_key_value`.
+    /// Returns the committed yrs update.
+    pub fn delete_resource_key(&mut self, resource_name: &str, key: &str) -> Result<Vec<u8>, Error> {
+        let resource = self
+            .resources
+            .get_mut(resource_name)
+            .ok_or_else(|| Error::DcoreError(format!("Resource {} not found.", resource_name)))?;
+        let previous = leaves(resource);
+        let found = std::cell::Cell::new(false);
+        let update = resource.add_local_update(|transaction| {
+            found.set(remove_key(transaction, key));
+            transaction
+        })?;
+        if !found.get() {
+            return Err(Error::DcoreError(format!("Key {} not found in {}.", key, resource_name)));
+        }
+
+        self.commit_local_update(&update, resource_name, &previous)?;
+        Ok(update)
+    }
+
+    /// Applies the operations of the batch with one update per resource, each committed to the
+    /// log of the resource with a single signature. Nothing is committed if a resource does not
+    /// exist, a key to delete is not found or a resource would become invalid.
+    /// Returns the committed update per resource.
+    pub fn apply_batch(&mut self, batch: &Batch) -> Result<BTreeMap<String, Vec<u8>>, Error> {
+        for name in batch.resources() {
+            if !self.resources.contains_key(name) {
+                return Err(Error::DcoreError(format!("Resource {} not found.", name)));
+            }
+        }
+
+        let mut updates = Vec::new();
+        let mut failure = None;
+        for name in batch.resources() {
+            let resource = self.resources.get_mut(name).unwrap();
+            let previous = leaves(resource);
+            let missing = std::cell::Cell::new(Vec::new());
+            let update = resource.add_local_update(|transaction| {
+                missing.set(apply_operations(transaction, batch, name));
                 transaction
-            })
-            .unwrap();
+            })?;
+            updates.push((name, update, previous));
 
-        let resource1 = self.resources.get(resource_name).unwrap();
-        self.commit_update(&update, &resource1);
-        Ok(())
+            let missing = missing.take();
+            if !missing.is_empty() {
+                failure = Some(Error::DcoreError(format!("Key {} not found in {}.", missing.join(", "), name)));
+                break;
+            }
+            if let Err(reason) = self.validators.check(&self.resources[name]) {
+                failure = Some(Error::ValidationError(format!("The update makes {} invalid: {}", name, reason)));
+                break;
+            }
+        }
+        if let Some(error) = failure {
+            // nothing was committed, the changed resources are replayed from their logs
+            for (name, _, _) in &updates {
+                let (resource, _) = self.validators.replay_valid(&self.event_log_store(), name)?;
+                self.resources.insert(name.to_string(), resource);
+            }
+            return Err(error);
+        }
+
+        let mut committed = BTreeMap::new();
+        for (name, update, previous) in updates {
+            let resource = &self.resources[name];
+            self.commit_update(&update, resource, changed_key_paths(&previous, &leaves(resource)))?;
+            committed.insert(name.to_string(), update);
+        }
+        Ok(committed)
     }
 
     pub(crate) fn get_config(&self) -> Result<Map, Error>{
@@ -369,6 +857,43 @@ impl Document {
         Ok(resource.store.transact().get_map("root"))
     }
 
+    /// The members of the document: fingerprint -> armored public key
+    pub(crate) fn config_get_members(&self) -> Result<HashMap<String, String>, Error> {
+        Ok(DocumentUtils::members(&self.get_config()?))
+    }
+
+    /// Registers the libp2p peer id of a member's device. `sync_libp2p::Node` only keeps
+    /// connections to registered peers.
+    pub fn config_add_peer(&mut self, fingerprint: &str, device: &str, peer_id: &str) -> Result<(), Error> {
+        if !self.config_get_members()?.contains_key(fingerprint) {
+            return Err(Error::DcoreError(format!("{} is not a member of the document.", fingerprint)));
+        }
+        let key = format!("{}.peers.{}", fingerprint, peer_id);
+        self.update_resource_with_key_value("config", key.as_str(), device)?;
+        Ok(())
+    }
+
+    /// The registered peers of the member devices, peer id -> (fingerprint, device).
+    pub fn config_get_peers(&self) -> Result<HashMap<String, (String, String)>, Error> {
+        let mut peers = HashMap::new();
+        if !self.resources.contains_key("config") {
+            return Ok(peers);
+        }
+        let config = self.get_config()?;
+        for (fingerprint, entry) in config.iter() {
+            let member_peers = entry
+                .to_ymap()
+                .and_then(|member| member.get("peers"))
+                .and_then(|member_peers| member_peers.to_ymap());
+            if let Some(member_peers) = member_peers {
+                for (peer_id, device) in member_peers.iter() {
+                    peers.insert(peer_id.to_string(), (fingerprint.to_string(), device.to_string()));
+                }
+            }
+        }
+        Ok(peers)
+    }
+
     pub(crate) fn config_get_remote(&self) -> Result<String, Error>{
         let config = self.get_config().unwrap();
         let fingerprint = self.identity.get_fingerprint();
@@ -398,12 +923,20 @@ mod tests {
 
     use lib0::any::Any;
 
-    use crate::document::DocumentNewOptions;
+    use crate::batch::Batch;
+    use crate::document::{DocumentNewOptions, DOCUMENT_ID_KEY};
+    use crate::errors::Error;
+    use crate::event_log_store::{EventLogStore, Log};
+    use crate::format;
+    use crate::projection::Projection;
+    use crate::sync_bundle::BundleSync;
+    use crate::update_meta::UpdateMeta;
     use crate::Document;
 
     use crate::test_utils::{
-        create_test_env, create_test_env_with_new_gpg_key, create_test_env_with_sample_gpg_key,
-        create_test_env_with_test_gpg_key, get_test_key,
+        create_test_document, create_test_env, create_test_env_with_new_gpg_key,
+        create_test_env_with_sample_gpg_key, create_test_env_with_test_gpg_key, create_test_gpg_home,
+        get_test_key,
     };
 
 
@@ -416,6 +949,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: get_test_key().fingerprint,
             name: String::from("name"),
+            gpg_home: None,
         })
         .unwrap();
     }
@@ -429,6 +963,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: key.fingerprint.clone(),
             name: String::from("test-doc1"),
+            gpg_home: None,
         })
         .unwrap();
 
@@ -460,6 +995,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: key.fingerprint.clone(),
             name: String::from("test-doc1"),
+            gpg_home: None,
         })
         .unwrap();
 
@@ -471,6 +1007,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: key.fingerprint.clone(),
             name: String::from("test-doc1"),
+            gpg_home: None,
         })
         .unwrap();
 
@@ -493,6 +1030,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: get_test_key().fingerprint,
             name: String::from("name"),
+            gpg_home: None,
         })
         .unwrap();
 
@@ -535,6 +1073,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: get_test_key().fingerprint,
             name: String::from("test-doc1"),
+            gpg_home: None,
         })
             .unwrap();
 
@@ -558,6 +1097,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
             .unwrap();
 
@@ -568,7 +1108,10 @@ mod tests {
         doc.resources.get_mut("config").unwrap();
         doc.add_resource("test".to_string()).unwrap();
 
-        let result =  fs::read("./.test/doc/add_resource/.data/refs/local/test/A84E5D451E9E75B4791556896F45F34A926FBB70/device-0").unwrap();
+        let result =  fs::read(format!(
+            "./.test/doc/add_resource/.data/refs/local/test/A84E5D451E9E75B4791556896F45F34A926FBB70/{}",
+            doc.config_get_local_device().unwrap()
+        )).unwrap();
         assert_eq!(result.len(), 41);
     }
 
@@ -580,6 +1123,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: key.fingerprint.clone(),
             name: String::from("name"),
+            gpg_home: None,
         })
         .unwrap();
 
@@ -602,6 +1146,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
             .unwrap();
 
@@ -611,7 +1156,10 @@ mod tests {
 
         doc.add_resource("test".to_string()).unwrap();
 
-        let result =  fs::read("./.test/doc/update_test_resource_with_key_value/.data/refs/local/test/A84E5D451E9E75B4791556896F45F34A926FBB70/device-0").unwrap();
+        let result =  fs::read(format!(
+            "./.test/doc/update_test_resource_with_key_value/.data/refs/local/test/A84E5D451E9E75B4791556896F45F34A926FBB70/{}",
+            doc.config_get_local_device().unwrap()
+        )).unwrap();
         assert_eq!(result.len(), 41);
 
         doc.update_resource_with_key_value("test", "entry", "1234").unwrap();
@@ -628,6 +1176,10 @@ mod tests {
 
         let result = doc.resources.get("test").unwrap().get_root();
         assert_eq!(result.get("entry").unwrap().to_string(), "newValueSameKey");
+        assert!(matches!(
+            doc.update_resource_with_key_value("missing", "entry", "1234"),
+            Err(Error::DcoreError(_))
+        ));
 
         // 2th time doc loaded
 
@@ -635,6 +1187,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
             .unwrap();
 
@@ -658,6 +1211,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
             .unwrap();
 
@@ -680,6 +1234,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
             .unwrap();
 
@@ -689,7 +1244,10 @@ mod tests {
 
         doc.add_resource("test".to_string()).unwrap();
 
-        let result =  fs::read("./.test/doc/reload_update_test_resource_with_key_value/.data/refs/local/test/A84E5D451E9E75B4791556896F45F34A926FBB70/device-0").unwrap();
+        let result =  fs::read(format!(
+            "./.test/doc/reload_update_test_resource_with_key_value/.data/refs/local/test/A84E5D451E9E75B4791556896F45F34A926FBB70/{}",
+            doc.config_get_local_device().unwrap()
+        )).unwrap();
         assert_eq!(result.len(), 41);
 
         doc.update_resource_with_key_value("test", "test", "1234").unwrap();
@@ -699,6 +1257,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
             .unwrap();
 
@@ -713,6 +1272,58 @@ mod tests {
     }
 
 
+    #[test]
+    fn document_id_is_shared_by_clones() {
+        let doc_dir = "./.test/doc/document_id_is_shared_by_clones/";
+        let gpg_home = create_test_gpg_home(doc_dir);
+        let bundle = PathBuf::from(doc_dir).join("doc.bundle");
+
+        let doc = Document::new(DocumentNewOptions {
+            directory: PathBuf::from(doc_dir).join("device-a"),
+            identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
+            name: String::from("name"),
+            gpg_home: Some(gpg_home.clone()),
+        }).unwrap();
+        doc.config_set_local_device("device-a").unwrap();
+        assert_eq!(doc.get_id(), None);
+
+        let mut doc = doc
+            .init(&get_test_key().fingerprint, &get_test_key().public_key)
+            .unwrap();
+        let id = doc.get_id().expect("an initialized document has an id");
+        assert_eq!(id.len(), 32);
+        doc.update_resource_with_key_value("config", "A84E5D451E9E75B4791556896F45F34A926FBB70.x", "1").unwrap();
+        assert_eq!(doc.get_id(), Some(id.clone()));
+        BundleSync::export(&doc, &bundle, None).unwrap();
+
+        let mut clone = Document::new(DocumentNewOptions {
+            directory: PathBuf::from(doc_dir).join("device-b"),
+            identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
+            name: String::from("name"),
+            gpg_home: Some(gpg_home.clone()),
+        }).unwrap();
+        clone.config_set_local_device("device-b").unwrap();
+        BundleSync::import(&mut clone, &bundle).unwrap();
+        // the format the document was created with is known to the clone
+        assert_eq!(format::recorded_version(&clone.resources["config"]), Some(format::FORMAT_VERSION));
+        // the import loads the document, which keeps the id
+        assert_eq!(clone.repository.config().unwrap().get_string(DOCUMENT_ID_KEY).unwrap(), id);
+        clone.update_resource_with_key_value("config", "A84E5D451E9E75B4791556896F45F34A926FBB70.y", "2").unwrap();
+        assert_eq!(clone.get_id(), Some(id.clone()));
+
+        // the id is kept once seen, a member can not change it later
+        let update = clone.resources.get_mut("config").unwrap().add_local_update(|transaction| {
+            let resource_meta = transaction.get_map("_resource_meta");
+            resource_meta.insert(transaction, "document_id".to_owned(), "forged");
+            transaction
+        }).unwrap();
+        let config = clone.resources.remove("config").unwrap();
+        clone.commit_update(&update, &config, Vec::new()).unwrap();
+        clone.resources.insert("config".to_string(), config);
+        clone.load().unwrap();
+        assert_eq!(clone.get_id(), Some(id));
+    }
+
     #[test]
     fn config_set_device_name() {
         let doc_dir = "./.test/doc/config_set_device_name/";
@@ -721,6 +1332,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         }).unwrap();
 
 
@@ -738,4 +1350,283 @@ mod tests {
 
     }
 
+    #[test]
+    fn delete_resource_key() {
+        let doc_dir = "./.test/doc/delete_resource_key/";
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("test".to_string()).unwrap();
+        doc.update_resource_with_key_value("test", "a.b", "1").unwrap();
+        doc.update_resource_with_key_value("test", "c", "2").unwrap();
+
+        doc.delete_resource_key("test", "a.b").unwrap();
+        doc.delete_resource_key("test", "c").unwrap();
+        assert!(doc.delete_resource_key("test", "c").is_err());
+        assert_eq!(doc.resources.get("test").unwrap().get_json(), serde_json::json!({"a": {}}));
+
+        doc.load().unwrap();
+        assert_eq!(doc.resources.get("test").unwrap().get_json(), serde_json::json!({"a": {}}));
+    }
+
+    #[test]
+    fn projection() {
+        let doc_dir = "./.test/doc/projection/";
+        let fingerprint = get_test_key().fingerprint;
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("test".to_string()).unwrap();
+        doc.update_resource_with_key_value("test", "a.b", "1").unwrap();
+        doc.enable_projection().unwrap();
+        doc.update_resource_with_key_value("test", "c", "2").unwrap();
+
+        let query = "SELECT key_path, value, author FROM entries WHERE resource = 'test' ORDER BY key_path";
+        let expected = vec![
+            serde_json::json!({"key_path": "a.b", "value": "1", "author": fingerprint}),
+            serde_json::json!({"key_path": "c", "value": "2", "author": fingerprint}),
+        ];
+        assert_eq!(doc.projection.as_ref().unwrap().query(query).unwrap(), expected);
+
+        // the projection is kept up to date once it exists
+        let mut reopened = Document::new(doc.reopen_options()).unwrap();
+        reopened.load().unwrap();
+        reopened.delete_resource_key("test", "c").unwrap();
+        let projection = Projection::open_read_only(&Projection::path(reopened.repository.path())).unwrap();
+        assert_eq!(projection.query(query).unwrap(), expected[..1].to_vec());
+    }
+
+    #[test]
+    fn search_index() {
+        let doc_dir = "./.test/doc/search_index/";
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("meetings".to_string()).unwrap();
+        doc.update_resource_with_key_value("meetings", "2022-11-07.notes", "Budget approved").unwrap();
+        doc.enable_search_index().unwrap();
+        doc.update_resource_with_key_value("meetings", "2022-11-14.notes", "Budget review").unwrap();
+
+        let hits = doc.search_index.as_ref().unwrap().search("budget").unwrap();
+        let mut key_paths: Vec<&str> = hits.iter().map(|hit| hit.key_path.as_str()).collect();
+        key_paths.sort();
+        assert_eq!(key_paths, vec!["2022-11-07.notes", "2022-11-14.notes"]);
+        assert!(hits.iter().all(|hit| hit.resource == "meetings"));
+    }
+
+    #[test]
+    fn update_resource_with_json() {
+        let doc_dir = "./.test/doc/update_resource_with_json/";
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("test".to_string()).unwrap();
+
+        let value = serde_json::json!({"name": "dcore", "tags": ["a", "b"], "stars": 3.0, "owner": {"alias": null}});
+        doc.update_resource_with_json("test", "project", &value).unwrap();
+        // nested objects are maps, their keys can be set on their own
+        doc.update_resource_with_json("test", "project.owner.alias", &serde_json::json!("fuubi")).unwrap();
+
+        let expected = serde_json::json!({"project": {"name": "dcore", "tags": ["a", "b"], "stars": 3.0, "owner": {"alias": "fuubi"}}});
+        assert_eq!(doc.resources.get("test").unwrap().get_json(), expected);
+        doc.load().unwrap();
+        assert_eq!(doc.resources.get("test").unwrap().get_json(), expected);
+    }
+
+    #[test]
+    fn config_add_peer() {
+        let doc_dir = "./.test/doc/config_add_peer/";
+        let fingerprint = get_test_key().fingerprint;
+        let mut doc = create_test_document(doc_dir);
+        assert!(doc.config_get_peers().unwrap().is_empty());
+
+        doc.config_add_peer(&fingerprint, "device-a", "12D3KooWA").unwrap();
+        doc.config_add_peer(&fingerprint, "device-b", "12D3KooWB").unwrap();
+        assert!(doc.config_add_peer("0000", "device-c", "12D3KooWC").is_err());
+
+        let peers = doc.config_get_peers().unwrap();
+        assert_eq!(peers.len(), 2);
+        assert_eq!(peers.get("12D3KooWB"), Some(&(fingerprint.clone(), "device-b".to_string())));
+    }
+
+    #[test]
+    fn validate_local_updates() {
+        let doc_dir = "./.test/doc/validate_local_updates/";
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("tasks".to_string()).unwrap();
+        doc.update_resource_with_key_value("tasks", "status", "open").unwrap();
+
+        let schema = serde_json::json!({"type": "object", "properties": {"status": {"enum": ["open", "done"]}}});
+        assert!(doc.set_resource_schema("tasks", &serde_json::json!({"type": 12})).is_err());
+        doc.set_resource_schema("tasks", &schema).unwrap();
+        doc.update_resource_with_key_value("tasks", "status", "done").unwrap();
+
+        // the invalid update is refused and the resource is as before
+        let result = doc.update_resource_with_key_value("tasks", "status", "lost");
+        assert!(matches!(result, Err(Error::ValidationError(_))));
+        assert_eq!(doc.resources["tasks"].get_json(), serde_json::json!({"status": "done"}));
+        doc.load().unwrap();
+        assert_eq!(doc.resources["tasks"].get_json(), serde_json::json!({"status": "done"}));
+        assert!(doc.quarantined.is_empty());
+
+        // the schema has to match the current content
+        let schema = serde_json::json!({"type": "object", "required": ["owner"]});
+        assert!(doc.set_resource_schema("tasks", &schema).is_err());
+        assert!(doc.resources["tasks"].get_schema().unwrap()["properties"].is_object());
+    }
+
+    #[test]
+    fn update_metadata() {
+        let doc_dir = "./.test/doc/update_metadata/";
+        let fingerprint = get_test_key().fingerprint;
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("test".to_string()).unwrap();
+        doc.update_resource_with_key_value("test", "a.b", "1").unwrap();
+        doc.update_resource_with_key_value("test", "c", "2").unwrap();
+
+        let log = Log::local("test", &fingerprint, &doc.config_get_local_device().unwrap());
+        let store = doc.event_log_store();
+        let metas: Vec<UpdateMeta> = store.metas(&log).unwrap().map(|meta| meta.unwrap().unwrap()).collect();
+        assert_eq!(metas.iter().map(|meta| meta.lamport).collect::<Vec<u64>>(), vec![1, 2, 3]);
+        assert!(metas[0].touched_key_paths.is_empty());
+        assert_eq!(metas[1].touched_key_paths, vec!["a.b".to_string()]);
+        assert_eq!(metas[2].touched_key_paths, vec!["c".to_string()]);
+        // all updates are from the same yrs client
+        assert_eq!(metas[2].state_vector.len(), 1);
+    }
+
+    #[test]
+    fn apply_batch() {
+        let doc_dir = "./.test/doc/apply_batch/";
+        let fingerprint = get_test_key().fingerprint;
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("notes".to_string()).unwrap();
+        doc.add_resource("tasks".to_string()).unwrap();
+        doc.update_resource_with_key_value("notes", "draft", "yes").unwrap();
+
+        let mut batch = Batch::new();
+        batch
+            .set("notes", "meeting.place", serde_json::json!("Zurich"))
+            .set("notes", "meeting.time", serde_json::json!("10:00"))
+            .delete("notes", "draft")
+            .set("tasks", "open", serde_json::json!(3));
+        let updates = doc.apply_batch(&batch).unwrap();
+        assert_eq!(updates.len(), 2);
+        assert_eq!(
+            doc.resources["notes"].get_json(),
+            serde_json::json!({"meeting": {"place": "Zurich", "time": "10:00"}})
+        );
+        assert_eq!(doc.resources["tasks"].get_json(), serde_json::json!({"open": 3.0}));
+
+        // one commit per resource log
+        let device = doc.config_get_local_device().unwrap();
+        let count = |doc: &Document, resource: &str| {
+            doc.event_log_store().updates(&Log::local(resource, &fingerprint, &device)).unwrap().count()
+        };
+        assert_eq!(count(&doc, "notes"), 3);
+        assert_eq!(count(&doc, "tasks"), 2);
+
+        // nothing is committed if one of the operations fails
+        let mut batch = Batch::new();
+        batch.set("tasks", "open", serde_json::json!(4)).delete("notes", "draft");
+        assert!(doc.apply_batch(&batch).is_err());
+        assert_eq!(doc.resources["tasks"].get_json(), serde_json::json!({"open": 3.0}));
+        assert_eq!(count(&doc, "tasks"), 2);
+        let mut batch = Batch::new();
+        batch.set("missing", "open", serde_json::json!(4));
+        assert!(doc.apply_batch(&batch).is_err());
+    }
+
+    #[test]
+    fn apply_patches() {
+        let doc_dir = "./.test/doc/apply_patches/";
+        let fingerprint = get_test_key().fingerprint;
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("notes".to_string()).unwrap();
+        let value = serde_json::json!({"title": "Meeting", "tags": ["a"], "place": {"city": "Zurich", "room": "1"}});
+        doc.update_resource_with_json("notes", "meeting", &value).unwrap();
+        let log = Log::local("notes", &fingerprint, &doc.config_get_local_device().unwrap());
+        let count = |doc: &Document| doc.event_log_store().updates(&log).unwrap().count();
+        assert_eq!(count(&doc), 2);
+
+        let patch = serde_json::json!([
+            {"op": "replace", "path": "/meeting/title", "value": "Retro"},
+            {"op": "add", "path": "/meeting/tags/-", "value": "b"},
+            {"op": "remove", "path": "/meeting/place/room"}
+        ]);
+        doc.apply_json_patch("notes", &patch).unwrap();
+        assert_eq!(
+            doc.resources["notes"].get_json(),
+            serde_json::json!({"meeting": {"title": "Retro", "tags": ["a", "b"], "place": {"city": "Zurich"}}})
+        );
+        assert_eq!(count(&doc), 3);
+
+        let patch = serde_json::json!({"meeting": {"place": null, "time": "10:00"}, "done": false});
+        doc.apply_merge_patch("notes", &patch).unwrap();
+        assert_eq!(
+            doc.resources["notes"].get_json(),
+            serde_json::json!({"meeting": {"title": "Retro", "tags": ["a", "b"], "time": "10:00"}, "done": false})
+        );
+        assert_eq!(count(&doc), 4);
+
+        // failing patches change nothing
+        let patch = serde_json::json!([
+            {"op": "remove", "path": "/done"},
+            {"op": "test", "path": "/meeting/title", "value": "Meeting"}
+        ]);
+        assert!(doc.apply_json_patch("notes", &patch).is_err());
+        assert!(doc.apply_merge_patch("notes", &serde_json::json!(["not", "an", "object"])).is_err());
+        assert!(doc.apply_merge_patch("missing", &serde_json::json!({})).is_err());
+        assert_eq!(doc.resources["notes"].get_json()["done"], serde_json::json!(false));
+        assert_eq!(count(&doc), 4);
+
+        doc.load().unwrap();
+        assert_eq!(doc.resources["notes"].get_json()["meeting"]["time"], serde_json::json!("10:00"));
+    }
+
+    #[test]
+    fn concurrent_array_appends() {
+        let doc_dir = "./.test/doc/concurrent_array_appends/";
+        let gpg_home = create_test_gpg_home(doc_dir);
+        let fingerprint = get_test_key().fingerprint;
+        let bundle_a = PathBuf::from(doc_dir).join("a.bundle");
+        let bundle_b = PathBuf::from(doc_dir).join("b.bundle");
+
+        let doc_a = Document::new(DocumentNewOptions {
+            directory: PathBuf::from(doc_dir).join("device-a"),
+            identity_fingerprint: fingerprint.clone(),
+            name: String::from("name"),
+            gpg_home: Some(gpg_home.clone()),
+        }).unwrap();
+        doc_a.config_set_local_device("device-a").unwrap();
+        let mut doc_a = doc_a.init(&fingerprint, &get_test_key().public_key).unwrap();
+        doc_a.add_resource("notes".to_string()).unwrap();
+        let value = serde_json::json!({"tags": ["a"], "items": [{"done": false}]});
+        doc_a.update_resource_with_json("notes", "meeting", &value).unwrap();
+        BundleSync::export(&doc_a, &bundle_a, None).unwrap();
+
+        let mut doc_b = Document::new(DocumentNewOptions {
+            directory: PathBuf::from(doc_dir).join("device-b"),
+            identity_fingerprint: fingerprint.clone(),
+            name: String::from("name"),
+            gpg_home: Some(gpg_home.clone()),
+        }).unwrap();
+        doc_b.config_set_local_device("device-b").unwrap();
+        BundleSync::import(&mut doc_b, &bundle_a).unwrap();
+
+        let append = |value: &str| serde_json::json!([{"op": "add", "path": "/meeting/tags/-", "value": value}]);
+        doc_a.apply_json_patch("notes", &append("from-a")).unwrap();
+        doc_b.apply_json_patch("notes", &append("from-b")).unwrap();
+        let check = serde_json::json!([{"op": "replace", "path": "/meeting/items/0/done", "value": true}]);
+        doc_b.apply_json_patch("notes", &check).unwrap();
+
+        BundleSync::export(&doc_a, &bundle_a, None).unwrap();
+        BundleSync::export(&doc_b, &bundle_b, None).unwrap();
+        BundleSync::import(&mut doc_a, &bundle_b).unwrap();
+        BundleSync::import(&mut doc_b, &bundle_a).unwrap();
+
+        for doc in [&doc_a, &doc_b] {
+            let meeting = &doc.resources["notes"].get_json()["meeting"];
+            let tags = meeting["tags"].as_array().unwrap();
+            assert_eq!(tags.len(), 3);
+            assert_eq!(tags[0], serde_json::json!("a"));
+            assert!(tags.contains(&serde_json::json!("from-a")));
+            assert!(tags.contains(&serde_json::json!("from-b")));
+            assert_eq!(meeting["items"], serde_json::json!([{"done": true}]));
+        }
+        assert_eq!(doc_a.resources["notes"].get_json(), doc_b.resources["notes"].get_json());
+    }
+
 }
@@ -1,94 +1,319 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::PathBuf;
+
+use git2::{Buf, Oid, Sort};
+use rand::RngCore;
+use yrs::updates::decoder::Decode;
+use yrs::{Map, Update};
+
+use crate::equivocation::{update_head, HeadUpdate};
+use crate::errors::Error;
+use crate::event_log_store::{EventLogStore, Log};
 use crate::gpg::Gpg;
 use crate::resource::Resource;
+use crate::update_meta::UpdateMeta;
 use crate::Document;
 
 pub struct DocumentUtils;
 
 impl DocumentUtils {
+    /// Appends the update and its metadata to the log of this document's identity and device.
+    /// `touched_key_paths` are the key paths of the resource that the update changed.
+    /// Returns the new head of the log.
     pub fn commit_update(
         doc: &Document,
-        _resource: &Resource,
+        resource: &Resource,
         update: Vec<u8>,
-    ) -> Result<(), git2::Error> {
-        let repo = &doc.repository;
-        let resource_name = &_resource.name;
-        let user_fingerprint = &doc.identity.get_fingerprint();
-        let config = doc.repository.config().unwrap().snapshot().unwrap();
-        let device = match config.get_str("user.device") {
-            Ok(device) => device,
-            Err(_) => "device-0",
-        };
-        let log_name = format!("refs/local/{}/{}/{}", resource_name, user_fingerprint, device);
-        let parents = match doc.repository.revparse_ext(log_name.as_str()) {
-            Ok((_obj, reference)) => {
-                let oid = reference
-                    .expect("Found a reference but could not load it.")
-                    .target()
-                    .expect("Found a reference but could not get its target oid.");
-                let head_commit = doc
-                    .repository
-                    .find_commit(oid)
-                    .expect("Found a reference but could not load its commit.");
-                Some(head_commit)
+        touched_key_paths: Vec<String>,
+    ) -> Result<String, Error> {
+        let log = Log::local(
+            &resource.name,
+            &doc.identity.get_fingerprint(),
+            &doc.config_get_local_device()?,
+        );
+        // todo: this is not ideal but making the doc mutable just because of this is not nice either
+        // look into more details: https://doc.rust-lang.org/error-index.html#E0382
+        let sign = |data: &str| doc.identity.gpg()?.sign_string(&data.to_string(), &doc.identity);
+        let store = doc.event_log_store();
+        let meta = UpdateMeta::for_local_update(&store, resource, touched_key_paths)?;
+        store.append(&log, &update, Some(&meta), &sign)
+    }
+
+    /// The heads of all event-logs of the document, named like on a git hub
+    /// (`refs/heads/{resource}/{fingerprint}/{device}`). Our own logs are in `refs/local`,
+    /// the ones we got from other devices in `refs/origin`.
+    pub(crate) fn log_heads(doc: &Document) -> Result<BTreeMap<String, Oid>, Error> {
+        let mut heads = BTreeMap::new();
+        for prefix in ["refs/origin/", "refs/local/"] {
+            for log in doc.repository.references_glob(&format!("{}*", prefix))? {
+                let log = log?;
+                if let (Some(name), Some(head)) = (log.name(), log.target()) {
+                    heads.insert(name.replacen(prefix, "refs/heads/", 1), head);
+                }
             }
-            Err(_) => None,
-        };
+        }
+        Ok(heads)
+    }
 
-        let update_oid = repo.blob(&update).unwrap();
-        let mut builder = repo.treebuilder(None).unwrap();
-        builder.insert("update", update_oid, 0o100644).unwrap();
-        let update_tree_oid = builder.write().unwrap();
-        let update_tree = repo.find_tree(update_tree_oid).unwrap();
-        // todo: pass signature info from config
-        let authors_signature = git2::Signature::now("Alice", "info@colomba.link").unwrap();
-
-        let commit_buffer = match parents {
-            Some(parent) => {
-                let commit_buffer = repo
-                    .commit_create_buffer(
-                        &authors_signature,
-                        &authors_signature,
-                        "update.",
-                        &update_tree,
-                        &[&parent],
-                    )
-                    .unwrap();
-                commit_buffer
+    /// Packs the commits of the given logs that are not reachable from `known`, and returns the
+    /// pack together with the number of commits packed per log.
+    pub(crate) fn pack_logs(
+        doc: &Document,
+        heads: &BTreeMap<String, Oid>,
+        known: &[Oid],
+    ) -> Result<(Vec<u8>, BTreeMap<String, usize>), Error> {
+        let repository = &doc.repository;
+        let mut commits_per_log = BTreeMap::new();
+        let mut packbuilder = repository.packbuilder()?;
+        for (name, head) in heads {
+            let mut revwalk = repository.revwalk()?;
+            revwalk.push(*head)?;
+            for known_commit in known {
+                revwalk.hide(*known_commit)?;
             }
-            None => {
-                let commit_buffer = repo
-                    .commit_create_buffer(
-                        &authors_signature,
-                        &authors_signature,
-                        "update.",
-                        &update_tree,
-                        &[],
-                    )
-                    .unwrap();
-                commit_buffer
+            let commits = revwalk.collect::<Result<Vec<Oid>, git2::Error>>()?;
+            for commit in &commits {
+                packbuilder.insert_commit(*commit)?;
             }
+            commits_per_log.insert(name.clone(), commits.len());
+        }
+        // an empty pack is sent as no pack at all
+        if commits_per_log.values().all(|commits| *commits == 0) {
+            return Ok((Vec::new(), commits_per_log));
+        }
+        let mut pack = Buf::new();
+        packbuilder.write_buf(&mut pack)?;
+        Ok((pack.to_vec(), commits_per_log))
+    }
+
+    /// The yrs update stored in a log commit.
+    pub(crate) fn read_update(doc: &Document, oid: Oid) -> Result<Vec<u8>, Error> {
+        doc.event_log_store().read_update(oid)
+    }
+
+    pub(crate) fn write_pack(doc: &Document, pack: &[u8]) -> Result<(), Error> {
+        if pack.is_empty() {
+            return Ok(());
+        }
+        let odb = doc.repository.odb()?;
+        let mut writer = odb.packwriter()?;
+        writer.write_all(pack)?;
+        writer.commit()?;
+        Ok(())
+    }
+
+    /// Merges a log of another identity or device that arrived outside of a git fetch.
+    ///
+    /// Like a fetch, the log is stored in `refs/origin/*`, but only if all the commits we do not
+    /// know yet are signed by the identity the log belongs to and the new head descends from the
+    /// accepted one, see `equivocation::update_head`. Returns the number of new commits.
+    pub(crate) fn merge_remote_log(
+        doc: &Document,
+        verifier: &mut CommitVerifier,
+        name: &str,
+        head: Oid,
+    ) -> Result<usize, Error> {
+        let tracking_log = name
+            .strip_prefix("refs/heads/")
+            .and_then(|log| Log::from_reference(&format!("refs/origin/{}", log)))
+            .ok_or_else(|| Error::DcoreError(format!("{} is not an event-log.", name)))?;
+        match update_head(doc, verifier, &tracking_log, head)? {
+            HeadUpdate::Accepted(commits) => Ok(commits),
+            HeadUpdate::Stale => Ok(0),
+            HeadUpdate::Forked(fork) => Err(Error::DcoreError(format!(
+                "{} was forked, {} does not descend from the accepted head {}.",
+                name, fork.forked, fork.accepted
+            ))),
+        }
+    }
+
+    /// Merges the logs of other identities and devices, see `merge_remote_log`, and reloads the
+    /// document. The config logs are merged first, such that members they add are known when the
+    /// other logs are verified. A document without a `config` resource yet trusts the creator of
+    /// the config logs on first use, see `CommitVerifier::trust_config_root`. Our own logs are
+    /// skipped, we are their only writer.
+    /// Returns the new commits per merged log and the reason per rejected log.
+    pub(crate) fn merge_remote_logs(
+        doc: &mut Document,
+        heads: Vec<(String, Oid)>,
+    ) -> Result<(BTreeMap<String, usize>, BTreeMap<String, String>), Error> {
+        let own_logs_suffix = Self::own_logs_suffix(doc)?;
+        let (config_heads, other_heads): (Vec<_>, Vec<_>) = heads
+            .into_iter()
+            .partition(|(name, _)| name.starts_with("refs/heads/config/"));
+
+        let mut verifier = CommitVerifier::new(doc)?;
+        verifier.trust_config_root(doc, &config_heads)?;
+
+        let mut merged = BTreeMap::new();
+        let mut rejected = BTreeMap::new();
+        for heads in [config_heads, other_heads] {
+            for (name, head) in heads {
+                if name.ends_with(&own_logs_suffix) {
+                    continue;
+                }
+                match Self::merge_remote_log(doc, &mut verifier, &name, head) {
+                    Ok(commits) => {
+                        merged.insert(name, commits);
+                    }
+                    Err(reason) => {
+                        rejected.insert(name, reason.to_string());
+                    }
+                }
+            }
+            doc.load()?;
+            verifier.refresh(doc)?;
+        }
+        Ok((merged, rejected))
+    }
+
+    /// The members in a `config` map: fingerprint -> armored public key
+    pub(crate) fn members(config: &Map) -> HashMap<String, String> {
+        let mut members = HashMap::new();
+        for (fingerprint, entry) in config.iter() {
+            let public_key = entry
+                .to_ymap()
+                .and_then(|member| member.get("public_key"));
+            if let Some(public_key) = public_key {
+                members.insert(fingerprint.to_string(), public_key.to_string());
+            }
+        }
+        members
+    }
+
+    /// The suffix `/{fingerprint}/{device}` of the logs written by this document's identity and device.
+    pub(crate) fn own_logs_suffix(doc: &Document) -> Result<String, Error> {
+        Ok(format!(
+            "/{}/{}",
+            doc.identity.get_fingerprint(),
+            doc.config_get_local_device()?
+        ))
+    }
+}
+
+/// Verifies the signatures of log commits against the keys of the document members. The keys are
+/// imported into a keyring of the document (`.data/keyring`), not into the keyring of the user,
+/// and only once, so one verifier is built per import, sync or fsck and used for all its commits.
+pub(crate) struct CommitVerifier {
+    gpg: Gpg,
+    trusted_keys: HashMap<String, String>,
+    /// The keyring of a `read_only` verifier, removed when the verifier is dropped
+    scratch_keyring: Option<PathBuf>,
+}
+
+impl CommitVerifier {
+    pub(crate) fn new(doc: &Document) -> Result<CommitVerifier, Error> {
+        Self::with_keyring(doc, doc.repository.path().join("keyring"), false)
+    }
+
+    /// A verifier that does not write to the document, e.g. for `fsck`. The keys are imported
+    /// into a keyring in the temporary directory instead.
+    pub(crate) fn read_only(doc: &Document) -> Result<CommitVerifier, Error> {
+        let keyring = std::env::temp_dir().join(format!("dcore-keyring-{:016x}", rand::thread_rng().next_u64()));
+        Self::with_keyring(doc, keyring, true)
+    }
+
+    fn with_keyring(doc: &Document, keyring: PathBuf, scratch: bool) -> Result<CommitVerifier, Error> {
+        let mut verifier = CommitVerifier {
+            gpg: Gpg::with_keyring(&keyring)?,
+            trusted_keys: HashMap::new(),
+            scratch_keyring: if scratch { Some(keyring) } else { None },
         };
+        verifier.refresh(doc)?;
+        Ok(verifier)
+    }
+
+    /// Trusts the members listed in the `config` resource. A document without a `config` resource
+    /// yet (e.g. before its first import) trusts the identity it is opened with.
+    pub(crate) fn refresh(&mut self, doc: &Document) -> Result<(), Error> {
+        if doc.resources.contains_key("config") {
+            self.trusted_keys.clear();
+            for (fingerprint, public_key) in doc.config_get_members()? {
+                self.trust(fingerprint, public_key)?;
+            }
+        } else {
+            self.trust(doc.identity.get_fingerprint(), doc.identity.get_armored_public_key()?)?;
+        }
+        Ok(())
+    }
 
-        let commit_string = &String::from(commit_buffer.as_str().unwrap());
-        let commit_identity = &doc.identity;
+    fn trust(&mut self, fingerprint: String, public_key: String) -> Result<(), Error> {
+        if !self.gpg.has_public_key(&fingerprint) {
+            self.gpg.import_public_key(&public_key)?;
+        }
+        self.trusted_keys.insert(fingerprint, public_key);
+        Ok(())
+    }
 
-        // todo: this is not ideal but making the doc mutable just because of this is not nice either
-        // look into more details: https://doc.rust-lang.org/error-index.html#E0382
-        let mut gpg = Gpg::new();
-        let commit_signature = gpg.sign_string(&commit_string, &commit_identity).unwrap();
-        let mut commit_copy = commit_signature.clone();
-        let _commit_signature_withoute_new_line = commit_copy.truncate(commit_copy.len() - 1);
-        let new_signed_commit = repo
-            .commit_signed(
-                commit_buffer.as_str().unwrap(),
-                commit_copy.as_str(),
-                Some("gpgsig"),
-            )
-            .unwrap();
-
-        repo.reference(&log_name, new_signed_commit, true, "update ref")
-            .expect("Could not update the reference with the new commit.");
+    /// Trust on first use: a document without a `config` resource yet trusts the identity that
+    /// created the config logs it receives. That is the signer of a root commit of a config log
+    /// whose update adds the signer itself as a member. Fails if there is more than one such
+    /// identity, e.g. because the logs belong to different documents.
+    pub(crate) fn trust_config_root(&mut self, doc: &Document, config_heads: &[(String, Oid)]) -> Result<(), Error> {
+        if doc.resources.contains_key("config") {
+            return Ok(());
+        }
+        let mut creators = BTreeMap::new();
+        for (_, head) in config_heads {
+            let mut revwalk = doc.repository.revwalk()?;
+            revwalk.push(*head)?;
+            revwalk.set_sorting(Sort::TOPOLOGICAL | Sort::REVERSE)?;
+            let root = match revwalk.next().transpose()? {
+                Some(root) => root,
+                None => continue,
+            };
+            let update = DocumentUtils::read_update(doc, root)?;
+            let update = Update::decode_v2(&update)
+                .map_err(|e| Error::DcoreError(format!("Could not decode the update of commit {}: {}", root, e)))?;
+            let store = yrs::Doc::new();
+            let mut transaction = store.transact();
+            transaction.apply_update(update);
+            let members = DocumentUtils::members(&transaction.get_map("root"));
 
+            let (signature, signed_data) = doc.repository.extract_signature(&root, None)?;
+            for (fingerprint, public_key) in &members {
+                if !self.gpg.has_public_key(fingerprint) {
+                    self.gpg.import_public_key(public_key)?;
+                }
+            }
+            if let Ok(signer) = self.gpg.verify_detached(&signature, &signed_data) {
+                if let Some(public_key) = members.get(&signer) {
+                    creators.insert(signer, public_key.clone());
+                }
+            }
+        }
+        if creators.len() > 1 {
+            return Err(Error::DcoreError(format!(
+                "The config logs are created by more than one identity ({}), refusing to trust any of them.",
+                creators.keys().cloned().collect::<Vec<String>>().join(", ")
+            )));
+        }
+        for (fingerprint, public_key) in creators {
+            self.trust(fingerprint, public_key)?;
+        }
         Ok(())
     }
+
+    /// Verifies the gpg signature of a log commit and returns the fingerprint of the signer, who
+    /// must be trusted.
+    pub(crate) fn verify(&mut self, doc: &Document, oid: Oid) -> Result<String, Error> {
+        let (signature, signed_data) = doc.repository.extract_signature(&oid, None)?;
+        let signer = self.gpg.verify_detached(&signature, &signed_data)?;
+        if !self.trusted_keys.contains_key(&signer) {
+            return Err(Error::DcoreError(format!(
+                "Commit {} is signed by {} which is not a member of the document.",
+                oid, signer
+            )));
+        }
+        Ok(signer)
+    }
+}
+
+impl Drop for CommitVerifier {
+    fn drop(&mut self) {
+        if let Some(keyring) = &self.scratch_keyring {
+            let _ = std::fs::remove_dir_all(keyring);
+        }
+    }
 }
@@ -0,0 +1,175 @@
+use std::collections::BTreeSet;
+
+use git2::{Oid, Repository};
+
+use crate::document_utils::CommitVerifier;
+use crate::errors::Error;
+use crate::event_log_store::{EventLogStore, Log, LogKind};
+use crate::Document;
+
+/// Two histories of the same device log that do not descend from one another, both signed by the
+/// author of the log. Honest devices only ever append to their logs, so the author is flagged.
+///
+/// The accepted history stays in `refs/origin`, the other one is kept in
+/// `refs/forks/{resource}/{fingerprint}/{device}/{head}` but not applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Fork {
+    pub log: Log,
+    pub accepted: String,
+    pub forked: String,
+}
+
+/// What happened to a new head of a log of another device, see `update_head`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum HeadUpdate {
+    /// The head descends from the accepted one, with the number of new commits
+    Accepted(usize),
+    /// The accepted head already contains the new one
+    Stale,
+    Forked(Fork),
+}
+
+fn fork_reference(log: &Log, head: Oid) -> String {
+    format!("refs/forks/{}/{}/{}/{}", log.resource, log.fingerprint, log.device, head)
+}
+
+/// Moves the head of a log received from another device forward. All the commits that are new to
+/// us must be signed by the author of the log, whatever the head is. A head that does not descend
+/// from the previously accepted one never replaces it, so updates that were accepted are not
+/// dropped by a rewritten history. If the rewritten history is signed by the author it is kept
+/// as a `Fork`, otherwise it is refused.
+pub(crate) fn update_head(
+    doc: &Document,
+    verifier: &mut CommitVerifier,
+    log: &Log,
+    head: Oid,
+) -> Result<HeadUpdate, Error> {
+    let repository = &doc.repository;
+    let store = doc.event_log_store();
+    let accepted = store.head(log)?.map(|head| Oid::from_str(&head)).transpose()?;
+
+    if let Some(accepted) = accepted {
+        if accepted == head || repository.graph_descendant_of(accepted, head)? {
+            return Ok(HeadUpdate::Stale);
+        }
+    }
+
+    let commits = new_commits(repository, head, accepted)?;
+    for oid in &commits {
+        let signer = verifier.verify(doc, *oid)?;
+        if signer != log.fingerprint {
+            return Err(Error::DcoreError(format!(
+                "{} belongs to {} but commit {} is signed by {}.",
+                log, log.fingerprint, oid, signer
+            )));
+        }
+    }
+
+    if let Some(accepted) = accepted {
+        if !repository.graph_descendant_of(head, accepted)? {
+            repository.reference(&fork_reference(log, head), head, true, "fork of a device log")?;
+            return Ok(HeadUpdate::Forked(Fork {
+                log: log.clone(),
+                accepted: accepted.to_string(),
+                forked: head.to_string(),
+            }));
+        }
+    }
+
+    store.set_head(log, &head.to_string())?;
+    Ok(HeadUpdate::Accepted(commits.len()))
+}
+
+fn new_commits(repository: &Repository, head: Oid, known: Option<Oid>) -> Result<Vec<Oid>, Error> {
+    let mut revwalk = repository.revwalk()?;
+    revwalk.push(head)?;
+    if let Some(known) = known {
+        revwalk.hide(known)?;
+    }
+    Ok(revwalk.collect::<Result<Vec<Oid>, git2::Error>>()?)
+}
+
+/// All forks that were detected so far.
+pub fn forks(doc: &Document) -> Result<Vec<Fork>, Error> {
+    let store = doc.event_log_store();
+    let mut forks = Vec::new();
+    for reference in doc.repository.references_glob("refs/forks/*")? {
+        let reference = reference?;
+        let parts: Vec<&str> = reference.name().unwrap_or_default().split('/').collect();
+        if parts.len() != 6 {
+            continue;
+        }
+        let log = Log {
+            kind: LogKind::Origin,
+            resource: parts[2].to_string(),
+            fingerprint: parts[3].to_string(),
+            device: parts[4].to_string(),
+        };
+        forks.push(Fork {
+            accepted: store.head(&log)?.unwrap_or_default(),
+            forked: parts[5].to_string(),
+            log,
+        });
+    }
+    Ok(forks)
+}
+
+/// The fingerprints of the identities that published forked histories.
+pub fn flagged_authors(doc: &Document) -> Result<BTreeSet<String>, Error> {
+    Ok(forks(doc)?.into_iter().map(|fork| fork.log.fingerprint).collect())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use git2::Oid;
+
+    use crate::document_utils::CommitVerifier;
+    use crate::equivocation::{flagged_authors, forks, update_head, HeadUpdate};
+    use crate::event_log_store::{EventLogStore, Log, LogKind};
+    use crate::fsck::{fsck, ProblemKind};
+    use crate::test_utils::{create_test_document, get_test_key};
+
+    #[test]
+    fn keep_forked_histories() {
+        let doc_dir = "./.test/equivocation/keep_forked_histories/";
+        let fingerprint = get_test_key().fingerprint;
+        let doc = create_test_document(doc_dir);
+
+        // two histories of the log of another device of ours, both signed by us
+        let store = doc.event_log_store();
+        let sign = |data: &str| doc.identity.gpg()?.sign_string(&data.to_string(), &doc.identity);
+        let first = Log::local("test", &fingerprint, "device-x");
+        let second = Log::local("test", &fingerprint, "device-y");
+        let base = Oid::from_str(&store.append(&first, &[1], None, &sign).unwrap()).unwrap();
+        let appended = Oid::from_str(&store.append(&first, &[2], None, &sign).unwrap()).unwrap();
+        let rewritten = Oid::from_str(&store.append(&second, &[3], None, &sign).unwrap()).unwrap();
+
+        let origin = Log {
+            kind: LogKind::Origin,
+            ..first.clone()
+        };
+        let verifier = &mut CommitVerifier::new(&doc).unwrap();
+        assert_eq!(update_head(&doc, verifier, &origin, base).unwrap(), HeadUpdate::Accepted(1));
+        assert_eq!(update_head(&doc, verifier, &origin, appended).unwrap(), HeadUpdate::Accepted(1));
+        assert_eq!(update_head(&doc, verifier, &origin, base).unwrap(), HeadUpdate::Stale);
+        assert!(matches!(
+            update_head(&doc, verifier, &origin, rewritten).unwrap(),
+            HeadUpdate::Forked(_)
+        ));
+
+        // the accepted updates are not dropped
+        assert_eq!(store.head(&origin).unwrap(), Some(appended.to_string()));
+        let forks = forks(&doc).unwrap();
+        assert_eq!(forks.len(), 1);
+        assert_eq!(forks[0].log, origin);
+        assert_eq!(forks[0].forked, rewritten.to_string());
+        assert!(flagged_authors(&doc).unwrap().contains(&fingerprint));
+
+        // and reported by fsck
+        let report = fsck(&doc).unwrap();
+        assert!(report.problems.iter().any(|problem| problem.kind == ProblemKind::DivergentHeads
+            && problem.log == origin.reference()
+            && problem.message.contains(&rewritten.to_string())));
+    }
+}
@@ -21,4 +21,10 @@ pub enum Error {
 
     #[error("`{0}`")]
     DcoreError(String),
+
+    #[error("`{0}`")]
+    ValidationError(String),
+
+    #[error("`{0}`")]
+    FormatError(String),
 }
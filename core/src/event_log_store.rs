@@ -0,0 +1,454 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+
+use git2::{ErrorCode, Oid, Repository};
+use yrs::updates::decoder::Decode;
+use yrs::Update;
+
+use crate::errors::Error;
+use crate::resource::Resource;
+use crate::update_meta::UpdateMeta;
+
+/// Whether a log is written by this device or was received from another one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum LogKind {
+    Local,
+    Origin,
+}
+
+/// The event-log of a resource written by one device of one identity.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Log {
+    pub kind: LogKind,
+    pub resource: String,
+    pub fingerprint: String,
+    pub device: String,
+}
+
+impl Log {
+    pub fn local(resource: &str, fingerprint: &str, device: &str) -> Log {
+        Log {
+            kind: LogKind::Local,
+            resource: resource.to_string(),
+            fingerprint: fingerprint.to_string(),
+            device: device.to_string(),
+        }
+    }
+
+    /// The git reference of the log, `refs/{local|origin}/{resource}/{fingerprint}/{device}`.
+    pub fn reference(&self) -> String {
+        let kind = match self.kind {
+            LogKind::Local => "local",
+            LogKind::Origin => "origin",
+        };
+        format!("refs/{}/{}/{}/{}", kind, self.resource, self.fingerprint, self.device)
+    }
+
+    /// The log of a git reference, None if the reference is not an event-log.
+    pub fn from_reference(reference: &str) -> Option<Log> {
+        let parts: Vec<&str> = reference.split('/').collect();
+        if parts.len() != 5 || parts[0] != "refs" {
+            return None;
+        }
+        let kind = match parts[1] {
+            "local" => LogKind::Local,
+            "origin" => LogKind::Origin,
+            _ => return None,
+        };
+        Some(Log {
+            kind,
+            resource: parts[2].to_string(),
+            fingerprint: parts[3].to_string(),
+            device: parts[4].to_string(),
+        })
+    }
+}
+
+impl fmt::Display for Log {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.reference())
+    }
+}
+
+/// Where the signed event-logs of a document are kept.
+///
+/// Heads are opaque ids chosen by the store, e.g. commit oids in git.
+pub trait EventLogStore {
+    /// Appends a yrs update and its metadata to a log and returns the new head. `sign` returns the
+    /// armored detached signature of the data it is given, what is signed is up to the store.
+    fn append(
+        &self,
+        log: &Log,
+        update: &[u8],
+        meta: Option<&UpdateMeta>,
+        sign: &dyn Fn(&str) -> Result<String, Error>,
+    ) -> Result<String, Error>;
+
+    /// All logs of the store, in no particular order.
+    fn logs(&self) -> Result<Vec<Log>, Error>;
+
+    /// The updates of a log, oldest first.
+    fn updates<'a>(&'a self, log: &Log) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>, Error>> + 'a>, Error>;
+
+    /// The metadata of the updates of a log, oldest first. None for updates appended without.
+    fn metas<'a>(&'a self, log: &Log)
+        -> Result<Box<dyn Iterator<Item = Result<Option<UpdateMeta>, Error>> + 'a>, Error>;
+
+    /// The metadata of the head of a log.
+    fn head_meta(&self, log: &Log) -> Result<Option<UpdateMeta>, Error> {
+        Ok(self.metas(log)?.last().transpose()?.flatten())
+    }
+
+    /// The head of a log, None if the log does not exist.
+    fn head(&self, log: &Log) -> Result<Option<String>, Error>;
+
+    /// Points a log to an entry that is already in the store, e.g. one received from a peer.
+    fn set_head(&self, log: &Log, head: &str) -> Result<(), Error>;
+}
+
+/// Replays all logs of a store, returns the resources by name.
+pub fn load_resources(store: &dyn EventLogStore) -> Result<HashMap<String, Resource>, Error> {
+    load_resources_with(store, &mut |_, _| ())
+}
+
+/// Like `load_resources`, `applied` is called after the updates of each log were applied to its resource.
+pub fn load_resources_with(
+    store: &dyn EventLogStore,
+    applied: &mut dyn FnMut(&Resource, &Log),
+) -> Result<HashMap<String, Resource>, Error> {
+    let mut logs_per_resource: BTreeMap<String, Vec<Log>> = BTreeMap::new();
+    for log in store.logs()? {
+        logs_per_resource.entry(log.resource.clone()).or_default().push(log);
+    }
+
+    let mut resources = HashMap::new();
+    for (name, logs) in logs_per_resource {
+        let resource = Resource::new(&name);
+        for log in logs {
+            let mut transaction = resource.store.transact();
+            for update in store.updates(&log)? {
+                let update = Update::decode_v2(update?.as_slice())
+                    .map_err(|e| Error::DcoreError(format!("Could not decode an update of {}: {}", log, e)))?;
+                // merge_updates leads to an nondeterministic result, so the updates are applied one by one
+                transaction.apply_update(update);
+            }
+            transaction.commit();
+            drop(transaction);
+            applied(&resource, &log);
+        }
+        resources.insert(name, resource);
+    }
+    Ok(resources)
+}
+
+/// The logs are branches of signed commits in the bare repository of the document,
+/// every commit has a tree with the update in the blob `update` and its metadata in the blob `meta`.
+pub struct GitEventLogStore<'r> {
+    repository: &'r Repository,
+}
+
+impl<'r> GitEventLogStore<'r> {
+    pub fn new(repository: &'r Repository) -> GitEventLogStore<'r> {
+        GitEventLogStore { repository }
+    }
+
+    /// The yrs update stored in a log commit.
+    pub(crate) fn read_update(&self, oid: Oid) -> Result<Vec<u8>, Error> {
+        self.read_blob(oid, "update")?
+            .ok_or_else(|| Error::DcoreError(format!("Commit {} has no update.", oid)))
+    }
+
+    /// The metadata of the update stored in a log commit, as it is in the blob `meta`.
+    pub(crate) fn read_meta(&self, oid: Oid) -> Result<Option<Vec<u8>>, Error> {
+        self.read_blob(oid, "meta")
+    }
+
+    fn read_blob(&self, oid: Oid, name: &str) -> Result<Option<Vec<u8>>, Error> {
+        let tree = self.repository.find_commit(oid)?.tree()?;
+        match tree.get_name(name) {
+            Some(entry) => Ok(Some(entry.to_object(self.repository)?.peel_to_blob()?.content().to_vec())),
+            None => Ok(None),
+        }
+    }
+
+    /// The commits of a log, oldest first.
+    fn commits(&self, log: &Log) -> Result<Vec<Oid>, Error> {
+        let head = match self.head_oid(log)? {
+            Some(head) => head,
+            None => return Ok(Vec::new()),
+        };
+        let mut revwalk = self.repository.revwalk()?;
+        revwalk.set_sorting(git2::Sort::REVERSE)?;
+        revwalk.push(head)?;
+        Ok(revwalk.collect::<Result<Vec<Oid>, git2::Error>>()?)
+    }
+
+    fn head_oid(&self, log: &Log) -> Result<Option<Oid>, Error> {
+        match self.repository.find_reference(&log.reference()) {
+            Ok(reference) => Ok(reference.target()),
+            Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl EventLogStore for GitEventLogStore<'_> {
+    fn append(
+        &self,
+        log: &Log,
+        update: &[u8],
+        meta: Option<&UpdateMeta>,
+        sign: &dyn Fn(&str) -> Result<String, Error>,
+    ) -> Result<String, Error> {
+        let repo = self.repository;
+        let parent = match self.head_oid(log)? {
+            Some(oid) => Some(repo.find_commit(oid)?),
+            None => None,
+        };
+
+        let update_oid = repo.blob(update)?;
+        let mut builder = repo.treebuilder(None)?;
+        builder.insert("update", update_oid, 0o100644)?;
+        if let Some(meta) = meta {
+            builder.insert("meta", repo.blob(&meta.to_bytes()?)?, 0o100644)?;
+        }
+        let update_tree = repo.find_tree(builder.write()?)?;
+        // todo: pass signature info from config
+        let authors_signature = git2::Signature::now("Alice", "info@colomba.link")?;
+        let parents: Vec<&git2::Commit> = parent.iter().collect();
+        let commit_buffer = repo.commit_create_buffer(
+            &authors_signature,
+            &authors_signature,
+            "update.",
+            &update_tree,
+            &parents,
+        )?;
+        let commit_string = commit_buffer
+            .as_str()
+            .ok_or_else(|| Error::DcoreError("The commit is not valid UTF-8.".to_string()))?;
+
+        let commit_signature = sign(commit_string)?;
+        // git does not expect the trailing new line of the armored signature
+        let commit_signature = commit_signature.trim_end_matches('\n');
+        let commit = repo.commit_signed(commit_string, commit_signature, Some("gpgsig"))?;
+
+        repo.reference(&log.reference(), commit, true, "update ref")?;
+        Ok(commit.to_string())
+    }
+
+    fn logs(&self) -> Result<Vec<Log>, Error> {
+        let mut logs = Vec::new();
+        for reference in self.repository.references()? {
+            if let Some(log) = reference?.name().and_then(Log::from_reference) {
+                logs.push(log);
+            }
+        }
+        Ok(logs)
+    }
+
+    fn updates<'a>(&'a self, log: &Log) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>, Error>> + 'a>, Error> {
+        let commits = self.commits(log)?;
+        Ok(Box::new(commits.into_iter().map(move |oid| self.read_update(oid))))
+    }
+
+    fn metas<'a>(&'a self, log: &Log)
+        -> Result<Box<dyn Iterator<Item = Result<Option<UpdateMeta>, Error>> + 'a>, Error> {
+        let commits = self.commits(log)?;
+        Ok(Box::new(commits.into_iter().map(move |oid| {
+            self.read_meta(oid)?.map(|meta| UpdateMeta::from_bytes(&meta)).transpose()
+        })))
+    }
+
+    fn head_meta(&self, log: &Log) -> Result<Option<UpdateMeta>, Error> {
+        match self.head_oid(log)? {
+            Some(head) => self.read_meta(head)?.map(|meta| UpdateMeta::from_bytes(&meta)).transpose(),
+            None => Ok(None),
+        }
+    }
+
+    fn head(&self, log: &Log) -> Result<Option<String>, Error> {
+        Ok(self.head_oid(log)?.map(|oid| oid.to_string()))
+    }
+
+    fn set_head(&self, log: &Log, head: &str) -> Result<(), Error> {
+        let oid = Oid::from_str(head)?;
+        self.repository.find_commit(oid)?;
+        self.repository.reference(&log.reference(), oid, true, "set head")?;
+        Ok(())
+    }
+}
+
+struct MemoryEntry {
+    parent: Option<usize>,
+    update: Vec<u8>,
+    meta: Option<UpdateMeta>,
+    signature: String,
+}
+
+/// Keeps the logs in memory, heads are the indices of the entries.
+#[derive(Default)]
+pub struct MemoryEventLogStore {
+    entries: RefCell<Vec<MemoryEntry>>,
+    heads: RefCell<BTreeMap<Log, usize>>,
+}
+
+impl MemoryEventLogStore {
+    pub fn new() -> MemoryEventLogStore {
+        MemoryEventLogStore::default()
+    }
+
+    /// The indices of the entries of a log, oldest first.
+    fn chain(&self, log: &Log) -> Vec<usize> {
+        let entries = self.entries.borrow();
+        let mut chain = Vec::new();
+        let mut next = self.heads.borrow().get(log).copied();
+        while let Some(index) = next {
+            chain.push(index);
+            next = entries[index].parent;
+        }
+        chain.reverse();
+        chain
+    }
+
+    /// The signature of an entry.
+    pub fn signature(&self, head: &str) -> Option<String> {
+        let index = head.parse::<usize>().ok()?;
+        self.entries.borrow().get(index).map(|entry| entry.signature.clone())
+    }
+}
+
+impl EventLogStore for MemoryEventLogStore {
+    fn append(
+        &self,
+        log: &Log,
+        update: &[u8],
+        meta: Option<&UpdateMeta>,
+        sign: &dyn Fn(&str) -> Result<String, Error>,
+    ) -> Result<String, Error> {
+        let parent = self.heads.borrow().get(log).copied();
+        let update_hex: String = update.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let meta_json = match meta {
+            Some(meta) => String::from_utf8_lossy(&meta.to_bytes()?).to_string(),
+            None => "none".to_string(),
+        };
+        let signed_data = format!(
+            "log {}\nparent {}\nupdate {}\nmeta {}\n",
+            log,
+            parent.map_or("none".to_string(), |parent| parent.to_string()),
+            update_hex,
+            meta_json
+        );
+        let signature = sign(&signed_data)?;
+
+        let mut entries = self.entries.borrow_mut();
+        entries.push(MemoryEntry {
+            parent,
+            update: update.to_vec(),
+            meta: meta.cloned(),
+            signature,
+        });
+        let head = entries.len() - 1;
+        self.heads.borrow_mut().insert(log.clone(), head);
+        Ok(head.to_string())
+    }
+
+    fn logs(&self) -> Result<Vec<Log>, Error> {
+        Ok(self.heads.borrow().keys().cloned().collect())
+    }
+
+    fn updates<'a>(&'a self, log: &Log) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>, Error>> + 'a>, Error> {
+        let entries = self.entries.borrow();
+        let updates: Vec<_> = self.chain(log).into_iter().map(|index| Ok(entries[index].update.clone())).collect();
+        Ok(Box::new(updates.into_iter()))
+    }
+
+    fn metas<'a>(&'a self, log: &Log)
+        -> Result<Box<dyn Iterator<Item = Result<Option<UpdateMeta>, Error>> + 'a>, Error> {
+        let entries = self.entries.borrow();
+        let metas: Vec<_> = self.chain(log).into_iter().map(|index| Ok(entries[index].meta.clone())).collect();
+        Ok(Box::new(metas.into_iter()))
+    }
+
+    fn head(&self, log: &Log) -> Result<Option<String>, Error> {
+        Ok(self.heads.borrow().get(log).map(|head| head.to_string()))
+    }
+
+    fn set_head(&self, log: &Log, head: &str) -> Result<(), Error> {
+        let index = head
+            .parse::<usize>()
+            .ok()
+            .filter(|index| *index < self.entries.borrow().len())
+            .ok_or_else(|| Error::DcoreError(format!("{} is not an entry of the store.", head)))?;
+        self.heads.borrow_mut().insert(log.clone(), index);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::event_log_store::{load_resources, EventLogStore, Log, LogKind, MemoryEventLogStore};
+    use crate::resource::Resource;
+
+    fn sign(data: &str) -> Result<String, crate::errors::Error> {
+        Ok(format!("signature of {} bytes", data.len()))
+    }
+
+    #[test]
+    fn log_references() {
+        let log = Log::local("test", "FP", "device-0");
+        assert_eq!(log.reference(), "refs/local/test/FP/device-0");
+        assert_eq!(Log::from_reference("refs/local/test/FP/device-0"), Some(log));
+        let origin = Log::from_reference("refs/origin/test/FP/device-1").unwrap();
+        assert_eq!(origin.kind, LogKind::Origin);
+        assert_eq!(Log::from_reference("refs/heads/master"), None);
+        assert_eq!(Log::from_reference("refs/heads/test/FP/device-1"), None);
+    }
+
+    #[test]
+    fn memory_store() {
+        let store = MemoryEventLogStore::new();
+        let log = Log::local("test", "FP", "device-0");
+        assert_eq!(store.head(&log).unwrap(), None);
+
+        let first = store.append(&log, &[1], None, &sign).unwrap();
+        let second = store.append(&log, &[2], None, &sign).unwrap();
+        assert_eq!(store.head(&log).unwrap(), Some(second.clone()));
+        assert!(store.signature(&second).is_some());
+        let updates: Vec<Vec<u8>> = store.updates(&log).unwrap().map(Result::unwrap).collect();
+        assert_eq!(updates, vec![vec![1], vec![2]]);
+
+        // a log received from another device that only knows the first update
+        let origin = Log {
+            kind: LogKind::Origin,
+            ..log.clone()
+        };
+        store.set_head(&origin, &first).unwrap();
+        assert_eq!(store.logs().unwrap().len(), 2);
+        let updates: Vec<Vec<u8>> = store.updates(&origin).unwrap().map(Result::unwrap).collect();
+        assert_eq!(updates, vec![vec![1]]);
+        assert!(store.set_head(&origin, "42").is_err());
+    }
+
+    #[test]
+    fn load_resources_from_memory_store() {
+        let store = MemoryEventLogStore::new();
+        let mut resource = Resource::new(&"test".to_string());
+        let log = Log::local("test", "FP", "device-0");
+        let update = resource.set_resource_meta(&"test".to_string()).unwrap();
+        store.append(&log, &update, None, &sign).unwrap();
+        let update = resource
+            .add_local_update(|transaction| {
+                let root = transaction.get_map("root");
+                root.insert(transaction, "entry".to_owned(), "1234");
+                transaction
+            })
+            .unwrap();
+        store.append(&log, &update, None, &sign).unwrap();
+
+        let resources = load_resources(&store).unwrap();
+        assert_eq!(resources.len(), 1);
+        assert_eq!(resources["test"].get_content(), "{entry: 1234}");
+    }
+}
@@ -0,0 +1,495 @@
+use std::path::Path;
+
+use git2::{ObjectType, Oid, Repository};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::errors::Error;
+use crate::event_log_store::{EventLogStore, GitEventLogStore, Log, LogKind};
+use crate::update_meta::UpdateMeta;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS updates (
+    id TEXT PRIMARY KEY,
+    resource TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    device TEXT NOT NULL,
+    sequence INTEGER NOT NULL,
+    parent TEXT REFERENCES updates(id),
+    signed_data TEXT NOT NULL,
+    signature TEXT NOT NULL,
+    update_bytes BLOB NOT NULL,
+    meta BLOB
+);
+CREATE INDEX IF NOT EXISTS updates_by_log ON updates (resource, fingerprint, device, sequence);
+CREATE TABLE IF NOT EXISTS heads (
+    kind TEXT NOT NULL,
+    resource TEXT NOT NULL,
+    fingerprint TEXT NOT NULL,
+    device TEXT NOT NULL,
+    head TEXT NOT NULL REFERENCES updates(id),
+    PRIMARY KEY (kind, resource, fingerprint, device)
+);
+";
+
+fn sqlite_error(e: rusqlite::Error) -> Error {
+    Error::DcoreError(format!("SQLite: {}", e))
+}
+
+fn kind_name(kind: LogKind) -> &'static str {
+    match kind {
+        LogKind::Local => "local",
+        LogKind::Origin => "origin",
+    }
+}
+
+/// A row of the `updates` table, `sequence` is the position in the log starting at 0.
+/// `meta` is the JSON of the `UpdateMeta`, as it is in the blob `meta` of the git commit.
+struct StoredUpdate<'a> {
+    id: &'a str,
+    sequence: i64,
+    parent: Option<&'a str>,
+    signed_data: &'a str,
+    signature: &'a str,
+    update: &'a [u8],
+    meta: Option<&'a [u8]>,
+}
+
+/// Keeps the event-logs in a SQLite database, for devices where a git object store is too heavy.
+///
+/// Every update is signed exactly like by the `GitEventLogStore`: the signed data is the git commit
+/// that holds the update, and the id of an update is the oid of the signed commit. So the logs can
+/// be exported to a git repository and synced with git hubs, see `export_git` and `import_git`.
+pub struct SqliteEventLogStore {
+    connection: Connection,
+}
+
+impl SqliteEventLogStore {
+    pub fn open(path: &Path) -> Result<SqliteEventLogStore, Error> {
+        Self::with_connection(Connection::open(path).map_err(sqlite_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<SqliteEventLogStore, Error> {
+        Self::with_connection(Connection::open_in_memory().map_err(sqlite_error)?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<SqliteEventLogStore, Error> {
+        connection.execute_batch(SCHEMA).map_err(sqlite_error)?;
+        // stores created before updates had metadata
+        let has_meta: i64 = connection
+            .query_row("SELECT COUNT(*) FROM pragma_table_info('updates') WHERE name = 'meta'", [], |row| row.get(0))
+            .map_err(sqlite_error)?;
+        if has_meta == 0 {
+            connection
+                .execute_batch("ALTER TABLE updates ADD COLUMN meta BLOB")
+                .map_err(sqlite_error)?;
+        }
+        Ok(SqliteEventLogStore { connection })
+    }
+
+    /// The commit git would write for the update, without its signature.
+    fn commit_buffer(update: &[u8], meta: Option<&[u8]>, parent: Option<&str>) -> Result<String, Error> {
+        // the entries of a git tree are sorted by name
+        let mut tree = Vec::new();
+        if let Some(meta) = meta {
+            tree.extend_from_slice(b"100644 meta\0");
+            tree.extend_from_slice(Oid::hash_object(ObjectType::Blob, meta)?.as_bytes());
+        }
+        tree.extend_from_slice(b"100644 update\0");
+        tree.extend_from_slice(Oid::hash_object(ObjectType::Blob, update)?.as_bytes());
+        let tree = Oid::hash_object(ObjectType::Tree, &tree)?;
+
+        // todo: pass signature info from config, like in GitEventLogStore::append
+        let author = git2::Signature::now("Alice", "info@colomba.link")?;
+        let offset = author.when().offset_minutes();
+        let person = format!(
+            "{} <{}> {} {}{:02}{:02}",
+            author.name().unwrap_or_default(),
+            author.email().unwrap_or_default(),
+            author.when().seconds(),
+            if offset < 0 { '-' } else { '+' },
+            offset.abs() / 60,
+            offset.abs() % 60
+        );
+
+        let mut buffer = format!("tree {}\n", tree);
+        if let Some(parent) = parent {
+            buffer.push_str(&format!("parent {}\n", parent));
+        }
+        buffer.push_str(&format!("author {}\ncommitter {}\n\nupdate.", person, person));
+        Ok(buffer)
+    }
+
+    /// The oid of the commit with the signature in its `gpgsig` header, as written by `Repository::commit_signed`.
+    fn signed_commit_oid(signed_data: &str, signature: &str) -> Result<Oid, Error> {
+        let header_end = signed_data
+            .find("\n\n")
+            .ok_or_else(|| Error::DcoreError("The signed data is not a commit.".to_string()))?
+            + 1;
+        let commit = format!(
+            "{}gpgsig {}\n{}",
+            &signed_data[..header_end],
+            signature.replace('\n', "\n "),
+            &signed_data[header_end..]
+        );
+        Ok(Oid::hash_object(ObjectType::Commit, commit.as_bytes())?)
+    }
+
+    fn insert_update(&self, log: &Log, update: StoredUpdate) -> Result<(), Error> {
+        self.connection
+            .execute(
+                "INSERT OR IGNORE INTO updates
+                 (id, resource, fingerprint, device, sequence, parent, signed_data, signature, update_bytes, meta)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)",
+                params![
+                    update.id,
+                    log.resource,
+                    log.fingerprint,
+                    log.device,
+                    update.sequence,
+                    update.parent,
+                    update.signed_data,
+                    update.signature,
+                    update.update,
+                    update.meta
+                ],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+
+    /// The updates and metadata of a log, oldest first.
+    fn chain(&self, log: &Log) -> Result<Vec<(Vec<u8>, Option<Vec<u8>>)>, Error> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "WITH RECURSIVE chain(id, parent, sequence, update_bytes, meta) AS (
+                     SELECT u.id, u.parent, u.sequence, u.update_bytes, u.meta FROM updates u
+                     JOIN heads h ON h.head = u.id
+                     WHERE h.kind = ?1 AND h.resource = ?2 AND h.fingerprint = ?3 AND h.device = ?4
+                     UNION ALL
+                     SELECT u.id, u.parent, u.sequence, u.update_bytes, u.meta FROM updates u
+                     JOIN chain c ON u.id = c.parent
+                 )
+                 SELECT update_bytes, meta FROM chain ORDER BY sequence",
+            )
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map(
+                params![kind_name(log.kind), log.resource, log.fingerprint, log.device],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(sqlite_error)?;
+        rows.collect::<Result<Vec<_>, rusqlite::Error>>().map_err(sqlite_error)
+    }
+
+    fn contains(&self, id: &str) -> Result<bool, Error> {
+        self.connection
+            .query_row("SELECT 1 FROM updates WHERE id = ?1", params![id], |_| Ok(()))
+            .optional()
+            .map(|row| row.is_some())
+            .map_err(sqlite_error)
+    }
+
+    /// Copies the logs of a git repository, e.g. one that was just fetched from a hub, into the store.
+    /// Returns the number of new updates.
+    pub fn import_git(&self, repository: &Repository) -> Result<usize, Error> {
+        let git_store = GitEventLogStore::new(repository);
+        let mut imported = 0;
+        for log in git_store.logs()? {
+            let head = match git_store.head(&log)? {
+                Some(head) => head,
+                None => continue,
+            };
+            let mut revwalk = repository.revwalk()?;
+            revwalk.set_sorting(git2::Sort::REVERSE)?;
+            revwalk.push(Oid::from_str(&head)?)?;
+            for (sequence, oid) in revwalk.enumerate() {
+                let oid = oid?;
+                let id = oid.to_string();
+                if self.contains(&id)? {
+                    continue;
+                }
+                let (signature, signed_data) = repository.extract_signature(&oid, None)?;
+                let parent = repository.find_commit(oid)?.parent_id(0).ok().map(|parent| parent.to_string());
+                let signature = signature
+                    .as_str()
+                    .ok_or_else(|| Error::DcoreError(format!("The signature of {} is not valid UTF-8.", oid)))?;
+                let signed_data = signed_data
+                    .as_str()
+                    .ok_or_else(|| Error::DcoreError(format!("Commit {} is not valid UTF-8.", oid)))?;
+                let update = git_store.read_update(oid)?;
+                let meta = git_store.read_meta(oid)?;
+                self.insert_update(
+                    &log,
+                    StoredUpdate {
+                        id: &id,
+                        sequence: sequence as i64,
+                        parent: parent.as_deref(),
+                        signed_data,
+                        signature,
+                        update: &update,
+                        meta: meta.as_deref(),
+                    },
+                )?;
+                imported += 1;
+            }
+            self.set_head(&log, &head)?;
+        }
+        Ok(imported)
+    }
+
+    /// Writes the logs of the store as signed commits and references into a git repository, e.g. to
+    /// push them to a hub. Returns the number of new commits.
+    pub fn export_git(&self, repository: &Repository) -> Result<usize, Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT id, signed_data, signature, update_bytes, meta FROM updates ORDER BY sequence")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, Vec<u8>>(3)?,
+                    row.get::<_, Option<Vec<u8>>>(4)?,
+                ))
+            })
+            .map_err(sqlite_error)?;
+
+        let mut exported = 0;
+        for row in rows {
+            let (id, signed_data, signature, update, meta) = row.map_err(sqlite_error)?;
+            let oid = Oid::from_str(&id)?;
+            if repository.find_commit(oid).is_ok() {
+                continue;
+            }
+            let update_oid = repository.blob(&update)?;
+            let mut builder = repository.treebuilder(None)?;
+            builder.insert("update", update_oid, 0o100644)?;
+            if let Some(meta) = meta {
+                builder.insert("meta", repository.blob(&meta)?, 0o100644)?;
+            }
+            builder.write()?;
+            let commit = repository.commit_signed(&signed_data, &signature, Some("gpgsig"))?;
+            if commit != oid {
+                return Err(Error::DcoreError(format!(
+                    "Update {} became commit {} in git.",
+                    id, commit
+                )));
+            }
+            exported += 1;
+        }
+
+        for log in self.logs()? {
+            if let Some(head) = self.head(&log)? {
+                repository.reference(&log.reference(), Oid::from_str(&head)?, true, "export from sqlite")?;
+            }
+        }
+        Ok(exported)
+    }
+}
+
+impl EventLogStore for SqliteEventLogStore {
+    fn append(
+        &self,
+        log: &Log,
+        update: &[u8],
+        meta: Option<&UpdateMeta>,
+        sign: &dyn Fn(&str) -> Result<String, Error>,
+    ) -> Result<String, Error> {
+        let parent = self.head(log)?;
+        let sequence: i64 = match &parent {
+            Some(parent) => self
+                .connection
+                .query_row("SELECT sequence + 1 FROM updates WHERE id = ?1", params![parent], |row| row.get(0))
+                .map_err(sqlite_error)?,
+            None => 0,
+        };
+
+        let meta = meta.map(UpdateMeta::to_bytes).transpose()?;
+        let signed_data = Self::commit_buffer(update, meta.as_deref(), parent.as_deref())?;
+        let signature = sign(&signed_data)?;
+        // git does not expect the trailing new line of the armored signature
+        let signature = signature.trim_end_matches('\n').to_string();
+        let id = Self::signed_commit_oid(&signed_data, &signature)?.to_string();
+
+        self.insert_update(
+            log,
+            StoredUpdate {
+                id: &id,
+                sequence,
+                parent: parent.as_deref(),
+                signed_data: &signed_data,
+                signature: &signature,
+                update,
+                meta: meta.as_deref(),
+            },
+        )?;
+        self.set_head(log, &id)?;
+        Ok(id)
+    }
+
+    fn logs(&self) -> Result<Vec<Log>, Error> {
+        let mut statement = self
+            .connection
+            .prepare("SELECT kind, resource, fingerprint, device FROM heads")
+            .map_err(sqlite_error)?;
+        let rows = statement
+            .query_map([], |row| {
+                let kind: String = row.get(0)?;
+                Ok(Log {
+                    kind: if kind == "local" { LogKind::Local } else { LogKind::Origin },
+                    resource: row.get(1)?,
+                    fingerprint: row.get(2)?,
+                    device: row.get(3)?,
+                })
+            })
+            .map_err(sqlite_error)?;
+        rows.collect::<Result<Vec<Log>, rusqlite::Error>>().map_err(sqlite_error)
+    }
+
+    fn updates<'a>(&'a self, log: &Log) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>, Error>> + 'a>, Error> {
+        let updates = self
+            .chain(log)?
+            .into_iter()
+            .map(|(update, _)| Ok(update))
+            .collect::<Vec<_>>();
+        Ok(Box::new(updates.into_iter()))
+    }
+
+    fn metas<'a>(&'a self, log: &Log)
+        -> Result<Box<dyn Iterator<Item = Result<Option<UpdateMeta>, Error>> + 'a>, Error> {
+        let metas = self
+            .chain(log)?
+            .into_iter()
+            .map(|(_, meta)| meta.map(|meta| UpdateMeta::from_bytes(&meta)).transpose())
+            .collect::<Vec<_>>();
+        Ok(Box::new(metas.into_iter()))
+    }
+
+    fn head_meta(&self, log: &Log) -> Result<Option<UpdateMeta>, Error> {
+        let meta: Option<Option<Vec<u8>>> = self
+            .connection
+            .query_row(
+                "SELECT updates.meta FROM heads JOIN updates ON updates.id = heads.head \
+                 WHERE heads.kind = ?1 AND heads.resource = ?2 AND heads.fingerprint = ?3 AND heads.device = ?4",
+                params![kind_name(log.kind), log.resource, log.fingerprint, log.device],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_error)?;
+        meta.flatten().map(|meta| UpdateMeta::from_bytes(&meta)).transpose()
+    }
+
+    fn head(&self, log: &Log) -> Result<Option<String>, Error> {
+        self.connection
+            .query_row(
+                "SELECT head FROM heads WHERE kind = ?1 AND resource = ?2 AND fingerprint = ?3 AND device = ?4",
+                params![kind_name(log.kind), log.resource, log.fingerprint, log.device],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(sqlite_error)
+    }
+
+    fn set_head(&self, log: &Log, head: &str) -> Result<(), Error> {
+        if !self.contains(head)? {
+            return Err(Error::DcoreError(format!("{} is not an update of the store.", head)));
+        }
+        self.connection
+            .execute(
+                "INSERT OR REPLACE INTO heads (kind, resource, fingerprint, device, head) VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![kind_name(log.kind), log.resource, log.fingerprint, log.device, head],
+            )
+            .map_err(sqlite_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeMap;
+    use std::fs;
+
+    use git2::{Repository, RepositoryInitOptions};
+
+    use crate::event_log_store::{load_resources, EventLogStore, GitEventLogStore, Log};
+    use crate::event_log_store_sqlite::SqliteEventLogStore;
+    use crate::resource::Resource;
+    use crate::update_meta::{UpdateMeta, UPDATE_FORMAT_VERSION};
+
+    fn sign(data: &str) -> Result<String, crate::errors::Error> {
+        Ok(format!("-----BEGIN PGP SIGNATURE-----\n\n{} bytes\n-----END PGP SIGNATURE-----\n", data.len()))
+    }
+
+    fn updates(store: &dyn EventLogStore, log: &Log) -> Vec<Vec<u8>> {
+        store.updates(log).unwrap().map(Result::unwrap).collect()
+    }
+
+    #[test]
+    fn sqlite_store() {
+        let store = SqliteEventLogStore::open_in_memory().unwrap();
+        let mut resource = Resource::new(&"test".to_string());
+        let log = Log::local("test", "FP", "device-0");
+        assert_eq!(store.head(&log).unwrap(), None);
+
+        let update = resource.set_resource_meta(&"test".to_string()).unwrap();
+        store.append(&log, &update, None, &sign).unwrap();
+        let update = resource
+            .add_local_update(|transaction| {
+                let root = transaction.get_map("root");
+                root.insert(transaction, "entry".to_owned(), "1234");
+                transaction
+            })
+            .unwrap();
+        let head = store.append(&log, &update, None, &sign).unwrap();
+        assert_eq!(store.head(&log).unwrap(), Some(head));
+        assert_eq!(updates(&store, &log).len(), 2);
+        assert!(store.set_head(&log, "0000000000000000000000000000000000000000").is_err());
+
+        let resources = load_resources(&store).unwrap();
+        assert_eq!(resources["test"].get_content(), "{entry: 1234}");
+    }
+
+    #[test]
+    fn export_and_import_git() {
+        let test_dir = "./.test/event_log_store_sqlite/export_and_import_git/";
+        fs::remove_dir_all(test_dir).ok();
+        let repository = Repository::init_opts(test_dir, &RepositoryInitOptions::new().bare(true)).unwrap();
+
+        let store = SqliteEventLogStore::open_in_memory().unwrap();
+        let log = Log::local("test", "FP", "device-0");
+        store.append(&log, &[1], None, &sign).unwrap();
+        let meta = UpdateMeta {
+            format_version: UPDATE_FORMAT_VERSION,
+            lamport: 1,
+            wall_clock: "2022-11-01T10:00:00+00:00".to_string(),
+            state_vector: BTreeMap::from([(42, 1)]),
+            touched_key_paths: vec!["entry".to_string()],
+        };
+        let head = store.append(&log, &[2], Some(&meta), &sign).unwrap();
+        assert_eq!(store.head_meta(&log).unwrap(), Some(meta.clone()));
+        assert_eq!(store.head_meta(&Log::local("missing", "FP", "device-0")).unwrap(), None);
+        assert_eq!(store.export_git(&repository).unwrap(), 2);
+        assert_eq!(store.export_git(&repository).unwrap(), 0);
+
+        // the ids are the oids of the signed commits
+        let git_store = GitEventLogStore::new(&repository);
+        assert_eq!(git_store.head(&log).unwrap(), Some(head.clone()));
+        assert_eq!(updates(&git_store, &log), vec![vec![1], vec![2]]);
+        assert_eq!(git_store.head_meta(&log).unwrap(), Some(meta.clone()));
+
+        // logs written to git can be imported, e.g. after a fetch
+        let next = git_store.append(&log, &[3], None, &sign).unwrap();
+        let imported = SqliteEventLogStore::open_in_memory().unwrap();
+        assert_eq!(imported.import_git(&repository).unwrap(), 3);
+        assert_eq!(imported.head(&log).unwrap(), Some(next));
+        assert_eq!(updates(&imported, &log), vec![vec![1], vec![2], vec![3]]);
+        let metas: Vec<_> = imported.metas(&log).unwrap().map(Result::unwrap).collect();
+        assert_eq!(metas, vec![None, Some(meta), None]);
+        assert_eq!(imported.head_meta(&log).unwrap(), None);
+        assert_eq!(store.import_git(&repository).unwrap(), 1);
+    }
+}
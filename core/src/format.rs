@@ -0,0 +1,220 @@
+use git2::{ErrorCode, Repository};
+
+use crate::errors::Error;
+use crate::event_log_store::{EventLogStore, GitEventLogStore};
+use crate::resource::Resource;
+
+/// The version of the on-disk format of a document that this version of dcore writes.
+///
+/// - 0: unversioned, the log commits only hold the `update` blob
+/// - 1: the log commits also hold the `meta` blob, see `UpdateMeta`
+/// - 2: the device name is always set in `user.device`, new documents get a generated one
+pub const FORMAT_VERSION: u32 = 2;
+
+/// Where the format version of the local copy is kept in its git config (`.data/config`).
+const CONFIG_KEY: &str = "dcore.formatVersion";
+
+/// Where `init` records the format version in the `_resource_meta` of the config resource, such
+/// that clones know which format the logs of the document are written in.
+pub(crate) const RECORDED_VERSION_KEY: &str = "format_version";
+
+/// Upgrades a document to `version` from the version before, in place.
+pub struct Migration {
+    pub version: u32,
+    pub description: &'static str,
+    pub run: fn(&Repository) -> Result<(), Error>,
+}
+
+/// All migrations, oldest first.
+pub fn migrations() -> Vec<Migration> {
+    vec![
+        Migration {
+            version: 1,
+            description: "Log commits may hold a meta blob, the commits without one stay readable as they are",
+            run: |_| Ok(()),
+        },
+        Migration {
+            version: 2,
+            description: "Record the device name device-0 that was used when no device name was set",
+            run: record_default_device,
+        },
+    ]
+}
+
+/// The format version recorded in the document, None for documents written before it was recorded.
+pub fn format_version(repository: &Repository) -> Result<Option<u32>, Error> {
+    match repository.config()?.get_i32(CONFIG_KEY) {
+        Ok(version) => Ok(Some(version as u32)),
+        Err(e) if e.code() == ErrorCode::NotFound => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn set_format_version(repository: &Repository, version: u32) -> Result<(), Error> {
+    repository.config()?.set_i32(CONFIG_KEY, version as i32)?;
+    Ok(())
+}
+
+/// To be called when a document is opened. Records the version of new documents, upgrades older
+/// ones in place and refuses documents of a newer version of dcore.
+/// Returns the descriptions of the migrations that ran.
+pub fn open(repository: &Repository) -> Result<Vec<&'static str>, Error> {
+    let version = match format_version(repository)? {
+        Some(version) => version,
+        None if GitEventLogStore::new(repository).logs()?.is_empty() => {
+            set_format_version(repository, FORMAT_VERSION)?;
+            return Ok(Vec::new());
+        }
+        None => 0,
+    };
+    if version > FORMAT_VERSION {
+        return Err(Error::FormatError(format!(
+            "The document has format version {}, this version of dcore only reads up to {}.",
+            version, FORMAT_VERSION
+        )));
+    }
+    migrate(repository, version, &migrations())
+}
+
+/// Refuses documents of a newer version of dcore like `open`, but changes nothing, e.g. to check
+/// a document as it is.
+pub fn check(repository: &Repository) -> Result<(), Error> {
+    match format_version(repository)? {
+        Some(version) if version > FORMAT_VERSION => Err(Error::FormatError(format!(
+            "The document has format version {}, this version of dcore only reads up to {}.",
+            version, FORMAT_VERSION
+        ))),
+        _ => Ok(()),
+    }
+}
+
+/// The format version recorded in the config resource, None for documents created before it was
+/// recorded.
+pub fn recorded_version(config: &Resource) -> Option<u32> {
+    let version = config.store.transact().get_map("_resource_meta").get(RECORDED_VERSION_KEY)?;
+    version.to_string().parse::<f64>().ok().map(|version| version as u32)
+}
+
+/// Refuses documents whose config records a format version newer than this version of dcore
+/// reads, e.g. a clone of a document created by a newer dcore.
+pub(crate) fn check_recorded(config: &Resource) -> Result<(), Error> {
+    match recorded_version(config) {
+        Some(version) if version > FORMAT_VERSION => Err(Error::FormatError(format!(
+            "The document was created with format version {}, this version of dcore only reads up to {}.",
+            version, FORMAT_VERSION
+        ))),
+        _ => Ok(()),
+    }
+}
+
+fn migrate(repository: &Repository, version: u32, migrations: &[Migration]) -> Result<Vec<&'static str>, Error> {
+    let mut applied = Vec::new();
+    for migration in migrations.iter().filter(|migration| migration.version > version) {
+        (migration.run)(repository).map_err(|e| {
+            Error::FormatError(format!(
+                "Migration to format version {} failed: {}",
+                migration.version, e
+            ))
+        })?;
+        // recorded after every step, so an interrupted upgrade continues where it stopped
+        set_format_version(repository, migration.version)?;
+        applied.push(migration.description);
+    }
+    Ok(applied)
+}
+
+fn record_default_device(repository: &Repository) -> Result<(), Error> {
+    let mut config = repository.config()?;
+    if config.get_string("user.device").is_err() && !GitEventLogStore::new(repository).logs()?.is_empty() {
+        config.set_str("user.device", "device-0")?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::fs;
+
+    use git2::{Repository, RepositoryInitOptions};
+
+    use crate::event_log_store::{EventLogStore, GitEventLogStore, Log};
+    use crate::format::{
+        check, check_recorded, format_version, migrate, migrations, open, recorded_version, Migration,
+        FORMAT_VERSION, RECORDED_VERSION_KEY,
+    };
+    use crate::resource::Resource;
+
+    fn sign(_data: &str) -> Result<String, crate::errors::Error> {
+        Ok("-----BEGIN PGP SIGNATURE-----\n\nsignature\n-----END PGP SIGNATURE-----\n".to_string())
+    }
+
+    fn repository(test_dir: &str) -> Repository {
+        fs::remove_dir_all(test_dir).ok();
+        Repository::init_opts(test_dir, &RepositoryInitOptions::new().bare(true)).unwrap()
+    }
+
+    #[test]
+    fn new_documents_get_the_current_version() {
+        let repository = repository("./.test/format/new/");
+        assert_eq!(format_version(&repository).unwrap(), None);
+        assert!(open(&repository).unwrap().is_empty());
+        assert_eq!(format_version(&repository).unwrap(), Some(FORMAT_VERSION));
+    }
+
+    #[test]
+    fn unversioned_documents_are_upgraded() {
+        let repository = repository("./.test/format/unversioned/");
+        GitEventLogStore::new(&repository)
+            .append(&Log::local("test", "FP", "device-0"), &[1], None, &sign)
+            .unwrap();
+        assert_eq!(open(&repository).unwrap().len(), migrations().len());
+        assert_eq!(format_version(&repository).unwrap(), Some(FORMAT_VERSION));
+        // the logs were written with the former default device name
+        assert_eq!(repository.config().unwrap().get_string("user.device").unwrap(), "device-0");
+        assert!(open(&repository).unwrap().is_empty());
+
+        repository.config().unwrap().set_i32("dcore.formatVersion", 99).unwrap();
+        assert!(open(&repository).is_err());
+        assert!(check(&repository).is_err());
+    }
+
+    #[test]
+    fn failed_migrations_keep_the_last_version() {
+        let repository = repository("./.test/format/failed/");
+        let migrations = vec![
+            Migration {
+                version: 1,
+                description: "first",
+                run: |_| Ok(()),
+            },
+            Migration {
+                version: 2,
+                description: "second",
+                run: |_| Err(crate::errors::Error::Other("broken".to_string())),
+            },
+        ];
+        assert!(migrate(&repository, 0, &migrations).is_err());
+        assert_eq!(format_version(&repository).unwrap(), Some(1));
+        assert_eq!(migrate(&repository, 1, &migrations[..1]).unwrap(), Vec::<&str>::new());
+    }
+
+    #[test]
+    fn recorded_versions() {
+        let mut config = Resource::new(&"config".to_string());
+        assert_eq!(recorded_version(&config), None);
+        assert!(check_recorded(&config).is_ok());
+
+        for (version, readable) in [(FORMAT_VERSION, true), (FORMAT_VERSION + 1, false)] {
+            config
+                .add_local_update(|transaction| {
+                    let resource_meta = transaction.get_map("_resource_meta");
+                    resource_meta.insert(transaction, RECORDED_VERSION_KEY.to_owned(), version as f64);
+                    transaction
+                })
+                .unwrap();
+            assert_eq!(recorded_version(&config), Some(version));
+            assert_eq!(check_recorded(&config).is_ok(), readable);
+        }
+    }
+}
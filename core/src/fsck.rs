@@ -0,0 +1,293 @@
+use std::collections::{BTreeMap, HashSet};
+
+use git2::{Oid, Repository};
+use serde::Serialize;
+use yrs::updates::decoder::Decode;
+use yrs::Update;
+
+use crate::document_utils::CommitVerifier;
+use crate::equivocation::forks;
+use crate::errors::Error;
+use crate::event_log_store::{EventLogStore, Log};
+use crate::update_meta::UpdateMeta;
+use crate::Document;
+
+/// What is wrong with a log or one of its commits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProblemKind {
+    /// The tree is not `update` with an optional `meta`, both plain blobs
+    UnexpectedTree,
+    UndecodableUpdate,
+    InvalidMeta,
+    /// The signature is missing or not made by a member of the document
+    BadSignature,
+    /// The commit is signed by another identity than the one in the name of the log
+    WrongSigner,
+    /// The commit has more than one parent
+    NonLinear,
+    /// Two heads of the same device log, e.g. in `refs/local` and `refs/origin` or a fork in
+    /// `refs/forks`, do not descend from one another
+    DivergentHeads,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Problem {
+    pub kind: ProblemKind,
+    /// The reference of the log
+    pub log: String,
+    pub commit: Option<String>,
+    pub message: String,
+}
+
+/// The result of `fsck`, printed as JSON by `dcore document-fsck`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct FsckReport {
+    pub logs: usize,
+    pub commits: usize,
+    pub problems: Vec<Problem>,
+}
+
+impl FsckReport {
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Checks the integrity of all event-logs of a document. Signatures are verified against the
+/// members in the `config` resource, so the document should be loaded. A commit that is in more
+/// than one log, e.g. in `refs/local` and `refs/origin`, is checked and counted once. Nothing is
+/// written to the document, the keys of the members are imported into a temporary keyring.
+pub fn fsck(doc: &Document) -> Result<FsckReport, Error> {
+    let repository = &doc.repository;
+    let store = doc.event_log_store();
+    let mut report = FsckReport {
+        logs: 0,
+        commits: 0,
+        problems: Vec::new(),
+    };
+    let mut heads_per_device: BTreeMap<(String, String, String), Vec<(Log, Oid)>> = BTreeMap::new();
+    let mut verifier = CommitVerifier::read_only(doc)?;
+    let mut checked = HashSet::new();
+
+    for log in store.logs()? {
+        let head = match store.head(&log)? {
+            Some(head) => Oid::from_str(&head)?,
+            None => continue,
+        };
+        report.logs += 1;
+        heads_per_device
+            .entry((log.resource.clone(), log.fingerprint.clone(), log.device.clone()))
+            .or_default()
+            .push((log.clone(), head));
+
+        let mut revwalk = repository.revwalk()?;
+        revwalk.push(head)?;
+        for oid in revwalk {
+            let oid = oid?;
+            if !checked.insert(oid) {
+                continue;
+            }
+            report.commits += 1;
+            for (kind, message) in check_commit(doc, &mut verifier, &log, oid)? {
+                report.problems.push(Problem {
+                    kind,
+                    log: log.reference(),
+                    commit: Some(oid.to_string()),
+                    message,
+                });
+            }
+        }
+    }
+
+    for heads in heads_per_device.values() {
+        for (index, (log, head)) in heads.iter().enumerate() {
+            for (other_log, other_head) in &heads[index + 1..] {
+                if !is_linear_history(repository, *head, *other_head)? {
+                    report.problems.push(Problem {
+                        kind: ProblemKind::DivergentHeads,
+                        log: log.reference(),
+                        commit: Some(head.to_string()),
+                        message: format!("{} points to {} which diverges from it.", other_log, other_head),
+                    });
+                }
+            }
+        }
+    }
+
+    // rewritten histories that were received and kept aside, see `equivocation::update_head`
+    for fork in forks(doc)? {
+        report.problems.push(Problem {
+            kind: ProblemKind::DivergentHeads,
+            log: fork.log.reference(),
+            commit: Some(fork.accepted.clone()),
+            message: format!("The author forked the log, {} is kept in refs/forks.", fork.forked),
+        });
+    }
+    Ok(report)
+}
+
+fn is_linear_history(repository: &Repository, head: Oid, other: Oid) -> Result<bool, Error> {
+    Ok(head == other
+        || repository.graph_descendant_of(head, other)?
+        || repository.graph_descendant_of(other, head)?)
+}
+
+fn check_commit(
+    doc: &Document,
+    verifier: &mut CommitVerifier,
+    log: &Log,
+    oid: Oid,
+) -> Result<Vec<(ProblemKind, String)>, Error> {
+    let repository = &doc.repository;
+    let commit = repository.find_commit(oid)?;
+    let mut problems = Vec::new();
+
+    if commit.parent_count() > 1 {
+        problems.push((ProblemKind::NonLinear, format!("The commit has {} parents.", commit.parent_count())));
+    }
+
+    let tree = commit.tree()?;
+    let names: Vec<String> = tree.iter().map(|entry| entry.name().unwrap_or_default().to_string()).collect();
+    let blobs = tree
+        .iter()
+        .all(|entry| entry.kind() == Some(git2::ObjectType::Blob) && entry.filemode() == 0o100644);
+    if !blobs || !(names == ["update"] || names == ["meta", "update"]) {
+        problems.push((ProblemKind::UnexpectedTree, format!("The tree has the entries {:?}.", names)));
+    }
+
+    let store = doc.event_log_store();
+    match store.read_update(oid) {
+        Ok(update) => {
+            if let Err(e) = Update::decode_v2(&update) {
+                problems.push((ProblemKind::UndecodableUpdate, e.to_string()));
+            }
+        }
+        Err(e) => problems.push((ProblemKind::UndecodableUpdate, e.to_string())),
+    }
+    if let Some(meta) = store.read_meta(oid)? {
+        if let Err(e) = UpdateMeta::from_bytes(&meta) {
+            problems.push((ProblemKind::InvalidMeta, e.to_string()));
+        }
+    }
+
+    match verifier.verify(doc, oid) {
+        Ok(signer) if signer != log.fingerprint => problems.push((
+            ProblemKind::WrongSigner,
+            format!("The commit is signed by {}.", signer),
+        )),
+        Ok(_) => {}
+        Err(e) => problems.push((ProblemKind::BadSignature, e.to_string())),
+    }
+    Ok(problems)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::BTreeMap;
+    use std::path::{Path, PathBuf};
+
+    use crate::document::DocumentNewOptions;
+    use crate::event_log_store::load_resources;
+    use crate::fsck::{fsck, ProblemKind};
+    use crate::test_utils::{create_test_document, get_test_key};
+    use crate::Document;
+
+    #[test]
+    fn report_problems() {
+        let doc_dir = "./.test/fsck/report_problems/";
+        let fingerprint = get_test_key().fingerprint;
+        let mut doc = create_test_document(doc_dir);
+        doc.add_resource("test".to_string()).unwrap();
+        doc.update_resource_with_key_value("test", "entry", "1234").unwrap();
+
+        let report = fsck(&doc).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.logs, 2);
+        assert_eq!(report.commits, 3);
+
+        // the commits of a log that is also in refs/origin are counted once
+        let device = doc.config_get_local_device().unwrap();
+        let local_head = doc
+            .repository
+            .refname_to_id(&format!("refs/local/test/{}/{}", fingerprint, device))
+            .unwrap();
+        doc.repository
+            .reference(&format!("refs/origin/test/{}/{}", fingerprint, device), local_head, true, "test")
+            .unwrap();
+        let report = fsck(&doc).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.logs, 3);
+        assert_eq!(report.commits, 3);
+
+        // the document is checked as it is, a missing one is not created
+        let missing = PathBuf::from(doc_dir).join("missing");
+        assert!(Document::open_existing(DocumentNewOptions {
+            directory: missing.clone(),
+            ..doc.reopen_options()
+        })
+        .is_err());
+        assert!(!missing.exists());
+
+        // an unsigned commit with an extra blob that rewrites the history of our device
+        let repository = &doc.repository;
+        let mut builder = repository.treebuilder(None).unwrap();
+        builder.insert("update", repository.blob(&[0]).unwrap(), 0o100644).unwrap();
+        builder.insert("extra", repository.blob(&[1]).unwrap(), 0o100644).unwrap();
+        let tree = repository.find_tree(builder.write().unwrap()).unwrap();
+        let signature = git2::Signature::now("Mallory", "mallory@example.com").unwrap();
+        let forged = repository
+            .commit(None, &signature, &signature, "update.", &tree, &[])
+            .unwrap();
+        repository
+            .reference(&format!("refs/origin/test/{}/{}", fingerprint, device), forged, true, "test")
+            .unwrap();
+
+        let report = fsck(&doc).unwrap();
+        assert!(!report.is_ok());
+        let kinds: Vec<ProblemKind> = report.problems.iter().map(|problem| problem.kind).collect();
+        assert!(kinds.contains(&ProblemKind::UnexpectedTree));
+        assert!(kinds.contains(&ProblemKind::BadSignature));
+        assert!(kinds.contains(&ProblemKind::DivergentHeads));
+        assert!(serde_json::to_string(&report).unwrap().contains("\"divergent_heads\""));
+    }
+
+    /// The files below `dir` with their content, directories map to None
+    fn snapshot(dir: &Path, files: &mut BTreeMap<PathBuf, Option<Vec<u8>>>) {
+        for entry in std::fs::read_dir(dir).unwrap() {
+            let path = entry.unwrap().path();
+            if path.is_dir() {
+                files.insert(path.clone(), None);
+                snapshot(&path, files);
+            } else {
+                files.insert(path.clone(), Some(std::fs::read(&path).unwrap()));
+            }
+        }
+    }
+
+    #[test]
+    fn leave_the_document_unchanged() {
+        let doc_dir = "./.test/fsck/leave_the_document_unchanged/";
+        let mut doc = create_test_document(doc_dir);
+        let options = doc.reopen_options();
+        doc.add_resource("test".to_string()).unwrap();
+        doc.update_resource_with_key_value("test", "entry", "1234").unwrap();
+        drop(doc);
+
+        let data_dir = PathBuf::from(doc_dir).join(".data");
+        let mut before = BTreeMap::new();
+        snapshot(&data_dir, &mut before);
+
+        let mut doc = Document::open_existing(options).unwrap();
+        doc.resources = load_resources(&doc.event_log_store()).unwrap();
+        let report = fsck(&doc).unwrap();
+        assert!(report.is_ok(), "{:?}", report.problems);
+        drop(doc);
+
+        let mut after = BTreeMap::new();
+        snapshot(&data_dir, &mut after);
+        assert_eq!(before.keys().collect::<Vec<_>>(), after.keys().collect::<Vec<_>>());
+        assert!(before == after);
+    }
+}
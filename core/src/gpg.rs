@@ -1,6 +1,8 @@
 use std::borrow::BorrowMut;
 
 use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::str::from_utf8;
 use std::time::Duration;
 
@@ -12,15 +14,15 @@ use crate::Identity;
 
 pub struct Gpg {
     pub context: gpgme::Context,
+    /// The gpg home of the context, None for the one in `GNUPGHOME` or the default one
+    home: Option<PathBuf>,
 }
 
 impl Gpg {
 
     // todo: we need the key in the armored ssh format
-    pub(crate) fn get_armored_public_key(fingerprint: &str) -> Result<String, Error> {
-        let mut context = gpgme::Context::from_protocol(gpgme::Protocol::OpenPgp)
-            .expect("Could create pgpme context from open pgp protocol");
-        context.set_armor(true);
+    pub(crate) fn get_armored_public_key(home: Option<&Path>, fingerprint: &str) -> Result<String, Error> {
+        let mut context = Gpg::in_home(home)?.context;
         let key = context
             .get_key(fingerprint)
             .unwrap();
@@ -32,10 +34,8 @@ impl Gpg {
     }
 
     // todo: maybe pass the reference to the str instead of returning a String (=avoid heap)?
-    pub(crate) fn get_armored_private_key(fingerprint: &str) -> Result<String, Error> {
-        let mut context = gpgme::Context::from_protocol(gpgme::Protocol::OpenPgp)
-            .expect("Could create pgpme context from open pgp protocol");
-        context.set_armor(true);
+    pub(crate) fn get_armored_private_key(home: Option<&Path>, fingerprint: &str) -> Result<String, Error> {
+        let mut context = Gpg::in_home(home)?.context;
         let key = context
             .get_key(fingerprint)
             .unwrap();
@@ -130,7 +130,7 @@ impl Gpg {
             context.set_engine_home_dir(gpg_home.unwrap()).unwrap();
         }
 
-        Gpg { context }
+        Gpg { context, home: None }
     }
 
     pub fn new_with_custom_home(home: &str) -> Self {
@@ -139,9 +139,42 @@ impl Gpg {
         gpg.context
             .set_engine_home_dir(home)
             .expect("Could not set gpg engine home dir");
+        gpg.home = Some(PathBuf::from(home));
         gpg
     }
 
+    /// A context on the gpg home `home`, None for the one in `GNUPGHOME` or the default one.
+    pub fn in_home(home: Option<&Path>) -> Result<Self, Error> {
+        let mut gpg = Gpg::new();
+        if let Some(home) = home {
+            gpg.context.set_engine_home_dir(home.to_string_lossy().into_owned())?;
+            gpg.home = Some(home.to_path_buf());
+        }
+        Ok(gpg)
+    }
+
+    /// A context with its own keyring in `home`, which is created if missing. Keys imported into
+    /// it do not end up in the keyring of the user.
+    pub fn with_keyring(home: &Path) -> Result<Self, Error> {
+        fs::create_dir_all(home)?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(home, fs::Permissions::from_mode(0o700))?;
+        }
+        Gpg::in_home(Some(home))
+    }
+
+    /// The gpg home of the context, None for the one in `GNUPGHOME` or the default one.
+    pub fn home(&self) -> Option<&Path> {
+        self.home.as_deref()
+    }
+
+    /// Whether the keyring contains the public key with the fingerprint.
+    pub fn has_public_key(&mut self, fingerprint: &str) -> bool {
+        self.context.get_key(fingerprint).is_ok()
+    }
+
     fn create_new_ed25519_key(&mut self, user: CreateUserArgs) -> Result<Key, Error> {
         let mut user_id = String::new();
         write!(user_id, "{} <{}>", user.name, user.email).unwrap();
@@ -220,6 +253,33 @@ impl Gpg {
         Ok(String::from(std::str::from_utf8(&output).unwrap()))
     }
 
+    pub fn import_public_key(&mut self, armored_public_key: &str) -> Result<(), Error> {
+        self.context.import(armored_public_key.as_bytes())?;
+        Ok(())
+    }
+
+    /// Verifies a detached signature and returns the fingerprint of the primary key that made it.
+    /// The public key must already be in the keyring, see `import_public_key`.
+    pub fn verify_detached(&mut self, signature: &[u8], data: &[u8]) -> Result<String, Error> {
+        let ctx = self.context.borrow_mut();
+        let result = ctx.verify_detached(signature, data)?;
+        let signature = result
+            .signatures()
+            .next()
+            .ok_or_else(|| Error::DcoreError("The data is not signed.".to_string()))?;
+        signature.status()?;
+
+        let signing_key = signature
+            .fingerprint()
+            .map_err(|_| Error::DcoreError("The signature has no valid fingerprint.".to_string()))?;
+        // the signature can be made by a sub key, we want the fingerprint of the primary key
+        let key = ctx.get_key(signing_key)?;
+        let fingerprint = key
+            .fingerprint()
+            .map_err(|_| Error::DcoreError("The signing key has no valid fingerprint.".to_string()))?;
+        Ok(fingerprint.to_string())
+    }
+
     pub fn get_public_key_by_identity(&mut self, identity: &Identity) -> Result<Vec<u8>, Error> {
         let fingerprint = identity.get_fingerprint();
         // Find the GPGME key to export
@@ -0,0 +1,313 @@
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
+use crate::projection::{changed_key_paths, leaves, AuthorTracker};
+use crate::resource::Resource;
+use crate::Document;
+
+/// What a hook gets to know about a change, executables get it as JSON on stdin.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ResourceChange {
+    pub resource: String,
+    /// Fingerprint of the identity that made the change
+    pub author: String,
+    pub device: String,
+    pub changed_key_paths: Vec<String>,
+    /// Whether the change came from another device, e.g. with a sync
+    pub remote: bool,
+}
+
+/// Callbacks have to be `Send`, documents are moved between threads, e.g. by the http server.
+pub type HookCallback = Box<dyn Fn(&ResourceChange) + Send>;
+
+/// An update that was just committed to the log of this identity and device.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommittedUpdate {
+    pub resource: String,
+    /// The commit of the update, the new head of the log
+    pub head: String,
+    /// The bytes `Resource::add_local_update` returned
+    pub update: Vec<u8>,
+}
+
+/// Gets every committed update, see `Document::register_commit_hook`.
+pub type CommitCallback = Box<dyn Fn(&CommittedUpdate) + Send>;
+
+/// Runs hooks after local commits and after remote updates were applied by `Document::load`.
+///
+/// Executables are registered in the git config of the document (`.data/config`) as
+/// `hook.{name}.command`, see `Document::config_add_hook`. Rust callbacks are registered with
+/// `Document::register_hook` and only live as long as the document is open.
+/// Failing hooks are reported but do not undo the change.
+#[derive(Default)]
+pub struct Hooks {
+    callbacks: Vec<HookCallback>,
+    commit_callbacks: Vec<CommitCallback>,
+    /// The leaves of the resources as of the last run, to find the changed key paths.
+    known: Mutex<HashMap<String, BTreeMap<String, serde_json::Value>>>,
+}
+
+impl Hooks {
+    /// The executables registered in the git config of the document by hook name.
+    pub fn commands(document: &Document) -> Result<BTreeMap<String, String>, Error> {
+        let config = document.repository.config()?.snapshot()?;
+        let mut commands = BTreeMap::new();
+        let entries = config.entries(Some(r"^hook\..*\.command$"))?;
+        for entry in &entries {
+            let entry = entry?;
+            if let (Some(name), Some(command)) = (entry.name(), entry.value()) {
+                let name = name.trim_start_matches("hook.").trim_end_matches(".command");
+                commands.insert(name.to_string(), command.to_string());
+            }
+        }
+        Ok(commands)
+    }
+
+    pub(crate) fn register(&mut self, callback: HookCallback) {
+        self.callbacks.push(callback);
+    }
+
+    pub(crate) fn register_commit(&mut self, callback: CommitCallback) {
+        self.commit_callbacks.push(callback);
+    }
+
+    pub(crate) fn is_active(&self, document: &Document) -> bool {
+        !self.callbacks.is_empty() || Self::commands(document).map_or(false, |commands| !commands.is_empty())
+    }
+
+    /// Takes the current state of the resources as the one hooks already know about.
+    pub(crate) fn remember(&self, resources: &HashMap<String, Resource>) {
+        let mut known = self.known.lock().unwrap();
+        for (name, resource) in resources {
+            known.insert(name.clone(), leaves(resource));
+        }
+    }
+
+    /// To be called with every update committed to our logs, before `local_change`.
+    pub(crate) fn committed(&self, committed: &CommittedUpdate) {
+        for callback in &self.commit_callbacks {
+            callback(committed);
+        }
+    }
+
+    /// To be called after a local update of the resource was committed.
+    pub(crate) fn local_change(&self, document: &Document, resource: &Resource) {
+        if !self.is_active(document) {
+            return;
+        }
+        let current = leaves(resource);
+        let previous = self.known.lock().unwrap().insert(resource.name.clone(), current.clone());
+        let changed_key_paths = changed_key_paths(&previous.unwrap_or_default(), &current);
+        if changed_key_paths.is_empty() {
+            return;
+        }
+        let device = document.config_get_local_device().unwrap_or_default();
+        self.run(
+            document,
+            &ResourceChange {
+                resource: resource.name.clone(),
+                author: document.identity.get_fingerprint(),
+                device,
+                changed_key_paths,
+                remote: false,
+            },
+        );
+    }
+
+    /// To be called after the logs were replayed. The changes of other identities and devices since
+    /// the last run are reported per resource and log, the ones of this device were already reported
+    /// when they were committed. Nothing is reported the first time, there is no earlier state.
+    pub(crate) fn loaded(&self, document: &Document, resources: &HashMap<String, Resource>, authors: &AuthorTracker) {
+        if !self.is_active(document) {
+            return;
+        }
+        let first_run = self.known.lock().unwrap().is_empty();
+        let own_fingerprint = document.identity.get_fingerprint();
+        let own_device = document.config_get_local_device().unwrap_or_default();
+
+        let mut changes = Vec::new();
+        for (name, resource) in resources {
+            let current = leaves(resource);
+            let previous = self.known.lock().unwrap().insert(name.clone(), current.clone());
+            if first_run {
+                continue;
+            }
+            let mut per_log: BTreeMap<(String, String), Vec<String>> = BTreeMap::new();
+            for key_path in changed_key_paths(&previous.unwrap_or_default(), &current) {
+                let (author, device) = match authors.log(name, &key_path) {
+                    Some(log) => (log.fingerprint.clone(), log.device.clone()),
+                    None => (String::new(), String::new()),
+                };
+                if author == own_fingerprint && device == own_device {
+                    continue;
+                }
+                per_log.entry((author, device)).or_default().push(key_path);
+            }
+            for ((author, device), changed_key_paths) in per_log {
+                changes.push(ResourceChange {
+                    resource: name.clone(),
+                    author,
+                    device,
+                    changed_key_paths,
+                    remote: true,
+                });
+            }
+        }
+        for change in changes {
+            self.run(document, &change);
+        }
+    }
+
+    fn run(&self, document: &Document, change: &ResourceChange) {
+        for callback in &self.callbacks {
+            callback(change);
+        }
+        let commands = match Self::commands(document) {
+            Ok(commands) => commands,
+            Err(e) => {
+                eprintln!("Could not read the hooks of document {}: {}", document.name, e);
+                return;
+            }
+        };
+        for (name, command) in commands {
+            if let Err(e) = Self::run_command(document, &command, change) {
+                eprintln!("Hook {} of document {} failed: {}", name, document.name, e);
+            }
+        }
+    }
+
+    fn run_command(document: &Document, command: &str, change: &ResourceChange) -> Result<(), Error> {
+        let input = serde_json::to_vec(change).map_err(|e| Error::Other(e.to_string()))?;
+        let mut child = Command::new(command)
+            .current_dir(document.repository.path().parent().unwrap_or(document.repository.path()))
+            .stdin(Stdio::piped())
+            .spawn()?;
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&input)?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(Error::DcoreError(format!("{} exited with {}", command, status)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::PathBuf;
+    use std::sync::{Arc, Mutex};
+
+    use crate::hooks::{CommittedUpdate, ResourceChange};
+    use crate::sync_bundle::BundleSync;
+    use crate::test_utils::{create_test_document, create_test_gpg_home, get_test_key, open_test_device};
+
+    #[test]
+    fn callbacks_on_local_and_remote_changes() {
+        let test_dir = PathBuf::from("./.test/hooks/callbacks/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let mut doc_a = open_test_device(&gpg_home, &test_dir.join("a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+        let mut doc_b = open_test_device(&gpg_home, &test_dir.join("b"), "device-b");
+
+        let changes: Arc<Mutex<Vec<ResourceChange>>> = Arc::new(Mutex::new(Vec::new()));
+        let recorded = changes.clone();
+        doc_a.register_hook(move |change| recorded.lock().unwrap().push(change.clone()));
+        doc_a.add_resource("test".to_string()).unwrap();
+        doc_a.update_resource_with_key_value("test", "a.b", "1").unwrap();
+        assert_eq!(
+            changes.lock().unwrap().last(),
+            Some(&ResourceChange {
+                resource: "test".to_string(),
+                author: fingerprint.clone(),
+                device: "device-a".to_string(),
+                changed_key_paths: vec!["a.b".to_string()],
+                remote: false,
+            })
+        );
+
+        // the other device gets the document and changes it
+        let bundle = test_dir.join("a.bundle");
+        BundleSync::export(&doc_a, &bundle, None).unwrap();
+        BundleSync::import(&mut doc_b, &bundle).unwrap();
+        doc_b.update_resource_with_key_value("test", "c", "2").unwrap();
+        let bundle = test_dir.join("b.bundle");
+        BundleSync::export(&doc_b, &bundle, None).unwrap();
+
+        changes.lock().unwrap().clear();
+        BundleSync::import(&mut doc_a, &bundle).unwrap();
+        assert_eq!(
+            *changes.lock().unwrap(),
+            vec![ResourceChange {
+                resource: "test".to_string(),
+                author: fingerprint,
+                device: "device-b".to_string(),
+                changed_key_paths: vec!["c".to_string()],
+                remote: true,
+            }]
+        );
+    }
+
+    #[test]
+    fn commit_hooks_get_every_committed_update() {
+        let mut doc = create_test_document("./.test/hooks/commit_hooks/");
+        let committed: Arc<Mutex<Vec<CommittedUpdate>>> = Arc::new(Mutex::new(Vec::new()));
+        let recorded = committed.clone();
+        doc.register_commit_hook(move |update| recorded.lock().unwrap().push(update.clone()));
+
+        doc.add_resource("test".to_string()).unwrap();
+        let set = doc.update_resource_with_key_value("test", "a", "1").unwrap();
+        let patched = doc
+            .apply_merge_patch("test", &serde_json::json!({"b": "2"}))
+            .unwrap();
+
+        let committed = committed.lock().unwrap();
+        let resources: Vec<&str> = committed.iter().map(|update| update.resource.as_str()).collect();
+        assert_eq!(resources, vec!["test", "test", "test"]);
+        assert_eq!(committed[1].update, set);
+        assert_eq!(committed[2].update, patched);
+        let head = doc
+            .repository
+            .find_reference(&format!(
+                "refs/local/test/{}/{}",
+                get_test_key().fingerprint,
+                doc.config_get_local_device().unwrap()
+            ))
+            .unwrap()
+            .target()
+            .unwrap();
+        assert_eq!(committed[2].head, head.to_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn executables_get_the_change_on_stdin() {
+        use std::fs;
+        use std::os::unix::fs::PermissionsExt;
+
+        let doc_dir = "./.test/hooks/executables/";
+        let mut doc = create_test_document(doc_dir);
+
+        let script = PathBuf::from(doc_dir).join("hook.sh");
+        fs::write(&script, "#!/bin/sh\ncat > changes.json\n").unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        doc.config_add_hook("record", fs::canonicalize(&script).unwrap().to_str().unwrap())
+            .unwrap();
+        assert_eq!(crate::hooks::Hooks::commands(&doc).unwrap().len(), 1);
+
+        doc.add_resource("test".to_string()).unwrap();
+        doc.update_resource_with_key_value("test", "entry", "1234").unwrap();
+        let change: ResourceChange =
+            serde_json::from_str(&fs::read_to_string(PathBuf::from(doc_dir).join("changes.json")).unwrap()).unwrap();
+        assert_eq!(change.resource, "test");
+        assert_eq!(change.changed_key_paths, vec!["entry".to_string()]);
+    }
+}
@@ -1,9 +1,13 @@
+use std::path::{Path, PathBuf};
+
 use crate::errors::Error;
 use crate::gpg;
 use crate::gpg::{CreateUserArgs, Gpg, Key};
 
 pub struct Identity {
     key: Key,
+    /// The gpg home with the secret key, None for the one in `GNUPGHOME` or the default one
+    gpg_home: Option<PathBuf>,
 }
 
 impl Identity {
@@ -16,13 +20,16 @@ impl Identity {
 impl Identity {
     pub fn from_fingerprint(gpg: &mut Gpg, fingerprint: &String) -> Result<Identity, Error> {
         let key = gpg.get_public_key(fingerprint)?;
-        Ok(Identity { key })
+        Ok(Identity {
+            key,
+            gpg_home: gpg.home().map(Path::to_path_buf),
+        })
     }
 }
 
 impl Identity {
     pub fn from_key(key: Key) -> Identity {
-        Identity { key }
+        Identity { key, gpg_home: None }
     }
 }
 
@@ -39,7 +46,7 @@ impl Identity {
                 name: user_name,
             })
             .expect("Could not create the key with the provided options.");
-        Ok(Identity { key: key })
+        Ok(Identity { key: key, gpg_home: None })
     }
 
     pub fn print_all_identities(_keyring_home_dir: Option<String>) -> Result<(), Error> {
@@ -68,6 +75,17 @@ impl Identity {
     pub fn get_fingerprint(&self) -> String {
         self.key.fingerprint.clone()
     }
+
+    /// The gpg home with the secret key of the identity, None for the one in `GNUPGHOME` or the
+    /// default one.
+    pub fn gpg_home(&self) -> Option<&Path> {
+        self.gpg_home.as_deref()
+    }
+
+    /// A gpg context that can sign with the identity.
+    pub fn gpg(&self) -> Result<Gpg, Error> {
+        Gpg::in_home(self.gpg_home())
+    }
 }
 
 pub struct GetIdentityArgs {
@@ -84,11 +102,11 @@ impl Identity {
 
 
     pub fn get_armored_public_key(&self, ) -> Result<String, Error> {
-        gpg::Gpg::get_armored_public_key(&self.key.fingerprint)
+        gpg::Gpg::get_armored_public_key(self.gpg_home(), &self.key.fingerprint)
     }
 
     pub fn get_armored_private_key(&self) -> Result<String, Error> {
-        gpg::Gpg::get_armored_private_key(&self.key.fingerprint)
+        gpg::Gpg::get_armored_private_key(self.gpg_home(), &self.key.fingerprint)
     }
 
 }
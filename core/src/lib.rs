@@ -1,15 +1,30 @@
 pub use crate::document::{Document, DocumentInitOptions};
 pub use crate::identity::Identity;
 
+#[cfg(unix)]
+pub mod daemon;
+pub mod batch;
 pub mod document;
 mod document_utils;
+pub mod equivocation;
 pub mod errors;
 mod event;
+pub mod event_log_store;
+pub mod event_log_store_sqlite;
+pub mod format;
+pub mod fsck;
 pub mod gpg;
+pub mod hooks;
 pub mod identity;
-mod sync_libp2p;
+pub mod patch;
+pub mod projection;
+pub mod sync_libp2p;
 pub mod resource;
+pub mod search;
 
-#[cfg(test)]
-mod test_utils;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
+pub mod sync_bundle;
 pub mod sync_git;
+pub mod update_meta;
+pub mod validation;
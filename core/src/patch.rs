@@ -0,0 +1,268 @@
+use serde_json::Value;
+use yrs::{Array, Map, Transaction};
+
+use crate::errors::Error;
+use crate::resource::{insert_json, insert_json_at};
+
+/// Applies a JSON Patch (RFC 6902) to a JSON document. All operations are applied or none, e.g.
+/// when a `test` operation fails.
+pub fn apply_json_patch(document: &Value, patch: &Value) -> Result<Value, Error> {
+    let operations = patch
+        .as_array()
+        .ok_or_else(|| invalid_patch("a JSON Patch is an array of operations"))?;
+    let mut document = document.clone();
+    for (index, operation) in operations.iter().enumerate() {
+        apply_operation(&mut document, operation)
+            .map_err(|reason| invalid_patch(&format!("operation {} failed, {}", index, reason)))?;
+    }
+    Ok(document)
+}
+
+/// Applies a JSON Merge Patch (RFC 7396) to a JSON document: `null` removes a key, objects are
+/// merged and every other value replaces the one in the document.
+pub fn apply_merge_patch(document: &Value, patch: &Value) -> Value {
+    let patch = match patch {
+        Value::Object(patch) => patch,
+        patch => return patch.clone(),
+    };
+    let mut document = match document {
+        Value::Object(document) => document.clone(),
+        _ => serde_json::Map::new(),
+    };
+    for (key, value) in patch {
+        if value.is_null() {
+            document.remove(key);
+        } else {
+            let merged = apply_merge_patch(document.get(key).unwrap_or(&Value::Null), value);
+            document.insert(key.clone(), merged);
+        }
+    }
+    Value::Object(document)
+}
+
+fn invalid_patch(reason: &str) -> Error {
+    Error::DcoreError(format!("Invalid patch: {}", reason))
+}
+
+fn apply_operation(document: &mut Value, operation: &Value) -> Result<(), String> {
+    let member = |name: &str| {
+        operation
+            .get(name)
+            .ok_or_else(|| format!("the member '{}' is missing", name))
+    };
+    let pointer = |name: &str| {
+        member(name)?
+            .as_str()
+            .ok_or_else(|| format!("the member '{}' is not a JSON Pointer", name))
+    };
+    let path = pointer("path")?;
+    match member("op")?.as_str() {
+        Some("add") => add(document, path, member("value")?.clone()),
+        Some("remove") => remove(document, path).map(|_| ()),
+        Some("replace") => {
+            remove(document, path)?;
+            add(document, path, member("value")?.clone())
+        }
+        Some("move") => {
+            let from = pointer("from")?;
+            if path.starts_with(&format!("{}/", from)) {
+                return Err(format!("{} can not be moved into itself", from));
+            }
+            let value = remove(document, from)?;
+            add(document, path, value)
+        }
+        Some("copy") => {
+            let from = pointer("from")?;
+            let value = document.pointer(from).ok_or_else(|| format!("{} does not exist", from))?.clone();
+            add(document, path, value)
+        }
+        Some("test") => match document.pointer(path) {
+            Some(value) if json_equal(value, member("value")?) => Ok(()),
+            _ => Err(format!("{} does not have the expected value", path)),
+        },
+        _ => Err("the op is not one of add, remove, replace, move, copy and test".to_string()),
+    }
+}
+
+/// Splits a JSON Pointer into the pointer of the parent and the unescaped last token.
+fn split_pointer(path: &str) -> Result<(&str, String), String> {
+    let (parent, token) = path
+        .rsplit_once('/')
+        .ok_or_else(|| format!("'{}' is not a JSON Pointer", path))?;
+    Ok((parent, token.replace("~1", "/").replace("~0", "~")))
+}
+
+fn array_index(token: &str, len: usize) -> Result<usize, String> {
+    let valid = !token.is_empty()
+        && token.chars().all(|c| c.is_ascii_digit())
+        && (token == "0" || !token.starts_with('0'));
+    match token.parse::<usize>() {
+        Ok(index) if valid && index <= len => Ok(index),
+        _ => Err(format!("{} is not an index of the array", token)),
+    }
+}
+
+fn add(document: &mut Value, path: &str, value: Value) -> Result<(), String> {
+    if path.is_empty() {
+        *document = value;
+        return Ok(());
+    }
+    let (parent, token) = split_pointer(path)?;
+    match document.pointer_mut(parent) {
+        Some(Value::Object(entries)) => {
+            entries.insert(token, value);
+            Ok(())
+        }
+        Some(Value::Array(values)) if token == "-" => {
+            values.push(value);
+            Ok(())
+        }
+        Some(Value::Array(values)) => {
+            let index = array_index(&token, values.len())?;
+            values.insert(index, value);
+            Ok(())
+        }
+        _ => Err(format!("{} is not an object or an array", parent)),
+    }
+}
+
+fn remove(document: &mut Value, path: &str) -> Result<Value, String> {
+    if path.is_empty() {
+        return Ok(std::mem::replace(document, Value::Null));
+    }
+    let (parent, token) = split_pointer(path)?;
+    let removed = match document.pointer_mut(parent) {
+        Some(Value::Object(entries)) => entries.remove(&token),
+        Some(Value::Array(values)) => match array_index(&token, values.len()) {
+            Ok(index) if index < values.len() => Some(values.remove(index)),
+            _ => None,
+        },
+        _ => None,
+    };
+    removed.ok_or_else(|| format!("{} does not exist", path))
+}
+
+/// Equality as in JSON, where `3` and `3.0` are the same number. Numbers in resources are floats.
+fn json_equal(a: &Value, b: &Value) -> bool {
+    match (a, b) {
+        (Value::Number(a), Value::Number(b)) => a.as_f64() == b.as_f64(),
+        (Value::Array(a), Value::Array(b)) => {
+            a.len() == b.len() && a.iter().zip(b).all(|(a, b)| json_equal(a, b))
+        }
+        (Value::Object(a), Value::Object(b)) => {
+            a.len() == b.len()
+                && a.iter().all(|(key, a)| b.get(key).map_or(false, |b| json_equal(a, b)))
+        }
+        (a, b) => a == b,
+    }
+}
+
+/// Changes a map from the content `from` to `to` with as few operations as possible. Nested maps
+/// and arrays that exist in both stay the same yrs types, so concurrent changes of their other
+/// entries still merge, e.g. two devices appending to the same array. Arrays stored as plain
+/// values by older versions are replaced by yrs arrays.
+pub(crate) fn write_map_diff(
+    transaction: &mut Transaction,
+    map: &Map,
+    from: &serde_json::Map<String, Value>,
+    to: &serde_json::Map<String, Value>,
+) {
+    for key in from.keys().filter(|key| !to.contains_key(*key)) {
+        map.remove(transaction, key);
+    }
+    for (key, value) in to {
+        let previous = from.get(key);
+        if previous.map_or(false, |previous| json_equal(previous, value)) {
+            continue;
+        }
+        let current = map.get(key);
+        match (previous, value) {
+            (Some(Value::Object(previous)), Value::Object(entries)) => {
+                if let Some(nested) = current.and_then(|current| current.to_ymap()) {
+                    write_map_diff(transaction, &nested, previous, entries);
+                    continue;
+                }
+            }
+            (Some(Value::Array(previous)), Value::Array(values)) => {
+                if let Some(array) = current.and_then(|current| current.to_yarray()) {
+                    write_array_diff(transaction, &array, previous, values);
+                    continue;
+                }
+            }
+            _ => {}
+        }
+        insert_json(transaction, map, key, value);
+    }
+}
+
+/// Replaces the entries between the common start and end of `from` and `to`.
+fn write_array_diff(transaction: &mut Transaction, array: &Array, from: &[Value], to: &[Value]) {
+    let prefix = from.iter().zip(to).take_while(|(a, b)| json_equal(a, b)).count();
+    let suffix = from[prefix..]
+        .iter()
+        .rev()
+        .zip(to[prefix..].iter().rev())
+        .take_while(|(a, b)| json_equal(a, b))
+        .count();
+    let removed = from.len() - prefix - suffix;
+    if removed > 0 {
+        array.remove_range(transaction, prefix as u32, removed as u32);
+    }
+    for (offset, value) in to[prefix..to.len() - suffix].iter().enumerate() {
+        insert_json_at(transaction, array, (prefix + offset) as u32, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use serde_json::json;
+
+    use crate::patch::{apply_json_patch, apply_merge_patch};
+
+    #[test]
+    fn json_patch() {
+        let document = json!({"title": "Meeting", "tags": ["a", "b"], "place": {"city": "Zurich"}, "stars": 3.0});
+        let patch = json!([
+            {"op": "test", "path": "/stars", "value": 3},
+            {"op": "add", "path": "/tags/1", "value": "x"},
+            {"op": "add", "path": "/tags/-", "value": "z"},
+            {"op": "remove", "path": "/tags/0"},
+            {"op": "replace", "path": "/title", "value": "Retro"},
+            {"op": "move", "from": "/place/city", "path": "/city"},
+            {"op": "copy", "from": "/city", "path": "/place/from"},
+            {"op": "add", "path": "/a~1b", "value": true}
+        ]);
+        assert_eq!(
+            apply_json_patch(&document, &patch).unwrap(),
+            json!({"title": "Retro", "tags": ["x", "b", "z"], "place": {"from": "Zurich"}, "city": "Zurich", "stars": 3.0, "a/b": true})
+        );
+
+        let failing = [
+            json!([{"op": "test", "path": "/title", "value": "Retro"}]),
+            json!([{"op": "remove", "path": "/missing"}]),
+            json!([{"op": "replace", "path": "/missing", "value": 1}]),
+            json!([{"op": "add", "path": "/tags/5", "value": 1}]),
+            json!([{"op": "add", "path": "/tags/01", "value": 1}]),
+            json!([{"op": "move", "from": "/place", "path": "/place/city"}]),
+            json!([{"op": "rename", "path": "/title"}]),
+            json!({"op": "add", "path": "/title", "value": 1}),
+        ];
+        for patch in failing {
+            assert!(apply_json_patch(&document, &patch).is_err(), "{}", patch);
+        }
+    }
+
+    #[test]
+    fn merge_patch() {
+        // the example of RFC 7396
+        let document = json!({"title": "Goodbye!", "author": {"givenName": "John", "familyName": "Doe"}, "tags": ["example", "sample"], "content": "This will be unchanged"});
+        let patch = json!({"title": "Hello!", "phoneNumber": "+01-123-456-7890", "author": {"familyName": null}, "tags": ["example"]});
+        assert_eq!(
+            apply_merge_patch(&document, &patch),
+            json!({"title": "Hello!", "author": {"givenName": "John"}, "tags": ["example"], "content": "This will be unchanged", "phoneNumber": "+01-123-456-7890"})
+        );
+        assert_eq!(apply_merge_patch(&json!({"a": "b"}), &json!("c")), json!("c"));
+        assert_eq!(apply_merge_patch(&json!("c"), &json!({"a": {"b": null}})), json!({"a": {}}));
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+
+use rusqlite::types::ValueRef;
+use rusqlite::{params, Connection, OpenFlags};
+
+use crate::errors::Error;
+use crate::event_log_store::Log;
+use crate::resource::Resource;
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS entries (
+    resource TEXT NOT NULL,
+    key_path TEXT NOT NULL,
+    value TEXT NOT NULL,
+    author TEXT NOT NULL,
+    updated_at TEXT NOT NULL,
+    PRIMARY KEY (resource, key_path)
+);
+";
+
+fn sqlite_error(e: rusqlite::Error) -> Error {
+    Error::DcoreError(format!("SQLite: {}", e))
+}
+
+/// The leaves of a resource's `root` map by key path, nested keys are separated by a dot like in
+/// `Document::update_resource_with_key_value`.
+pub(crate) fn leaves(resource: &Resource) -> BTreeMap<String, serde_json::Value> {
+    fn collect(prefix: &str, value: serde_json::Value, leaves: &mut BTreeMap<String, serde_json::Value>) {
+        match value {
+            serde_json::Value::Object(map) if !map.is_empty() => {
+                for (key, value) in map {
+                    collect(&format!("{}.{}", prefix, key), value, leaves);
+                }
+            }
+            value => {
+                leaves.insert(prefix.to_string(), value);
+            }
+        }
+    }
+    let mut leaves = BTreeMap::new();
+    if let serde_json::Value::Object(root) = resource.get_json() {
+        for (key, value) in root {
+            collect(&key, value, &mut leaves);
+        }
+    }
+    leaves
+}
+
+/// The leaves of a resource as text, strings are kept as they are, other values are JSON.
+fn flatten(resource: &Resource) -> BTreeMap<String, String> {
+    leaves(resource)
+        .into_iter()
+        .map(|(key_path, value)| match value {
+            serde_json::Value::String(value) => (key_path, value),
+            value => (key_path, value.to_string()),
+        })
+        .collect()
+}
+
+/// Remembers which log last changed a key while the logs of a document are replayed.
+#[derive(Default)]
+pub(crate) struct AuthorTracker {
+    values: HashMap<String, BTreeMap<String, serde_json::Value>>,
+    logs: HashMap<String, HashMap<String, Log>>,
+}
+
+impl AuthorTracker {
+    /// To be called after the updates of a log were applied to the resource.
+    pub(crate) fn applied(&mut self, resource: &Resource, log: &Log) {
+        let values = leaves(resource);
+        let previous = self.values.entry(resource.name.clone()).or_default();
+        let logs = self.logs.entry(resource.name.clone()).or_default();
+        for key_path in changed_key_paths(previous, &values) {
+            logs.insert(key_path, log.clone());
+        }
+        *previous = values;
+    }
+
+    /// The log that last added, changed or removed a key.
+    pub(crate) fn log(&self, resource: &str, key_path: &str) -> Option<&Log> {
+        self.logs.get(resource).and_then(|logs| logs.get(key_path))
+    }
+
+    pub(crate) fn author(&self, resource: &str, key_path: &str) -> String {
+        self.log(resource, key_path)
+            .map(|log| log.fingerprint.clone())
+            .unwrap_or_default()
+    }
+}
+
+/// The key paths that were added, changed or removed between two states of a resource.
+pub(crate) fn changed_key_paths(
+    previous: &BTreeMap<String, serde_json::Value>,
+    current: &BTreeMap<String, serde_json::Value>,
+) -> Vec<String> {
+    let mut changed: Vec<String> = current
+        .iter()
+        .filter(|(key_path, value)| previous.get(*key_path) != Some(value))
+        .map(|(key_path, _)| key_path.clone())
+        .collect();
+    changed.extend(previous.keys().filter(|key_path| !current.contains_key(*key_path)).cloned());
+    changed.sort();
+    changed
+}
+
+/// A materialized SQLite index of the contents of all resources, kept in `.data/projection.sqlite`.
+///
+/// The table `entries` has a row per leaf of a resource's `root` map with the columns `resource`,
+/// `key_path`, `value`, `author` (the fingerprint of who last changed it) and `updated_at`.
+/// Once a document has a projection, it is refreshed on every local update and every load.
+pub struct Projection {
+    connection: Connection,
+}
+
+impl Projection {
+    /// The projection in the repository of a document, `{document directory}/.data/projection.sqlite`.
+    pub fn path(repository_path: &Path) -> PathBuf {
+        repository_path.join("projection.sqlite")
+    }
+
+    pub fn open(path: &Path) -> Result<Projection, Error> {
+        Self::with_connection(Connection::open(path).map_err(sqlite_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<Projection, Error> {
+        Self::with_connection(Connection::open_in_memory().map_err(sqlite_error)?)
+    }
+
+    /// Opens an existing projection for queries only, e.g. from reporting scripts.
+    pub fn open_read_only(path: &Path) -> Result<Projection, Error> {
+        let connection = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(sqlite_error)?;
+        Ok(Projection { connection })
+    }
+
+    fn with_connection(connection: Connection) -> Result<Projection, Error> {
+        connection.execute_batch(SCHEMA).map_err(sqlite_error)?;
+        Ok(Projection { connection })
+    }
+
+    /// Writes the keys of a resource that changed since the last refresh, they are attributed to `author`.
+    /// Returns the number of changed rows.
+    pub fn refresh(&self, resource: &Resource, author: &str) -> Result<usize, Error> {
+        self.write(&resource.name, &flatten(resource), &|_| author.to_string())
+    }
+
+    pub(crate) fn refresh_loaded(&self, resource: &Resource, authors: &AuthorTracker) -> Result<usize, Error> {
+        self.write(&resource.name, &flatten(resource), &|key_path| {
+            authors.author(&resource.name, key_path)
+        })
+    }
+
+    fn write(
+        &self,
+        resource: &str,
+        values: &BTreeMap<String, String>,
+        author_of: &dyn Fn(&str) -> String,
+    ) -> Result<usize, Error> {
+        let transaction = self.connection.unchecked_transaction().map_err(sqlite_error)?;
+        let existing: BTreeMap<String, String> = {
+            let mut statement = transaction
+                .prepare("SELECT key_path, value FROM entries WHERE resource = ?1")
+                .map_err(sqlite_error)?;
+            let rows = statement
+                .query_map(params![resource], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(sqlite_error)?;
+            rows.collect::<Result<_, rusqlite::Error>>().map_err(sqlite_error)?
+        };
+
+        let updated_at = chrono::Utc::now().to_rfc3339();
+        let mut changed = 0;
+        for (key_path, value) in values {
+            if existing.get(key_path) == Some(value) {
+                continue;
+            }
+            transaction
+                .execute(
+                    "INSERT OR REPLACE INTO entries (resource, key_path, value, author, updated_at)
+                     VALUES (?1, ?2, ?3, ?4, ?5)",
+                    params![resource, key_path, value, author_of(key_path), updated_at],
+                )
+                .map_err(sqlite_error)?;
+            changed += 1;
+        }
+        for key_path in existing.keys().filter(|key_path| !values.contains_key(*key_path)) {
+            transaction
+                .execute(
+                    "DELETE FROM entries WHERE resource = ?1 AND key_path = ?2",
+                    params![resource, key_path],
+                )
+                .map_err(sqlite_error)?;
+            changed += 1;
+        }
+        transaction.commit().map_err(sqlite_error)?;
+        Ok(changed)
+    }
+
+    /// Runs a read-only SQL query, e.g. `SELECT resource, value FROM entries WHERE key_path = 'status'`,
+    /// and returns a JSON object per row.
+    pub fn query(&self, sql: &str) -> Result<Vec<serde_json::Value>, Error> {
+        let mut statement = self.connection.prepare(sql).map_err(sqlite_error)?;
+        if !statement.readonly() {
+            return Err(Error::DcoreError("Only queries that do not write are allowed.".to_string()));
+        }
+        let columns: Vec<String> = statement.column_names().iter().map(|name| name.to_string()).collect();
+        let mut rows = statement.query([]).map_err(sqlite_error)?;
+        let mut results = Vec::new();
+        while let Some(row) = rows.next().map_err(sqlite_error)? {
+            let mut object = serde_json::Map::new();
+            for (index, column) in columns.iter().enumerate() {
+                let value = match row.get_ref(index).map_err(sqlite_error)? {
+                    ValueRef::Null => serde_json::Value::Null,
+                    ValueRef::Integer(value) => value.into(),
+                    ValueRef::Real(value) => value.into(),
+                    ValueRef::Text(value) | ValueRef::Blob(value) => String::from_utf8_lossy(value).into(),
+                };
+                object.insert(column.clone(), value);
+            }
+            results.push(serde_json::Value::Object(object));
+        }
+        Ok(results)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use crate::event_log_store::Log;
+    use crate::projection::{AuthorTracker, Projection};
+    use crate::resource::Resource;
+
+    fn set(resource: &mut Resource, key: &'static str, value: &'static str) {
+        resource
+            .add_local_update(|transaction| {
+                let root = transaction.get_map("root");
+                root.insert(transaction, key.to_owned(), value);
+                transaction
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn refresh_and_query() {
+        let projection = Projection::open_in_memory().unwrap();
+        let mut resource = Resource::new(&"notes".to_string());
+        set(&mut resource, "status", "draft");
+        set(&mut resource, "title", "Meeting");
+        assert_eq!(projection.refresh(&resource, "ALICE").unwrap(), 2);
+        assert_eq!(projection.refresh(&resource, "ALICE").unwrap(), 0);
+
+        set(&mut resource, "status", "done");
+        assert_eq!(projection.refresh(&resource, "BOB").unwrap(), 1);
+        let rows = projection
+            .query("SELECT key_path, value, author FROM entries WHERE resource = 'notes' ORDER BY key_path")
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                serde_json::json!({"key_path": "status", "value": "done", "author": "BOB"}),
+                serde_json::json!({"key_path": "title", "value": "Meeting", "author": "ALICE"}),
+            ]
+        );
+        assert!(projection.query("DELETE FROM entries").is_err());
+    }
+
+    #[test]
+    fn track_authors_of_logs() {
+        let mut resource = Resource::new(&"notes".to_string());
+        let mut tracker = AuthorTracker::default();
+        set(&mut resource, "status", "draft");
+        tracker.applied(&resource, &Log::local("notes", "ALICE", "device-0"));
+        set(&mut resource, "title", "Meeting");
+        tracker.applied(&resource, &Log::local("notes", "BOB", "device-0"));
+
+        let projection = Projection::open_in_memory().unwrap();
+        projection.refresh_loaded(&resource, &tracker).unwrap();
+        let rows = projection
+            .query("SELECT key_path, author FROM entries ORDER BY key_path")
+            .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                serde_json::json!({"key_path": "status", "author": "ALICE"}),
+                serde_json::json!({"key_path": "title", "author": "BOB"}),
+            ]
+        );
+    }
+}
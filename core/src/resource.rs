@@ -1,7 +1,8 @@
 use std::collections::HashMap;
 
 use git2::Error;
-use yrs::{Map, Transaction, UpdateEvent};
+use lib0::any::Any;
+use yrs::{Array, Map, PrelimArray, PrelimMap, Transaction, UpdateEvent};
 
 use crate::event::{EventHandler, Subscription};
 
@@ -85,6 +86,103 @@ impl Resource {
         transaction.get_map("root")
     }
 
+    /// The content of the `root` map as JSON, unlike `get_content` which uses the yrs notation.
+    pub fn get_json(&self) -> serde_json::Value {
+        any_to_json(&self.get_root().to_json())
+    }
+
+    /// The JSON Schema in `_resource_meta.schema`, see `Document::set_resource_schema`.
+    pub fn get_schema(&self) -> Option<serde_json::Value> {
+        let mut transaction = self.store.transact();
+        let schema = transaction.get_map("_resource_meta").get("schema")?;
+        serde_json::from_str(&schema.to_string()).ok()
+    }
+}
+
+/// Inserts a JSON value into a map. Objects become nested maps and arrays become yrs arrays, so
+/// their entries can be changed on their own later and concurrent changes of them merge.
+/// Everything else is stored as a plain value.
+pub(crate) fn insert_json(transaction: &mut Transaction, map: &Map, key: &str, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Object(entries) => {
+            map.insert(transaction, key.to_owned(), PrelimMap::<Any>::from(HashMap::default()));
+            let nested = map.get(key).unwrap().to_ymap().unwrap();
+            for (nested_key, nested_value) in entries {
+                insert_json(transaction, &nested, nested_key, nested_value);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            map.insert(transaction, key.to_owned(), PrelimArray::<Vec<Any>, Any>::from(Vec::new()));
+            let nested = map.get(key).unwrap().to_yarray().unwrap();
+            for (index, nested_value) in values.iter().enumerate() {
+                insert_json_at(transaction, &nested, index as u32, nested_value);
+            }
+        }
+        value => {
+            map.insert(transaction, key.to_owned(), json_to_any(value));
+        }
+    }
+}
+
+/// Inserts a JSON value into an array at `index`, like `insert_json` does for maps.
+pub(crate) fn insert_json_at(transaction: &mut Transaction, array: &Array, index: u32, value: &serde_json::Value) {
+    match value {
+        serde_json::Value::Object(entries) => {
+            array.insert(transaction, index, PrelimMap::<Any>::from(HashMap::default()));
+            let nested = array.get(index).unwrap().to_ymap().unwrap();
+            for (nested_key, nested_value) in entries {
+                insert_json(transaction, &nested, nested_key, nested_value);
+            }
+        }
+        serde_json::Value::Array(values) => {
+            array.insert(transaction, index, PrelimArray::<Vec<Any>, Any>::from(Vec::new()));
+            let nested = array.get(index).unwrap().to_yarray().unwrap();
+            for (nested_index, nested_value) in values.iter().enumerate() {
+                insert_json_at(transaction, &nested, nested_index as u32, nested_value);
+            }
+        }
+        value => {
+            array.insert(transaction, index, json_to_any(value));
+        }
+    }
+}
+
+fn json_to_any(value: &serde_json::Value) -> Any {
+    match value {
+        serde_json::Value::Null => Any::Null,
+        serde_json::Value::Bool(value) => Any::Bool(*value),
+        // like in JavaScript, all numbers are floats
+        serde_json::Value::Number(value) => Any::Number(value.as_f64().unwrap_or_default()),
+        serde_json::Value::String(value) => Any::String(value.as_str().into()),
+        serde_json::Value::Array(values) => {
+            Any::Array(values.iter().map(json_to_any).collect::<Vec<Any>>().into_boxed_slice())
+        }
+        serde_json::Value::Object(entries) => Any::Map(Box::new(
+            entries
+                .iter()
+                .map(|(key, value)| (key.clone(), json_to_any(value)))
+                .collect(),
+        )),
+    }
+}
+
+fn any_to_json(any: &Any) -> serde_json::Value {
+    match any {
+        Any::Null | Any::Undefined => serde_json::Value::Null,
+        Any::Bool(value) => serde_json::Value::Bool(*value),
+        Any::Number(value) => serde_json::json!(value),
+        Any::BigInt(value) => serde_json::json!(value),
+        Any::String(value) => serde_json::Value::String(value.to_string()),
+        Any::Buffer(value) => serde_json::json!(value.as_ref()),
+        Any::Array(values) => serde_json::Value::Array(values.iter().map(any_to_json).collect()),
+        Any::Map(entries) => serde_json::Value::Object(
+            entries
+                .iter()
+                .map(|(key, value)| (key.clone(), any_to_json(value)))
+                .collect(),
+        ),
+    }
+
 }
 
 #[cfg(test)]
@@ -106,6 +204,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "todo".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
         .unwrap();
         let _doc = doc
@@ -131,6 +230,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "todo".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
         .unwrap();
         let _doc = doc
@@ -153,6 +253,7 @@ mod tests {
             directory: PathBuf::from(doc_dir),
             identity_fingerprint: "todo".to_string(),
             name: String::from("name"),
+            gpg_home: None,
         })
         .unwrap();
         let _doc = doc
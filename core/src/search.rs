@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use lib0::any::Any;
+use rusqlite::{params, Connection};
+use yrs::types::Value;
+
+use crate::errors::Error;
+use crate::resource::Resource;
+
+const SCHEMA: &str = "
+CREATE VIRTUAL TABLE IF NOT EXISTS search USING fts5(resource UNINDEXED, key_path UNINDEXED, value);
+";
+
+fn sqlite_error(e: rusqlite::Error) -> Error {
+    Error::DcoreError(format!("SQLite: {}", e))
+}
+
+/// A string value that matched a search, the matches are in square brackets in the snippet.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SearchHit {
+    pub resource: String,
+    pub key_path: String,
+    pub snippet: String,
+}
+
+/// The searchable values of a resource by key path: its strings and texts, also those in arrays.
+/// Nested keys and array indexes are separated by a dot, e.g. `meeting.tags.0`.
+fn texts(resource: &Resource) -> BTreeMap<String, String> {
+    fn collect(key_path: String, value: Value, texts: &mut BTreeMap<String, String>) {
+        match value {
+            Value::YText(text) => {
+                texts.insert(key_path, text.to_string());
+            }
+            Value::YMap(map) => {
+                for (key, value) in map.iter() {
+                    collect(format!("{}.{}", key_path, key), value, texts);
+                }
+            }
+            Value::YArray(array) => {
+                for (index, value) in array.iter().enumerate() {
+                    collect(format!("{}.{}", key_path, index), value, texts);
+                }
+            }
+            Value::Any(value) => collect_any(key_path, &value, texts),
+            _ => {}
+        }
+    }
+    fn collect_any(key_path: String, value: &Any, texts: &mut BTreeMap<String, String>) {
+        match value {
+            Any::String(value) => {
+                texts.insert(key_path, value.to_string());
+            }
+            Any::Map(map) => {
+                for (key, value) in map.iter() {
+                    collect_any(format!("{}.{}", key_path, key), value, texts);
+                }
+            }
+            Any::Array(values) => {
+                for (index, value) in values.iter().enumerate() {
+                    collect_any(format!("{}.{}", key_path, index), value, texts);
+                }
+            }
+            _ => {}
+        }
+    }
+    let mut texts = BTreeMap::new();
+    for (key, value) in resource.get_root().iter() {
+        collect(key.to_string(), value, &mut texts);
+    }
+    texts
+}
+
+/// A full-text index of the strings and texts of all resources, kept in `.data/search.sqlite`.
+///
+/// Like the `Projection`, once a document has an index it is updated on every local update and
+/// every load, only the values that changed are indexed again.
+pub struct SearchIndex {
+    connection: Connection,
+}
+
+impl SearchIndex {
+    /// The index in the repository of a document, `{document directory}/.data/search.sqlite`.
+    pub fn path(repository_path: &Path) -> PathBuf {
+        repository_path.join("search.sqlite")
+    }
+
+    pub fn open(path: &Path) -> Result<SearchIndex, Error> {
+        Self::with_connection(Connection::open(path).map_err(sqlite_error)?)
+    }
+
+    pub fn open_in_memory() -> Result<SearchIndex, Error> {
+        Self::with_connection(Connection::open_in_memory().map_err(sqlite_error)?)
+    }
+
+    fn with_connection(connection: Connection) -> Result<SearchIndex, Error> {
+        connection.execute_batch(SCHEMA).map_err(sqlite_error)?;
+        Ok(SearchIndex { connection })
+    }
+
+    /// Indexes the strings and texts of a resource that changed since the last refresh.
+    /// Returns the number of changed values.
+    pub fn refresh(&self, resource: &Resource) -> Result<usize, Error> {
+        let values = texts(resource);
+
+        let transaction = self.connection.unchecked_transaction().map_err(sqlite_error)?;
+        let existing: BTreeMap<String, String> = {
+            let mut statement = transaction
+                .prepare("SELECT key_path, value FROM search WHERE resource = ?1")
+                .map_err(sqlite_error)?;
+            let rows = statement
+                .query_map(params![resource.name], |row| Ok((row.get(0)?, row.get(1)?)))
+                .map_err(sqlite_error)?;
+            rows.collect::<Result<_, rusqlite::Error>>().map_err(sqlite_error)?
+        };
+
+        let mut changed = 0;
+        for (key_path, value) in &existing {
+            if values.get(key_path) != Some(value) {
+                transaction
+                    .execute(
+                        "DELETE FROM search WHERE resource = ?1 AND key_path = ?2",
+                        params![resource.name, key_path],
+                    )
+                    .map_err(sqlite_error)?;
+                changed += 1;
+            }
+        }
+        for (key_path, value) in &values {
+            if existing.get(key_path) != Some(value) {
+                transaction
+                    .execute(
+                        "INSERT INTO search (resource, key_path, value) VALUES (?1, ?2, ?3)",
+                        params![resource.name, key_path, value],
+                    )
+                    .map_err(sqlite_error)?;
+                if !existing.contains_key(key_path) {
+                    changed += 1;
+                }
+            }
+        }
+        transaction.commit().map_err(sqlite_error)?;
+        Ok(changed)
+    }
+
+    /// Searches the indexed values, best matches first. The query uses the SQLite FTS5 syntax,
+    /// e.g. `budget`, `"exact phrase"` or `meet*`.
+    pub fn search(&self, query: &str) -> Result<Vec<SearchHit>, Error> {
+        let mut statement = self
+            .connection
+            .prepare(
+                "SELECT resource, key_path, snippet(search, 2, '[', ']', '...', 12) FROM search
+                 WHERE search MATCH ?1 ORDER BY rank",
+            )
+            .map_err(sqlite_error)?;
+        let hits = statement
+            .query_map(params![query], |row| {
+                Ok(SearchHit {
+                    resource: row.get(0)?,
+                    key_path: row.get(1)?,
+                    snippet: row.get(2)?,
+                })
+            })
+            .map_err(sqlite_error)?;
+        hits.collect::<Result<Vec<SearchHit>, rusqlite::Error>>().map_err(sqlite_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use yrs::types::text::PrelimText;
+    use yrs::{Array, PrelimArray};
+
+    use crate::resource::Resource;
+    use crate::search::{SearchHit, SearchIndex};
+
+    fn set(resource: &mut Resource, key: &'static str, value: &'static str) {
+        resource
+            .add_local_update(|transaction| {
+                let root = transaction.get_map("root");
+                root.insert(transaction, key.to_owned(), value);
+                transaction
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn search_string_values() {
+        let index = SearchIndex::open_in_memory().unwrap();
+        let mut notes = Resource::new(&"notes".to_string());
+        set(&mut notes, "monday", "We discussed the budget for the next release");
+        set(&mut notes, "tuesday", "Nothing to report");
+        assert_eq!(index.refresh(&notes).unwrap(), 2);
+        assert_eq!(index.refresh(&notes).unwrap(), 0);
+
+        assert_eq!(
+            index.search("budget").unwrap(),
+            vec![SearchHit {
+                resource: "notes".to_string(),
+                key_path: "monday".to_string(),
+                snippet: "We discussed the [budget] for the next release".to_string(),
+            }]
+        );
+
+        // changed values are indexed again
+        set(&mut notes, "monday", "The budget was approved");
+        set(&mut notes, "tuesday", "Approved by everyone");
+        assert_eq!(index.refresh(&notes).unwrap(), 2);
+        let hits = index.search("approv*").unwrap();
+        assert_eq!(hits.len(), 2);
+        assert!(index.search("discussed").unwrap().is_empty());
+    }
+
+    #[test]
+    fn search_texts_and_arrays() {
+        let index = SearchIndex::open_in_memory().unwrap();
+        let mut notes = Resource::new(&"notes".to_string());
+        notes
+            .add_local_update(|transaction| {
+                let root = transaction.get_map("root");
+                root.insert(transaction, "body".to_owned(), PrelimText("Minutes of the planning meeting"));
+                root.insert(transaction, "tags".to_owned(), PrelimArray::<Vec<String>, String>::from(vec![
+                    "budget".to_string(),
+                    "release".to_string(),
+                ]));
+                transaction
+            })
+            .unwrap();
+        assert_eq!(index.refresh(&notes).unwrap(), 3);
+
+        let hits = index.search("planning").unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].key_path, "body");
+        let hits = index.search("release").unwrap();
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].key_path, "tags.1");
+
+        // edits of the text are indexed again
+        notes
+            .add_local_update(|transaction| {
+                let tags = transaction.get_map("root").get("tags").unwrap().to_yarray().unwrap();
+                tags.push_back(transaction, "retrospective");
+                let body = transaction.get_map("root").get("body").unwrap().to_ytext().unwrap();
+                body.push(transaction, " and retrospective");
+                transaction
+            })
+            .unwrap();
+        assert_eq!(index.refresh(&notes).unwrap(), 2);
+        assert_eq!(index.search("retrospective").unwrap().len(), 2);
+    }
+}
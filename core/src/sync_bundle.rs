@@ -0,0 +1,302 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use git2::Oid;
+
+use crate::document_utils::DocumentUtils;
+use crate::errors::Error;
+use crate::Document;
+
+const BUNDLE_SIGNATURE: &str = "# v2 git bundle\n";
+
+/// Offline sync for documents that can not reach a remote.
+///
+/// The event-logs are written to a single file in the git bundle (v2) format, which can be
+/// carried to the other site and imported there. The logs are stored under the same names as
+/// on a git hub (`refs/heads/{resource}/{fingerprint}/{device}`), so `git bundle verify` and
+/// `git fetch` work on the file too.
+pub struct BundleSync;
+
+/// What a bundle export or import did.
+///
+/// `logs` holds the number of commits written (export) or merged (import) per log,
+/// `rejected` the logs of a bundle that were not imported with the reason.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct BundleReport {
+    pub logs: BTreeMap<String, usize>,
+    pub rejected: BTreeMap<String, String>,
+}
+
+impl BundleReport {
+    pub fn is_success(&self) -> bool {
+        self.rejected.is_empty()
+    }
+}
+
+impl fmt::Display for BundleReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Logs:")?;
+        if self.logs.is_empty() {
+            writeln!(f, "\t(none)")?;
+        }
+        for (log, commits) in &self.logs {
+            writeln!(f, "\t- {}: {} commit(s)", log, commits)?;
+        }
+        if !self.rejected.is_empty() {
+            writeln!(f, "Rejected logs:")?;
+        }
+        for (log, reason) in &self.rejected {
+            writeln!(f, "\t- {}: {}", log, reason)?;
+        }
+        Ok(())
+    }
+}
+
+struct BundleHeader {
+    /// Commits the importing side must already have
+    prerequisites: Vec<Oid>,
+    heads: Vec<(Oid, String)>,
+}
+
+impl BundleHeader {
+    /// Splits a bundle into its header and the pack data that follows it.
+    fn parse(content: &[u8]) -> Result<(BundleHeader, &[u8]), Error> {
+        if !content.starts_with(BUNDLE_SIGNATURE.as_bytes()) {
+            return Err(Error::DcoreError("The file is not a git bundle (v2).".to_string()));
+        }
+
+        let mut header = BundleHeader {
+            prerequisites: Vec::new(),
+            heads: Vec::new(),
+        };
+        let mut position = BUNDLE_SIGNATURE.len();
+        loop {
+            let end = content[position..]
+                .iter()
+                .position(|byte| *byte == b'\n')
+                .ok_or_else(|| Error::DcoreError("The bundle header is truncated.".to_string()))?
+                + position;
+            let line = std::str::from_utf8(&content[position..end])?;
+            position = end + 1;
+
+            // an empty line ends the header, the pack starts after it
+            if line.is_empty() {
+                break;
+            }
+            if let Some(prerequisite) = line.strip_prefix('-') {
+                let oid = prerequisite.split(' ').next().unwrap_or_default();
+                header.prerequisites.push(Oid::from_str(oid)?);
+            } else {
+                let (oid, name) = line.split_once(' ').ok_or_else(|| {
+                    Error::DcoreError(format!("Invalid line in bundle header: {}", line))
+                })?;
+                header.heads.push((Oid::from_str(oid)?, name.to_string()));
+            }
+        }
+        Ok((header, &content[position..]))
+    }
+}
+
+impl BundleSync {
+    /// Writes all the event-logs of the document to `path`.
+    ///
+    /// If `since` points to an earlier bundle, only the commits that are not in it are written
+    /// and the importing side needs to have imported the earlier bundle first.
+    pub fn export(doc: &Document, path: &Path, since: Option<&Path>) -> Result<BundleReport, Error> {
+        let heads = DocumentUtils::log_heads(doc)?;
+
+        let mut prerequisites = Vec::new();
+        if let Some(since) = since {
+            let content = fs::read(since)?;
+            let (earlier, _) = BundleHeader::parse(&content)?;
+            prerequisites = earlier
+                .heads
+                .into_iter()
+                .map(|(oid, _)| oid)
+                .filter(|oid| doc.repository.find_commit(*oid).is_ok())
+                .collect();
+            prerequisites.sort();
+            prerequisites.dedup();
+        }
+
+        let (pack, commits_per_log) = DocumentUtils::pack_logs(doc, &heads, &prerequisites)?;
+        let report = BundleReport {
+            logs: commits_per_log,
+            rejected: BTreeMap::new(),
+        };
+
+        let mut bundle = Vec::new();
+        bundle.write_all(BUNDLE_SIGNATURE.as_bytes())?;
+        for prerequisite in &prerequisites {
+            writeln!(bundle, "-{} update.", prerequisite)?;
+        }
+        for (name, head) in &heads {
+            writeln!(bundle, "{} {}", head, name)?;
+        }
+        bundle.write_all(b"\n")?;
+        bundle.write_all(&pack)?;
+        fs::write(path, bundle)?;
+
+        Ok(report)
+    }
+
+    /// Reads the event-logs of a bundle into the document.
+    ///
+    /// Like a fetch, the logs are stored in `refs/origin/*`. A log is only merged if all its new
+    /// commits are signed by the identity the log belongs to, and that identity is a member of
+    /// the document. The document is reloaded afterwards.
+    pub fn import(doc: &mut Document, path: &Path) -> Result<BundleReport, Error> {
+        let content = fs::read(path)?;
+        let (header, pack) = BundleHeader::parse(&content)?;
+
+        for prerequisite in &header.prerequisites {
+            if doc.repository.find_commit(*prerequisite).is_err() {
+                return Err(Error::DcoreError(format!(
+                    "The bundle needs commit {} which is missing, import the earlier bundle first.",
+                    prerequisite
+                )));
+            }
+        }
+
+        DocumentUtils::write_pack(doc, pack)?;
+
+        let heads = header
+            .heads
+            .into_iter()
+            .map(|(head, name)| (name, head))
+            .collect();
+        let (logs, rejected) = DocumentUtils::merge_remote_logs(doc, heads)?;
+        let report = BundleReport { logs, rejected };
+
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::path::PathBuf;
+
+    use crate::document::DocumentNewOptions;
+    use crate::gpg::{CreateUserArgs, Gpg};
+    use crate::sync_bundle::BundleSync;
+    use crate::test_utils::{create_test_gpg_home, get_test_key, open_test_device};
+    use crate::Document;
+
+    #[test]
+    fn export_and_import_bundles() {
+        let test_dir = PathBuf::from("./.test/sync_bundle/export_and_import_bundles/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let first_bundle = test_dir.join("first.bundle");
+        let second_bundle = test_dir.join("second.bundle");
+        let test_log = format!("refs/heads/test/{}/device-a", fingerprint);
+        let config_log = format!("refs/heads/config/{}/device-a", fingerprint);
+
+        let mut doc_a = open_test_device(&gpg_home, &test_dir.join("device-a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+        doc_a.add_resource("test".to_string()).unwrap();
+        doc_a.update_resource_with_key_value("test", "entry", "1234").unwrap();
+
+        let report = BundleSync::export(&doc_a, &first_bundle, None).unwrap();
+        assert_eq!(report.logs.get(&test_log), Some(&2));
+        assert_eq!(report.logs.get(&config_log), Some(&1));
+
+        let mut doc_b = open_test_device(&gpg_home, &test_dir.join("device-b"), "device-b");
+        let report = BundleSync::import(&mut doc_b, &first_bundle).unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.logs.get(&test_log), Some(&2));
+        let content = doc_b.resources.get("test").unwrap().get_content();
+        assert_eq!(content, "{entry: 1234}");
+
+        // the second bundle only contains what changed since the first one
+        doc_a.update_resource_with_key_value("test", "entry", "2345").unwrap();
+        let report = BundleSync::export(&doc_a, &second_bundle, Some(&first_bundle)).unwrap();
+        assert_eq!(report.logs.get(&test_log), Some(&1));
+        assert_eq!(report.logs.get(&config_log), Some(&0));
+
+        let report = BundleSync::import(&mut doc_b, &second_bundle).unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.logs.get(&test_log), Some(&1));
+        let content = doc_b.resources.get("test").unwrap().get_content();
+        assert_eq!(content, "{entry: 2345}");
+    }
+
+    #[test]
+    fn import_rejects_unsigned_commits() {
+        let test_dir = PathBuf::from("./.test/sync_bundle/import_rejects_unsigned_commits/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let bundle = test_dir.join("tampered.bundle");
+
+        let doc_a = open_test_device(&gpg_home, &test_dir.join("device-a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+
+        // somebody appends an unsigned commit to the config log
+        let config_log = format!("refs/local/config/{}/device-a", fingerprint);
+        {
+            let repository = &doc_a.repository;
+            let parent = repository.find_reference(&config_log).unwrap().peel_to_commit().unwrap();
+            let signature = git2::Signature::now("Mallory", "mallory@example.com").unwrap();
+            repository
+                .commit(Some(&config_log), &signature, &signature, "update.", &parent.tree().unwrap(), &[&parent])
+                .unwrap();
+        }
+        BundleSync::export(&doc_a, &bundle, None).unwrap();
+
+        let mut doc_b = open_test_device(&gpg_home, &test_dir.join("device-b"), "device-b");
+        let report = BundleSync::import(&mut doc_b, &bundle).unwrap();
+        assert!(!report.is_success());
+        assert!(report
+            .rejected
+            .contains_key(&format!("refs/heads/config/{}/device-a", fingerprint)));
+        assert!(doc_b.resources.get("config").is_none());
+    }
+
+    #[test]
+    fn import_trusts_the_document_creator_on_first_use() {
+        let test_dir = PathBuf::from("./.test/sync_bundle/import_trusts_the_document_creator_on_first_use/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let bundle = test_dir.join("doc.bundle");
+        let bob = Gpg::in_home(Some(gpg_home.as_path()))
+            .unwrap()
+            .create_key(CreateUserArgs {
+                email: "bob@colomba.link",
+                name: "Bob",
+            })
+            .unwrap()
+            .fingerprint;
+
+        // alice creates the document and adds bob as a member
+        let mut doc_a = open_test_device(&gpg_home, &test_dir.join("device-a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+        let member = serde_json::json!({
+            "fingerprint": bob,
+            "public_key": Gpg::get_armored_public_key(Some(gpg_home.as_path()), &bob).unwrap(),
+        });
+        doc_a.update_resource_with_json("config", &bob, &member).unwrap();
+        BundleSync::export(&doc_a, &bundle, None).unwrap();
+
+        // bob opens the document for the first time with his own identity
+        let mut doc_b = Document::new(DocumentNewOptions {
+            directory: test_dir.join("device-b"),
+            identity_fingerprint: bob.clone(),
+            name: String::from("name"),
+            gpg_home: Some(gpg_home.clone()),
+        })
+        .unwrap();
+        doc_b.config_set_local_device("device-b").unwrap();
+        let report = BundleSync::import(&mut doc_b, &bundle).unwrap();
+        assert!(report.is_success(), "{:?}", report.rejected);
+        let members = doc_b.config_get_members().unwrap();
+        assert!(members.contains_key(&fingerprint));
+        assert!(members.contains_key(&bob));
+    }
+}
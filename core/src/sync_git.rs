@@ -1,32 +1,142 @@
-use std::borrow::Borrow;
-use std::collections::{HashMap, HashSet};
-use git2::{BranchType, Cred, PushOptions};
-use libp2p::swarm::KeepAlive::No;
-use crate::{Document, Identity};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::path::Path;
+
+use git2::{Cred, Direction, Oid, PushOptions, Remote, Repository, RepositoryInitOptions};
+
+use crate::Document;
+use crate::document::DocumentNewOptions;
+use crate::document_utils::CommitVerifier;
+use crate::equivocation::{update_head, HeadUpdate};
 use crate::errors::Error;
+use crate::event_log_store::{load_resources, Log};
 
 pub struct GitSync;
 
+/// The git config key of the private ssh key for the remote, see `GitSync::get_credentials`.
+const SSH_KEY_CONFIG: &str = "dcore.sshKey";
+
+/// A local log and the remote log it is pushed to.
+struct LogToPush {
+    reference: String,
+    remote_ref: String,
+    head: Oid,
+}
+
+/// The outcome of pushing one of the local event-logs to the remote.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PushStatus {
+    /// The remote accepted the new head of the log.
+    Pushed,
+    /// The remote already has the head of the log, nothing was sent.
+    UpToDate,
+    /// The remote refused the update, with the reason it reported.
+    Rejected(String),
+}
+
+impl fmt::Display for PushStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushStatus::Pushed => write!(f, "pushed"),
+            PushStatus::UpToDate => write!(f, "up to date"),
+            PushStatus::Rejected(reason) => write!(f, "rejected ({})", reason),
+        }
+    }
+}
+
+/// What a call to `GitSync::sync` did.
+///
+/// `pushed` is keyed by the local log name `refs/local/{resource}/{fingerprint}/{device}`,
+/// `fetched` by the remote tracking log `refs/origin/...` and holds the number of new commits.
+/// `forked` holds the remote logs whose history was rewritten by their author, see
+/// `equivocation::Fork`, `rejected` the remote logs that were refused, e.g. because a commit is not
+/// signed by the author, both with the reason they were not taken over.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct SyncReport {
+    pub pushed: BTreeMap<String, PushStatus>,
+    pub fetched: BTreeMap<String, usize>,
+    pub forked: BTreeMap<String, String>,
+    pub rejected: BTreeMap<String, String>,
+}
+
+impl SyncReport {
+    /// False if the remote rejected at least one of the local logs, or a remote log was rewritten
+    /// or refused.
+    pub fn is_success(&self) -> bool {
+        self.forked.is_empty()
+            && self.rejected.is_empty()
+            && self
+                .pushed
+                .values()
+                .all(|status| !matches!(status, PushStatus::Rejected(_)))
+    }
+}
+
+impl fmt::Display for SyncReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Pushed logs:")?;
+        if self.pushed.is_empty() {
+            writeln!(f, "\t(none)")?;
+        }
+        for (log, status) in &self.pushed {
+            writeln!(f, "\t- {}: {}", log, status)?;
+        }
+        writeln!(f, "Fetched logs:")?;
+        if self.fetched.is_empty() {
+            writeln!(f, "\t(none)")?;
+        }
+        for (log, commits) in &self.fetched {
+            writeln!(f, "\t- {}: {} new commit(s)", log, commits)?;
+        }
+        if !self.forked.is_empty() {
+            writeln!(f, "Forked logs:")?;
+        }
+        for (log, reason) in &self.forked {
+            writeln!(f, "\t- {}: {}", log, reason)?;
+        }
+        if !self.rejected.is_empty() {
+            writeln!(f, "Rejected logs:")?;
+        }
+        for (log, reason) in &self.rejected {
+            writeln!(f, "\t- {}: {}", log, reason)?;
+        }
+        Ok(())
+    }
+}
+
 impl GitSync {
-    pub(crate) fn clone(doc: Document, remote: &str) -> Result<(), Error> {
-        let mut pull_options = git2::FetchOptions::new();
-        let mut callbacks = git2::RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, allowed_types| {
-            Self::get_credentials(&doc.identity)
-        });
-        pull_options.remote_callbacks(callbacks);
+    /// Creates an empty bare repository that documents can use as their remote,
+    /// e.g. a directory on a USB stick or on a shared drive.
+    pub fn create_hub(path: &Path) -> Result<Repository, Error> {
+        let repository = Repository::init_opts(path, RepositoryInitOptions::new().bare(true))?;
+        Ok(repository)
+    }
 
+    /// Local remotes (plain paths) are turned into absolute paths, such that they still
+    /// resolve when dcore runs in another directory. Urls, including `file://`, and
+    /// scp-like remotes (`git@host:path`) are returned as they are.
+    pub fn normalize_remote(remote: &str) -> String {
+        if remote.contains("://") {
+            return remote.to_string();
+        }
+        match std::fs::canonicalize(remote) {
+            Ok(path) => path.to_string_lossy().to_string(),
+            Err(_) => remote.to_string(),
+        }
+    }
 
+    pub(crate) fn clone(doc: &Document, remote: &str) -> Result<(), Error> {
         doc.repository.remote_set_url("origin", remote)?;
-        let mut remote = doc.repository.find_remote("origin").unwrap();
+        let mut remote = doc.repository.find_remote("origin")?;
 
-        remote.fetch(&["+refs/heads/*:refs/origin/*"], Some(&mut pull_options), None).unwrap();
+        let remote_heads = Self::remote_heads(doc, &mut remote)?;
+        Self::fetch(doc, &mut remote, &remote_heads, &mut SyncReport::default())?;
         Ok(())
     }
 }
 
 impl GitSync {
-    pub fn sync(doc: Document) -> Result<(), Error> {
+    pub fn sync(doc: &Document) -> Result<SyncReport, Error> {
         // Frist we need to get the remote repo
         let remote = doc.config_get_remote();
         let remote = match remote {
@@ -38,159 +148,588 @@ impl GitSync {
             },
         };
 
-        // Then we push all our local event-logs to the remote
-
-        doc.repository.config().unwrap().set_str("user.name", "fuubi").unwrap();
         doc.repository.remote_set_url("origin", remote.as_str())?;
 
-        let local_device = doc.config_get_local_device().unwrap();
-        let local_fingerprint = doc.identity.get_fingerprint();
-        let identify_push_suffix = format!("{}/{}", local_fingerprint, local_device);
-
-        let mut refs_to_push = HashSet::new();
-            doc.repository
-            .references()
-            .map_err(|e| Error::GitError(e))
-            .unwrap()
-            .for_each(|log| {
-                let reference = log.unwrap().name().unwrap().clone().to_string();
-                // refs/local/
-                if reference.starts_with("refs/local/") {
-                    refs_to_push.insert(reference);
+        let mut report = SyncReport::default();
+        let mut remote = doc.repository.find_remote("origin")?;
+
+        // Then we pull the remote event-logs that changed since the last sync
+        let remote_heads = Self::remote_heads(&doc, &mut remote)?;
+        Self::fetch(&doc, &mut remote, &remote_heads, &mut report)?;
+
+        // Then we push the event-logs of our identity and device in one go
+        let mut logs_to_push = Vec::new();
+        for (reference, head) in Self::owned_logs(&doc)? {
+            let remote_ref = reference.replacen("refs/local/", "refs/heads/", 1);
+            match remote_heads.get(&remote_ref) {
+                Some(remote_head) if *remote_head == head => {
+                    report.pushed.insert(reference, PushStatus::UpToDate);
+                    continue;
                 }
-            });
-
-        let mut update_status = HashMap::new();
-        let mut remote = doc.repository.find_remote("origin").unwrap();
-        for reference in refs_to_push {
-            let mut callbacks = git2::RemoteCallbacks::new();
-            callbacks.credentials(|_url, username_from_url, allowed_types| {
-                Self::get_credentials(&doc.identity)
-            });
-
-            callbacks.push_update_reference(|refname, status| {
-                update_status.insert(refname.to_string(), status.map(|s| s.to_string()));
-                Ok(())
-            });
-            let mut push_options = PushOptions::new();;
-            push_options.remote_callbacks(callbacks);
-            let remote_ref = reference.replace("local", "heads");
-            remote.push(
-                &[format!("{}:{}", reference, remote_ref)],
-                Some(&mut push_options)
-            ).unwrap();
-
+                Some(remote_head) if !Self::extends(&doc.repository, head, *remote_head) => {
+                    let reason = format!(
+                        "the remote log has commits that are not in ours, another machine probably \
+                         uses the device name {}. Give this machine its own name with \
+                         config-set-device-name and sync again",
+                        doc.config_get_local_device()?
+                    );
+                    report.pushed.insert(reference, PushStatus::Rejected(reason));
+                    continue;
+                }
+                _ => {}
+            }
+            logs_to_push.push(LogToPush { reference, remote_ref, head });
         }
+        report.pushed.extend(Self::push(&doc, &mut remote, &logs_to_push)?);
 
+        Ok(report)
+    }
 
-        let logs_to_pull = doc.repository
-            .branches(Some(BranchType::Remote))
-            .map_err(|e| Error::GitError(e))
-            .unwrap()
-            .map(|log| log.unwrap().0 )
-            .filter(|log| !log.name().unwrap().unwrap().ends_with(&identify_push_suffix));
+    /// Opens the document again and syncs it, such that a sync can run on a thread of its own
+    /// while the document stays open, see `Document::reopen_options`. Only the resources are
+    /// loaded and hooks do not run, the caller loads the fetched logs into its document.
+    pub fn sync_reopened(options: DocumentNewOptions) -> Result<SyncReport, Error> {
+        let mut document = Document::new(options)?;
+        document.resources = load_resources(&document.event_log_store())?;
+        Self::sync(&document)
+    }
 
+    /// The logs `refs/local/{resource}/{fingerprint}/{device}` written by the local identity
+    /// on the local device, together with their heads. Logs of other identities or devices
+    /// that were copied locally are never pushed by us.
+    fn owned_logs(doc: &Document) -> Result<Vec<(String, Oid)>, Error> {
+        let local_device = doc.config_get_local_device()?;
+        let local_fingerprint = doc.identity.get_fingerprint();
+        let identify_push_suffix = format!("/{}/{}", local_fingerprint, local_device);
+
+        let mut logs = Vec::new();
+        for log in doc.repository.references_glob("refs/local/*")? {
+            let log = log?;
+            let name = log.name().unwrap_or_default();
+            if !name.ends_with(&identify_push_suffix) {
+                continue;
+            }
+            if let Some(head) = log.target() {
+                logs.push((name.to_string(), head));
+            }
+        }
+        Ok(logs)
+    }
 
+    /// Asks the remote for the heads of all its event-logs, keyed by `refs/heads/...`.
+    fn remote_heads(doc: &Document, remote: &mut Remote) -> Result<HashMap<String, Oid>, Error> {
         let mut callbacks = git2::RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, allowed_types| {
-            Self::get_credentials(&doc.identity)
+        callbacks.credentials(|_url, username_from_url, _allowed_types| {
+            Self::get_credentials(&doc.repository, username_from_url)
         });
+        let connection = remote.connect_auth(Direction::Fetch, Some(callbacks), None)?;
+        let heads = connection
+            .list()?
+            .iter()
+            .filter(|head| head.name().starts_with("refs/heads/"))
+            .map(|head| (head.name().to_string(), head.oid()))
+            .collect();
+        Ok(heads)
+    }
 
-        let mut pull_options = git2::FetchOptions::new();
+    /// Fetches the remote event-logs whose head differs from our `refs/origin/*` copy. They are
+    /// fetched to `refs/incoming/*` first and only moved to `refs/origin/*` if their new commits
+    /// are signed by the author of the log and they descend from the head we accepted before, see
+    /// `equivocation::update_head`. Adds the number of new
+    /// commits for every log that changed and the logs that were rewritten to the report.
+    fn fetch(
+        doc: &Document,
+        remote: &mut Remote,
+        remote_heads: &HashMap<String, Oid>,
+        report: &mut SyncReport,
+    ) -> Result<(), Error> {
+        let names: Vec<&String> = remote_heads
+            .iter()
+            .filter(|(name, head)| {
+                let tracking_ref = name.replacen("refs/heads/", "refs/origin/", 1);
+                Self::ref_target(&doc.repository, &tracking_ref) != Some(**head)
+            })
+            .map(|(name, _)| name)
+            .collect();
+        // an empty list would make git fall back to the configured refspecs
+        if names.is_empty() {
+            return Ok(());
+        }
+        let refspecs: Vec<String> = names
+            .iter()
+            .map(|name| format!("+{}:{}", name, name.replacen("refs/heads/", "refs/incoming/", 1)))
+            .collect();
 
-        let mut callbacks = git2::RemoteCallbacks::new();
-        callbacks.credentials(|_url, username_from_url, allowed_types| {
-            Self::get_credentials(&doc.identity)
-        });
-        pull_options.remote_callbacks(callbacks);
+        {
+            let mut callbacks = git2::RemoteCallbacks::new();
+            callbacks.credentials(|_url, username_from_url, _allowed_types| {
+                Self::get_credentials(&doc.repository, username_from_url)
+            });
 
-        remote.fetch(&["+refs/heads/*:refs/origin/*"], Some(&mut pull_options), None).unwrap();
-        // Then we pull all the remote event-logs to our local repo
+            let mut pull_options = git2::FetchOptions::new();
+            pull_options.remote_callbacks(callbacks);
+            remote.fetch(&refspecs, Some(&mut pull_options), None)?;
+        }
 
+        let mut verifier = CommitVerifier::new(doc)?;
+        for name in names {
+            let incoming_ref = name.replacen("refs/heads/", "refs/incoming/", 1);
+            let tracking_ref = name.replacen("refs/heads/", "refs/origin/", 1);
+            let head = match doc.repository.find_reference(&incoming_ref) {
+                Ok(mut reference) => {
+                    let head = reference.target();
+                    reference.delete()?;
+                    head
+                }
+                Err(_) => None,
+            };
+            let head = match head {
+                Some(head) => head,
+                None => continue,
+            };
+            // refs of the hub that are not event-logs are never taken over
+            let log = match Log::from_reference(&tracking_ref) {
+                Some(log) => log,
+                None => continue,
+            };
+            match update_head(doc, &mut verifier, &log, head) {
+                Ok(HeadUpdate::Accepted(commits)) => {
+                    report.fetched.insert(tracking_ref, commits);
+                }
+                Ok(HeadUpdate::Stale) => {}
+                Ok(HeadUpdate::Forked(fork)) => {
+                    report.forked.insert(
+                        tracking_ref,
+                        format!("forked by its author, {} is kept in refs/forks", fork.forked),
+                    );
+                }
+                Err(reason) => {
+                    report.rejected.insert(tracking_ref, reason.to_string());
+                }
+            }
+        }
         Ok(())
     }
 
+    /// Pushes all the given logs with a single `push`, so they share one connection and
+    /// one pack negotiation.
+    fn push(
+        doc: &Document,
+        remote: &mut Remote,
+        logs: &[LogToPush],
+    ) -> Result<BTreeMap<String, PushStatus>, Error> {
+        let mut pushed = BTreeMap::new();
+        if logs.is_empty() {
+            return Ok(pushed);
+        }
 
+        let refspecs: Vec<String> = logs
+            .iter()
+            .map(|log| format!("{}:{}", log.reference, log.remote_ref))
+            .collect();
 
+        let mut rejections = HashMap::new();
+        let result = {
+            let mut callbacks = git2::RemoteCallbacks::new();
+            callbacks.credentials(|_url, username_from_url, _allowed_types| {
+                Self::get_credentials(&doc.repository, username_from_url)
+            });
+            callbacks.push_update_reference(|refname, status| {
+                if let Some(reason) = status {
+                    rejections.insert(refname.to_string(), reason.to_string());
+                }
+                Ok(())
+            });
+            let mut push_options = PushOptions::new();
+            push_options.remote_callbacks(callbacks);
+            remote.push(&refspecs, Some(&mut push_options))
+        };
 
-    fn get_credentials(identity: &Identity) -> Result<Cred, git2::Error> {
-        let public_key = identity.get_armored_public_key().unwrap();
-        let private_key = identity.get_armored_private_key().unwrap();
-
-        /*
-        let cred = Cred::ssh_key_from_memory(
-        "git",
-        None,
-            &private_key,
-            None
-        ).unwrap();
-         */
+        for log in logs {
+            let status = match (&result, rejections.get(&log.remote_ref)) {
+                (Err(e), _) => PushStatus::Rejected(e.message().to_string()),
+                (Ok(()), Some(reason)) => PushStatus::Rejected(reason.clone()),
+                (Ok(()), None) => {
+                    // keep the tracking log in line with what the remote now has
+                    let tracking_ref = log.reference.replacen("refs/local/", "refs/origin/", 1);
+                    doc.repository.reference(&tracking_ref, log.head, true, "sync: pushed")?;
+                    PushStatus::Pushed
+                }
+            };
+            pushed.insert(log.reference.clone(), status);
+        }
+        Ok(pushed)
+    }
 
-        // todo replace wiht ssh key from memory
-        let cred = Cred::ssh_key(
-            "git",
-            None,
-            std::path::Path::new("/home/parfab00/.ssh/id_rsa"),
-            None).unwrap();
+    /// True if `head` descends from `remote_head`, i.e. pushing it does not drop commits of the
+    /// remote log. The commits of `remote_head` were fetched before.
+    fn extends(repository: &Repository, head: Oid, remote_head: Oid) -> bool {
+        repository.graph_descendant_of(head, remote_head).unwrap_or(false)
+    }
 
-        Ok(cred)
+    fn ref_target(repository: &Repository, name: &str) -> Option<Oid> {
+        repository
+            .find_reference(name)
+            .ok()
+            .and_then(|reference| reference.target())
     }
 
+    /// Credentials for ssh remotes: the private key in `dcore.sshKey` of the git config of the
+    /// document if it is set, otherwise the keys of the ssh agent.
+    fn get_credentials(repository: &Repository, username_from_url: Option<&str>) -> Result<Cred, git2::Error> {
+        let username = username_from_url.unwrap_or("git");
+        match repository.config()?.get_path(SSH_KEY_CONFIG) {
+            Ok(private_key) => Cred::ssh_key(username, None, &private_key, None),
+            Err(_) => Cred::ssh_key_from_agent(username),
+        }
+    }
 }
+
 #[cfg(test)]
 mod tests {
 
-    use std::collections::HashMap;
-    use std::fs;
+    use std::path::{Path, PathBuf};
 
-    use fs_extra::dir::CopyOptions;
-    use std::path::PathBuf;
+    use git2::{Repository, Signature};
 
-    use lib0::any::Any;
-
-    use crate::document::DocumentNewOptions;
     use crate::Document;
-    use crate::sync_git::GitSync;
-
-    use crate::test_utils::{
-        create_test_env, create_test_env_with_new_gpg_key, create_test_env_with_sample_gpg_key,
-        create_test_env_with_test_gpg_key, get_test_key,
-    };
+    use crate::sync_git::{GitSync, LogToPush, PushStatus, SyncReport};
 
+    use crate::test_utils::{create_test_gpg_home, get_test_key, test_document_options};
 
+    fn open_device(gpg_home: &Path, directory: &Path) -> Document {
+        Document::new(test_document_options(gpg_home, directory)).unwrap()
+    }
 
     #[test]
-    fn sync_with_git() {
-        let doc_dir = "./.test/sync_git/sync_with_git/";
-        create_test_env_with_test_gpg_key(doc_dir.to_string());
-        let doc = Document::new(DocumentNewOptions {
-            directory: PathBuf::from(doc_dir),
-            identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
-            name: String::from("name"),
-        }).unwrap();
+    fn sync_between_devices_with_local_hub() {
+        let test_dir = PathBuf::from("./.test/sync_git/sync_between_devices_with_local_hub/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let hub_dir = test_dir.join("hub.git");
+        let device_a_dir = test_dir.join("device-a");
+        let device_b_dir = test_dir.join("device-b");
+        GitSync::create_hub(&hub_dir).unwrap();
+        let hub = hub_dir.to_str().unwrap().to_string();
+
+        // device a creates the document and shares it through the hub
+        let doc = open_device(&gpg_home, &device_a_dir);
+        doc.config_set_local_device("device-a").unwrap();
         let mut doc = doc
-            .init(&get_test_key().fingerprint, &get_test_key().public_key)
+            .init(&fingerprint, &get_test_key().public_key)
             .unwrap();
+        doc.config_set_remote(&hub).unwrap();
+        assert_eq!(doc.config_get_remote().unwrap(), GitSync::normalize_remote(&hub));
 
-        doc.config_set_remote(&"git@github.com:fuubi/gpgtest.git").unwrap();
+        let report = doc.sync().unwrap();
+        assert!(report.is_success());
+        let config_log = format!("refs/local/config/{}/device-a", fingerprint);
+        assert_eq!(report.pushed.get(&config_log), Some(&PushStatus::Pushed));
 
-        //let content = doc.resources.get("config").unwrap().get_content();
-        //assert_eq!(content, "..., just for debugging");
+        // device b clones the document, adds a resource and shares it
+        let doc = open_device(&gpg_home, &device_b_dir);
+        doc.config_set_local_device("device-b").unwrap();
+        doc.clone(&hub).unwrap();
 
-        let remote = doc.config_get_remote().unwrap();
-        assert_eq!(remote, "git@github.com:fuubi/gpgtest.git");
+        let mut doc = open_device(&gpg_home, &device_b_dir);
+        doc.load().unwrap();
+        doc.add_resource("test".to_string()).unwrap();
+        doc.update_resource_with_key_value("test", "entry", "1234").unwrap();
+        let report = doc.sync().unwrap();
+        assert!(report.is_success());
+        // the config log registers device b, the test log holds the new resource
+        assert_eq!(report.pushed.len(), 2);
+
+        let hub_repository = Repository::open_bare(&hub_dir).unwrap();
+        let test_log = format!("refs/heads/test/{}/device-b", fingerprint);
+        assert!(hub_repository.find_reference(&test_log).is_ok());
+
+        // device a gets the new resource with the next sync
+        let mut doc = open_device(&gpg_home, &device_a_dir);
+        doc.load().unwrap();
+        let report = doc.sync().unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.pushed.get(&config_log), Some(&PushStatus::UpToDate));
+        let fetched_log = format!("refs/origin/test/{}/device-b", fingerprint);
+        assert_eq!(report.fetched.get(&fetched_log), Some(&2));
+
+        let mut doc = open_device(&gpg_home, &device_a_dir);
+        doc.load().unwrap();
+        let content = doc.resources.get("test").unwrap().get_content();
+        assert_eq!(content, "{entry: 1234}");
+    }
 
-        GitSync::sync(doc).unwrap();
+    #[test]
+    fn two_machines_with_the_same_device_name() {
+        let test_dir = PathBuf::from("./.test/sync_git/two_machines_with_the_same_device_name/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let hub_dir = test_dir.join("hub.git");
+        let laptop_dir = test_dir.join("laptop");
+        let desktop_dir = test_dir.join("desktop");
+        GitSync::create_hub(&hub_dir).unwrap();
+        let hub = hub_dir.to_str().unwrap().to_string();
+
+        // new documents get a generated device name
+        let doc = open_device(&gpg_home, &laptop_dir);
+        assert!(doc.config_get_local_device().unwrap().starts_with("device-"));
+        let other = open_device(&gpg_home, &desktop_dir);
+        assert_ne!(doc.config_get_local_device().unwrap(), other.config_get_local_device().unwrap());
+        doc.config_set_local_device("laptop").unwrap();
+        let mut laptop = doc.init(&fingerprint, &get_test_key().public_key).unwrap();
+        laptop.config_set_remote(&hub).unwrap();
+        assert!(laptop.sync().unwrap().is_success());
+
+        // a clone may not take the name of a registered device
+        let doc = open_device(&gpg_home, &desktop_dir);
+        doc.config_set_local_device("laptop").unwrap();
+        assert!(doc.clone(&hub).is_err());
+        let doc = open_device(&gpg_home, &desktop_dir);
+        doc.config_set_local_device("desktop").unwrap();
+        doc.clone(&hub).unwrap();
+        let mut desktop = open_device(&gpg_home, &desktop_dir);
+        desktop.load().unwrap();
+        assert!(desktop.config_get_devices(&fingerprint).unwrap().contains_key("desktop"));
+
+        // both machines write to the same log if the name is changed afterwards
+        desktop.config_set_local_device("laptop").unwrap();
+        laptop.add_resource("test".to_string()).unwrap();
+        assert!(laptop.sync().unwrap().is_success());
+        desktop.add_resource("test".to_string()).unwrap();
+        let report = desktop.sync().unwrap();
+        assert!(!report.is_success());
+        let test_log = format!("refs/local/test/{}/laptop", fingerprint);
+        assert!(matches!(
+            report.pushed.get(&test_log),
+            Some(PushStatus::Rejected(reason)) if reason.contains("config-set-device-name")
+        ));
+
+        // the log of the laptop was not overwritten
+        let hub_repository = Repository::open_bare(&hub_dir).unwrap();
+        let remote_head = hub_repository.refname_to_id(&format!("refs/heads/test/{}/laptop", fingerprint)).unwrap();
+        let laptop_head = laptop.repository.refname_to_id(&test_log).unwrap();
+        assert_eq!(remote_head, laptop_head);
+    }
 
-        let doc = Document::new(DocumentNewOptions {
-            directory: PathBuf::from(doc_dir),
-            identity_fingerprint: "A84E5D451E9E75B4791556896F45F34A926FBB70".to_string(),
-            name: String::from("name"),
-        }).unwrap();
-        let mut doc = doc
-            .init(&get_test_key().fingerprint, &get_test_key().public_key)
+    #[test]
+    fn push_only_owned_logs() {
+        let test_dir = PathBuf::from("./.test/sync_git/push_only_owned_logs/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let doc = open_device(&gpg_home, &test_dir.join("device-a"));
+        doc.config_set_local_device("device-a").unwrap();
+        let mut doc = doc.init(&fingerprint, &get_test_key().public_key).unwrap();
+        doc.add_resource("test".to_string()).unwrap();
+
+        // copies of logs of another device and another identity
+        let own_log = format!("refs/local/test/{}/device-a", fingerprint);
+        let head = doc.repository.refname_to_id(&own_log).unwrap();
+        let other_device = format!("refs/local/test/{}/device-b", fingerprint);
+        let other_identity = "refs/local/test/0000000000000000000000000000000000000000/device-a";
+        doc.repository.reference(&other_device, head, true, "test").unwrap();
+        doc.repository.reference(other_identity, head, true, "test").unwrap();
+
+        let mut owned: Vec<String> = GitSync::owned_logs(&doc).unwrap().into_iter().map(|(name, _)| name).collect();
+        owned.sort();
+        assert_eq!(owned, vec![format!("refs/local/config/{}/device-a", fingerprint), own_log]);
+
+        let hub_dir = test_dir.join("hub.git");
+        GitSync::create_hub(&hub_dir).unwrap();
+        doc.config_set_remote(hub_dir.to_str().unwrap()).unwrap();
+        let report = doc.sync().unwrap();
+        assert!(report.is_success());
+        assert_eq!(report.pushed.len(), 2);
+        let hub_repository = Repository::open_bare(&hub_dir).unwrap();
+        assert!(hub_repository.find_reference(&format!("refs/heads/test/{}/device-a", fingerprint)).is_ok());
+        assert!(hub_repository.find_reference(&format!("refs/heads/test/{}/device-b", fingerprint)).is_err());
+        assert!(hub_repository
+            .find_reference("refs/heads/test/0000000000000000000000000000000000000000/device-a")
+            .is_err());
+    }
+
+    #[test]
+    fn fetch_only_changed_logs() {
+        let test_dir = PathBuf::from("./.test/sync_git/fetch_only_changed_logs/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let hub_dir = test_dir.join("hub.git");
+        GitSync::create_hub(&hub_dir).unwrap();
+        let hub = hub_dir.to_str().unwrap().to_string();
+
+        let doc = open_device(&gpg_home, &test_dir.join("device-a"));
+        doc.config_set_local_device("device-a").unwrap();
+        let mut doc_a = doc.init(&fingerprint, &get_test_key().public_key).unwrap();
+        doc_a.config_set_remote(&hub).unwrap();
+        assert!(doc_a.sync().unwrap().is_success());
+
+        let doc = open_device(&gpg_home, &test_dir.join("device-b"));
+        doc.config_set_local_device("device-b").unwrap();
+        doc.clone(&hub).unwrap();
+        let mut doc_b = open_device(&gpg_home, &test_dir.join("device-b"));
+        doc_b.load().unwrap();
+        doc_b.add_resource("test".to_string()).unwrap();
+        doc_b.add_resource("notes".to_string()).unwrap();
+        assert!(doc_b.sync().unwrap().is_success());
+
+        // device a fetches the config, test and notes logs of device b
+        let report = doc_a.sync().unwrap();
+        assert!(report.is_success());
+        let fetched: Vec<&String> = report.fetched.keys().collect();
+        assert_eq!(
+            fetched,
+            vec![
+                &format!("refs/origin/config/{}/device-b", fingerprint),
+                &format!("refs/origin/notes/{}/device-b", fingerprint),
+                &format!("refs/origin/test/{}/device-b", fingerprint),
+            ]
+        );
+        assert_eq!(doc_a.repository.references_glob("refs/incoming/*").unwrap().count(), 0);
+
+        // nothing changed, nothing is fetched
+        let report = doc_a.sync().unwrap();
+        assert!(report.fetched.is_empty());
+
+        // only the log that changed is fetched
+        doc_b.update_resource_with_key_value("test", "entry", "1234").unwrap();
+        assert!(doc_b.sync().unwrap().is_success());
+        let report = doc_a.sync().unwrap();
+        assert_eq!(report.fetched.len(), 1);
+        assert_eq!(report.fetched.get(&format!("refs/origin/test/{}/device-b", fingerprint)), Some(&1));
+    }
+
+    #[test]
+    fn push_several_logs_at_once() {
+        let test_dir = PathBuf::from("./.test/sync_git/push_several_logs_at_once/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let hub_dir = test_dir.join("hub.git");
+        GitSync::create_hub(&hub_dir).unwrap();
+
+        let doc = open_device(&gpg_home, &test_dir.join("device-a"));
+        doc.config_set_local_device("device-a").unwrap();
+        let mut doc = doc.init(&fingerprint, &get_test_key().public_key).unwrap();
+        doc.add_resource("test".to_string()).unwrap();
+        doc.add_resource("notes".to_string()).unwrap();
+
+        let hub = GitSync::normalize_remote(hub_dir.to_str().unwrap());
+        let mut remote = doc.repository.remote_anonymous(&hub).unwrap();
+        let logs: Vec<LogToPush> = GitSync::owned_logs(&doc)
+            .unwrap()
+            .into_iter()
+            .map(|(reference, head)| LogToPush {
+                remote_ref: reference.replacen("refs/local/", "refs/heads/", 1),
+                reference,
+                head,
+            })
+            .collect();
+        assert_eq!(logs.len(), 3);
+        let pushed = GitSync::push(&doc, &mut remote, &logs).unwrap();
+
+        // one push with a refspec per log updates all of them on the hub
+        let hub_repository = Repository::open_bare(&hub_dir).unwrap();
+        assert_eq!(pushed.len(), 3);
+        for log in &logs {
+            assert_eq!(pushed.get(&log.reference), Some(&PushStatus::Pushed));
+            assert_eq!(hub_repository.refname_to_id(&log.remote_ref).unwrap(), log.head);
+            let tracking_ref = log.reference.replacen("refs/local/", "refs/origin/", 1);
+            assert_eq!(doc.repository.refname_to_id(&tracking_ref).unwrap(), log.head);
+        }
+    }
+
+    #[test]
+    fn reject_unsigned_commits_of_the_hub() {
+        let test_dir = PathBuf::from("./.test/sync_git/reject_unsigned_commits_of_the_hub/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+        let hub_dir = test_dir.join("hub.git");
+        GitSync::create_hub(&hub_dir).unwrap();
+        let hub = hub_dir.to_str().unwrap().to_string();
+
+        let doc = open_device(&gpg_home, &test_dir.join("device-a"));
+        doc.config_set_local_device("device-a").unwrap();
+        let mut doc_a = doc.init(&fingerprint, &get_test_key().public_key).unwrap();
+        doc_a.config_set_remote(&hub).unwrap();
+        assert!(doc_a.sync().unwrap().is_success());
+
+        let doc = open_device(&gpg_home, &test_dir.join("device-b"));
+        doc.config_set_local_device("device-b").unwrap();
+        doc.clone(&hub).unwrap();
+        let mut doc_b = open_device(&gpg_home, &test_dir.join("device-b"));
+        doc_b.load().unwrap();
+        let tracking_ref = format!("refs/origin/config/{}/device-a", fingerprint);
+        let accepted = doc_b.repository.refname_to_id(&tracking_ref).unwrap();
+
+        // someone with write access to the hub appends an unsigned commit to the log of device a,
+        // and adds a ref that is not an event-log
+        let hub_repository = Repository::open_bare(&hub_dir).unwrap();
+        let hub_log = format!("refs/heads/config/{}/device-a", fingerprint);
+        let parent = hub_repository.find_commit(hub_repository.refname_to_id(&hub_log).unwrap()).unwrap();
+        let signature = Signature::now("mallory", "mallory@example.com").unwrap();
+        let forged = hub_repository
+            .commit(Some(&hub_log), &signature, &signature, "forged", &parent.tree().unwrap(), &[&parent])
             .unwrap();
-        doc.load().unwrap();
+        hub_repository.reference("refs/heads/other", forged, true, "test").unwrap();
+
+        let report = doc_b.sync().unwrap();
+        assert!(!report.is_success());
+        assert!(!report.fetched.contains_key(&tracking_ref));
+        assert!(report.forked.is_empty());
+        assert!(report.rejected.contains_key(&tracking_ref));
+        assert_eq!(doc_b.repository.refname_to_id(&tracking_ref).unwrap(), accepted);
+        assert!(doc_b.repository.find_reference("refs/origin/other").is_err());
+        assert_eq!(doc_b.repository.references_glob("refs/incoming/*").unwrap().count(), 0);
+    }
+
+    #[test]
+    fn sync_report_with_rejected_log() {
+        let mut report = SyncReport::default();
+        report.pushed.insert("refs/local/config/FP/device-0".to_string(), PushStatus::Pushed);
+        report.pushed.insert("refs/local/test/FP/device-0".to_string(), PushStatus::UpToDate);
+        report.fetched.insert("refs/origin/config/FP/device-1".to_string(), 2);
+        assert!(report.is_success());
+
+        report.pushed.insert(
+            "refs/local/notes/FP/device-0".to_string(),
+            PushStatus::Rejected("non-fast-forward".to_string()),
+        );
+        assert!(!report.is_success());
+        assert_eq!(
+            report.to_string(),
+            "Pushed logs:\n\
+             \t- refs/local/config/FP/device-0: pushed\n\
+             \t- refs/local/notes/FP/device-0: rejected (non-fast-forward)\n\
+             \t- refs/local/test/FP/device-0: up to date\n\
+             Fetched logs:\n\
+             \t- refs/origin/config/FP/device-1: 2 new commit(s)\n"
+        );
     }
 
+    #[test]
+    fn sync_report_with_forked_log() {
+        let mut report = SyncReport::default();
+        report.forked.insert(
+            "refs/origin/config/FP/device-1".to_string(),
+            "forked by its author, 1234 is kept in refs/forks".to_string(),
+        );
+        assert!(!report.is_success());
+        assert!(report
+            .to_string()
+            .ends_with("Forked logs:\n\t- refs/origin/config/FP/device-1: forked by its author, 1234 is kept in refs/forks\n"));
+    }
+
+    #[test]
+    fn sync_report_with_rejected_remote_log() {
+        let mut report = SyncReport::default();
+        report.fetched.insert("refs/origin/config/FP/device-1".to_string(), 2);
+        report.rejected.insert(
+            "refs/origin/test/FP/device-1".to_string(),
+            "commit 1234 is not signed".to_string(),
+        );
+        assert!(!report.is_success());
+        assert!(report.forked.is_empty());
+        assert!(report
+            .to_string()
+            .ends_with("Rejected logs:\n\t- refs/origin/test/FP/device-1: commit 1234 is not signed\n"));
+        assert!(!report.to_string().contains("Forked logs:"));
+    }
 }
@@ -1,246 +1,1180 @@
-use futures::{channel::mpsc, prelude::*};
-use libp2p::{identity, mplex, noise, ping, tcp, yamux, Multiaddr, PeerId, Swarm};
-//use quickcheck::*;
-use crate::sync_libp2p::tcp::TcpTransport;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::{fs, io, iter};
+
+use async_trait::async_trait;
+use futures::channel::mpsc;
+use futures::prelude::*;
+use git2::Oid;
 use libp2p::core::muxing::StreamMuxerBox;
+use libp2p::core::upgrade::{read_length_prefixed, write_length_prefixed, ProtocolName};
 use libp2p::core::{transport, upgrade};
-use libp2p::swarm::{DummyBehaviour, KeepAlive, SwarmEvent};
-use libp2p::tcp::GenTcpConfig;
-use libp2p::Transport;
-use rand::prelude::*;
-use std::{num::NonZeroU8, time::Duration};
-#[test]
-fn ping_pong() {
-    fn prop(count: NonZeroU8, muxer: MuxerChoice) {
-        let cfg = ping::Config::new()
-            .with_keep_alive(true)
-            .with_interval(Duration::from_millis(10));
-
-        let (peer1_id, trans) = mk_transport(muxer);
-        let mut swarm1 = Swarm::new(trans, ping::Behaviour::new(cfg.clone()), peer1_id.clone());
-
-        let (peer2_id, trans) = mk_transport(muxer);
-        let mut swarm2 = Swarm::new(trans, ping::Behaviour::new(cfg), peer2_id.clone());
+use libp2p::gossipsub::{
+    Gossipsub, GossipsubConfig, GossipsubEvent, GossipsubMessage, IdentTopic, MessageAuthenticity,
+    PublishError,
+};
+use libp2p::identify::{Identify, IdentifyConfig, IdentifyEvent};
+use libp2p::mdns::{Mdns, MdnsConfig, MdnsEvent};
+use libp2p::request_response::{
+    ProtocolSupport, RequestResponse, RequestResponseCodec, RequestResponseConfig,
+    RequestResponseEvent, RequestResponseMessage,
+};
+use libp2p::swarm::behaviour::toggle::Toggle;
+use libp2p::swarm::SwarmEvent;
+use libp2p::tcp::{GenTcpConfig, TcpTransport};
+use libp2p::{identity, noise, yamux, Multiaddr, NetworkBehaviour, PeerId, Swarm, Transport};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use yrs::updates::decoder::Decode;
+use yrs::updates::encoder::Encode;
+use yrs::StateVector;
 
-        let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
+use crate::document_utils::{CommitVerifier, DocumentUtils};
+use crate::errors::Error;
+use crate::event_log_store::Log;
+use crate::hooks::CommittedUpdate;
+use crate::Document;
 
-        let pid1 = peer1_id.clone();
-        let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
-        swarm1.listen_on(addr).unwrap();
+/// Upper bound for a single message, a response carries all the commits the peer is missing.
+const MAX_MESSAGE_SIZE: usize = 64 * 1024 * 1024;
 
-        let mut count1 = count.get();
-        let mut count2 = count.get();
+const IDENTIFY_PROTOCOL_VERSION: &str = "/dcore/1.0.0";
 
-        let peer1 = async move {
-            loop {
-                match swarm1.select_next_some().await {
-                    SwarmEvent::NewListenAddr { address, .. } => tx.send(address).await.unwrap(),
-                    SwarmEvent::Behaviour(ping::Event {
-                        peer,
-                        result: Ok(ping::Success::Ping { rtt }),
-                    }) => {
-                        count1 -= 1;
-                        if count1 == 0 {
-                            return (pid1.clone(), peer, rtt);
-                        }
+#[derive(Debug, Clone)]
+pub struct SyncProtocol;
+
+impl ProtocolName for SyncProtocol {
+    fn protocol_name(&self) -> &[u8] {
+        b"/dcore/sync/1.0.0"
+    }
+}
+
+/// Asks a peer for the updates we are missing.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncRequest {
+    /// The yrs state vector (v1 encoded) of every resource we have
+    pub state_vectors: BTreeMap<String, Vec<u8>>,
+    /// The heads of the logs we have, `refs/heads/{resource}/{fingerprint}/{device}` -> oid
+    pub heads: BTreeMap<String, String>,
+}
+
+/// The signed update commits the requesting peer is missing, as a git pack,
+/// and the heads of the logs they belong to.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SyncResponse {
+    pub heads: BTreeMap<String, String>,
+    pub pack: Vec<u8>,
+}
+
+/// A locally committed update, published to the peers subscribed to the document's topic.
+///
+/// Besides the yrs update it carries the signed commit it was persisted with (as a git pack),
+/// so the receiving peers can verify it and store it in their copy of the log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LiveUpdate {
+    /// `refs/heads/{resource}/{fingerprint}/{device}`
+    pub log: String,
+    pub head: String,
+    /// The bytes `Resource::add_local_update` returned
+    pub update: Vec<u8>,
+    pub pack: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SyncCodec;
+
+#[async_trait]
+impl RequestResponseCodec for SyncCodec {
+    type Protocol = SyncProtocol;
+    type Request = SyncRequest;
+    type Response = SyncResponse;
+
+    async fn read_request<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncRequest>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(&read_length_prefixed(io, MAX_MESSAGE_SIZE).await?)
+    }
+
+    async fn read_response<T>(&mut self, _: &SyncProtocol, io: &mut T) -> io::Result<SyncResponse>
+    where
+        T: AsyncRead + Unpin + Send,
+    {
+        decode(&read_length_prefixed(io, MAX_MESSAGE_SIZE).await?)
+    }
+
+    async fn write_request<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        request: SyncRequest,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&request)?).await?;
+        io.close().await
+    }
+
+    async fn write_response<T>(
+        &mut self,
+        _: &SyncProtocol,
+        io: &mut T,
+        response: SyncResponse,
+    ) -> io::Result<()>
+    where
+        T: AsyncWrite + Unpin + Send,
+    {
+        write_length_prefixed(io, encode(&response)?).await?;
+        io.close().await
+    }
+}
+
+fn encode<T: Serialize>(message: &T) -> io::Result<Vec<u8>> {
+    bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> io::Result<T> {
+    bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "DcoreEvent")]
+pub struct DcoreBehaviour {
+    pub sync: RequestResponse<SyncCodec>,
+    /// Tells the peers which document we have, see `Node::agent_version`
+    pub identify: Identify,
+    pub mdns: Toggle<Mdns>,
+    /// Live updates, one topic per document, see `Node::publish_committed`
+    pub gossipsub: Gossipsub,
+}
+
+#[derive(Debug)]
+pub enum DcoreEvent {
+    Sync(RequestResponseEvent<SyncRequest, SyncResponse>),
+    Identify(IdentifyEvent),
+    Mdns(MdnsEvent),
+    Gossipsub(GossipsubEvent),
+}
+
+impl From<GossipsubEvent> for DcoreEvent {
+    fn from(event: GossipsubEvent) -> Self {
+        DcoreEvent::Gossipsub(event)
+    }
+}
+
+impl From<IdentifyEvent> for DcoreEvent {
+    fn from(event: IdentifyEvent) -> Self {
+        DcoreEvent::Identify(event)
+    }
+}
+
+impl From<MdnsEvent> for DcoreEvent {
+    fn from(event: MdnsEvent) -> Self {
+        DcoreEvent::Mdns(event)
+    }
+}
+
+impl From<RequestResponseEvent<SyncRequest, SyncResponse>> for DcoreEvent {
+    fn from(event: RequestResponseEvent<SyncRequest, SyncResponse>) -> Self {
+        DcoreEvent::Sync(event)
+    }
+}
+
+/// What happened on a node, see `Node::next_event`.
+#[derive(Debug)]
+pub enum NodeEvent {
+    Listening(Multiaddr),
+    /// A peer sent us the commits we were missing. `merged` holds the new commits per log,
+    /// `rejected` the logs that failed the signature check with the reason.
+    Synced {
+        peer: PeerId,
+        merged: BTreeMap<String, usize>,
+        rejected: BTreeMap<String, String>,
+    },
+    SyncFailed {
+        peer: PeerId,
+        reason: String,
+    },
+    /// A peer published an update which was verified, stored and applied to the resource.
+    Updated {
+        peer: PeerId,
+        resource: String,
+        log: String,
+    },
+    UpdateRejected {
+        peer: PeerId,
+        log: String,
+        reason: String,
+    },
+    /// A peer subscribed to the live updates of the document.
+    PeerSubscribed(PeerId),
+    /// A peer that is not registered for a member device connected and was disconnected.
+    PeerRejected(PeerId),
+}
+
+/// A libp2p node that keeps a document in sync with the peers it is connected to.
+///
+/// When a connection is established, both peers send each other the state vectors of their
+/// resources and the heads of their logs. Each side answers with the signed update commits of
+/// the resources the other one is behind on. The commits are verified and stored in
+/// `refs/origin/*`, exactly like logs fetched from a git hub, and the document is reloaded.
+///
+/// With mDNS enabled, the node also dials the peers it finds on the local network. Those are
+/// only synced with once the identify protocol showed they have the same document, otherwise
+/// the connection is closed again.
+///
+/// Between full syncs, the updates committed to the document are pushed out with gossipsub, see
+/// `Node::publish_committed`.
+///
+/// Only peers registered for a member device in the `config` resource
+/// (`Document::config_add_peer`) are accepted, all other connections are closed right after the
+/// handshake. A document without a config yet has nothing to share, it accepts every peer so it
+/// can get its first copy.
+pub struct Node {
+    pub document: Document,
+    pub swarm: Swarm<DcoreBehaviour>,
+    /// Peers found with mDNS
+    mdns_peers: HashSet<PeerId>,
+    /// The updates committed to the document that are not published yet
+    committed: mpsc::UnboundedReceiver<CommittedUpdate>,
+}
+
+impl Node {
+    pub fn new(document: Document, keypair: identity::Keypair) -> Result<Node, Error> {
+        Self::build(document, keypair, None)
+    }
+
+    /// Like `new`, but also discovers the peers on the local network that have the same document.
+    pub fn with_mdns(document: Document, keypair: identity::Keypair) -> Result<Node, Error> {
+        let mdns = Mdns::new(MdnsConfig::default())?;
+        Self::build(document, keypair, Some(mdns))
+    }
+
+    fn build(mut document: Document, keypair: identity::Keypair, mdns: Option<Mdns>) -> Result<Node, Error> {
+        let (sender, committed) = mpsc::unbounded();
+        document.register_commit_hook(move |update| {
+            let _ = sender.unbounded_send(update.clone());
+        });
+        let peer_id = keypair.public().to_peer_id();
+        let transport = build_transport(&keypair)?;
+        let identify = IdentifyConfig::new(IDENTIFY_PROTOCOL_VERSION.to_string(), keypair.public())
+            .with_agent_version(Self::agent_version(&document));
+        let behaviour = DcoreBehaviour {
+            sync: RequestResponse::new(
+                SyncCodec,
+                iter::once((SyncProtocol, ProtocolSupport::Full)),
+                RequestResponseConfig::default(),
+            ),
+            identify: Identify::new(identify),
+            mdns: Toggle::from(mdns),
+            gossipsub: Gossipsub::new(
+                MessageAuthenticity::Signed(keypair.clone()),
+                GossipsubConfig::default(),
+            )
+            .map_err(|e| Error::Other(e.to_string()))?,
+        };
+        let swarm = Swarm::new(transport, behaviour, peer_id);
+        let mut node = Node {
+            document,
+            swarm,
+            mdns_peers: HashSet::new(),
+            committed,
+        };
+        node.subscribe_updates()?;
+        Ok(node)
+    }
+
+    /// The gossipsub topic of the document's live updates, None as long as it has no id.
+    pub fn updates_topic(document: &Document) -> Option<IdentTopic> {
+        document
+            .get_id()
+            .map(|id| IdentTopic::new(format!("/dcore/updates/{}", id)))
+    }
+
+    /// Subscribes to the live updates once the document has an id, e.g. after the first sync.
+    fn subscribe_updates(&mut self) -> Result<(), Error> {
+        if let Some(topic) = Self::updates_topic(&self.document) {
+            self.swarm
+                .behaviour_mut()
+                .gossipsub
+                .subscribe(&topic)
+                .map_err(|e| Error::Other(format!("{:?}", e)))?;
+        }
+        Ok(())
+    }
+
+    /// Publishes the updates committed to the document since the last call, whichever method of
+    /// the document committed them. Called by `next_event` before it waits for the swarm.
+    ///
+    /// Peers that are not subscribed (or not connected) get the updates with the next sync.
+    fn publish_committed(&mut self) -> Result<(), Error> {
+        while let Ok(Some(committed)) = self.committed.try_next() {
+            self.publish_update(&committed)?;
+        }
+        Ok(())
+    }
+
+    fn publish_update(&mut self, committed: &CommittedUpdate) -> Result<(), Error> {
+        let topic = match Self::updates_topic(&self.document) {
+            Some(topic) => topic,
+            None => return Ok(()),
+        };
+        let log = format!(
+            "{}{}",
+            committed.resource,
+            DocumentUtils::own_logs_suffix(&self.document)?
+        );
+        let head = self
+            .document
+            .repository
+            .find_commit(Oid::from_str(&committed.head)?)?;
+        let name = format!("refs/heads/{}", log);
+        let mut heads = BTreeMap::new();
+        heads.insert(name.clone(), head.id());
+        let (pack, _) = DocumentUtils::pack_logs(&self.document, &heads, &head.parent_ids().collect::<Vec<Oid>>())?;
+
+        let message = LiveUpdate {
+            log: name,
+            head: head.id().to_string(),
+            update: committed.update.clone(),
+            pack,
+        };
+        match self.swarm.behaviour_mut().gossipsub.publish(topic, encode(&message)?) {
+            Ok(_) | Err(PublishError::InsufficientPeers) => Ok(()),
+            Err(e) => Err(Error::Other(format!("Could not publish the update: {:?}", e))),
+        }
+    }
+
+    /// The keypair of this device, generated on first use and stored next to the document.
+    /// Its peer id is what gets registered with `Document::config_add_peer`.
+    pub fn load_or_create_keypair(document: &Document) -> Result<identity::Keypair, Error> {
+        let path = document.repository.path().join("dcore-node-key");
+        if path.exists() {
+            let mut bytes = fs::read(&path)?;
+            let keypair = identity::ed25519::Keypair::decode(&mut bytes)
+                .map_err(|e| Error::Other(format!("Invalid node key: {}", e)))?;
+            return Ok(identity::Keypair::Ed25519(keypair));
+        }
+        let keypair = identity::ed25519::Keypair::generate();
+        fs::write(&path, keypair.encode())?;
+        Ok(identity::Keypair::Ed25519(keypair))
+    }
+
+    /// Whether the peer may connect, see the connection gate in the `Node` docs.
+    pub fn is_member_peer(&self, peer: &PeerId) -> Result<bool, Error> {
+        if !self.document.resources.contains_key("config") {
+            return Ok(true);
+        }
+        Ok(self.document.config_get_peers()?.contains_key(&peer.to_base58()))
+    }
+
+    /// What the node advertises with the identify protocol: `dcore/{document id}`,
+    /// or just `dcore` for a document that has no id yet.
+    pub fn agent_version(document: &Document) -> String {
+        match document.get_id() {
+            Some(id) => format!("dcore/{}", id),
+            None => "dcore".to_string(),
+        }
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        *self.swarm.local_peer_id()
+    }
+
+    pub fn listen_on(&mut self, address: Multiaddr) -> Result<(), Error> {
+        self.swarm
+            .listen_on(address)
+            .map_err(|e| Error::Other(e.to_string()))?;
+        Ok(())
+    }
+
+    pub fn dial(&mut self, address: Multiaddr) -> Result<(), Error> {
+        self.swarm.dial(address).map_err(|e| Error::Other(e.to_string()))
+    }
+
+    /// Asks the peer for all the updates we are missing.
+    pub fn sync_with(&mut self, peer: &PeerId) -> Result<(), Error> {
+        let request = Self::sync_request(&self.document)?;
+        self.swarm.behaviour_mut().sync.send_request(peer, request);
+        Ok(())
+    }
+
+    /// Drives the swarm until something happens the caller should know about.
+    pub async fn next_event(&mut self) -> Result<NodeEvent, Error> {
+        loop {
+            self.publish_committed()?;
+            match self.swarm.select_next_some().await {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    return Ok(NodeEvent::Listening(address));
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } => {
+                    if !self.is_member_peer(&peer_id)? {
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                        return Ok(NodeEvent::PeerRejected(peer_id));
                     }
-                    SwarmEvent::Behaviour(ping::Event { result: Err(e), .. }) => {
-                        panic!("Ping failure: {:?}", e)
+                    // peers found with mDNS have to show the same document first
+                    if num_established.get() == 1 && !self.mdns_peers.contains(&peer_id) {
+                        self.sync_with(&peer_id)?;
                     }
-                    _ => {}
                 }
+                SwarmEvent::Behaviour(DcoreEvent::Mdns(event)) => self.handle_mdns_event(event),
+                SwarmEvent::Behaviour(DcoreEvent::Identify(IdentifyEvent::Received { peer_id, info })) => {
+                    if !self.mdns_peers.contains(&peer_id) {
+                        continue;
+                    }
+                    if info.agent_version == Self::agent_version(&self.document) {
+                        self.sync_with(&peer_id)?;
+                    } else {
+                        let _ = self.swarm.disconnect_peer_id(peer_id);
+                    }
+                }
+                SwarmEvent::Behaviour(DcoreEvent::Sync(event)) => {
+                    if let Some(event) = self.handle_sync_event(event)? {
+                        return Ok(event);
+                    }
+                }
+                SwarmEvent::Behaviour(DcoreEvent::Gossipsub(GossipsubEvent::Message {
+                    propagation_source,
+                    message,
+                    ..
+                })) => {
+                    if let Some(event) = self.handle_live_update(propagation_source, message)? {
+                        return Ok(event);
+                    }
+                }
+                SwarmEvent::Behaviour(DcoreEvent::Gossipsub(GossipsubEvent::Subscribed { peer_id, .. })) => {
+                    return Ok(NodeEvent::PeerSubscribed(peer_id));
+                }
+                _ => {}
             }
-        };
-
-        let pid2 = peer2_id.clone();
-        let peer2 = async move {
-            swarm2.dial(rx.next().await.unwrap()).unwrap();
+        }
+    }
 
-            loop {
-                match swarm2.select_next_some().await {
-                    SwarmEvent::Behaviour(ping::Event {
-                        peer,
-                        result: Ok(ping::Success::Ping { rtt }),
-                    }) => {
-                        count2 -= 1;
-                        if count2 == 0 {
-                            return (pid2.clone(), peer, rtt);
-                        }
-                    }
-                    SwarmEvent::Behaviour(ping::Event { result: Err(e), .. }) => {
-                        panic!("Ping failure: {:?}", e)
+    fn handle_mdns_event(&mut self, event: MdnsEvent) {
+        match event {
+            MdnsEvent::Discovered(peers) => {
+                // a peer is announced once per address, one dial is enough
+                let mut addresses = HashMap::new();
+                for (peer, address) in peers {
+                    addresses.entry(peer).or_insert(address);
+                }
+                for (peer, address) in addresses {
+                    if self.mdns_peers.insert(peer) && !self.swarm.is_connected(&peer) {
+                        // an unreachable peer is announced again once its record expired
+                        let _ = self.swarm.dial(address);
                     }
-                    _ => {}
                 }
             }
+            MdnsEvent::Expired(peers) => {
+                for (peer, _) in peers {
+                    self.mdns_peers.remove(&peer);
+                }
+            }
+        }
+    }
+
+    /// Verifies and stores the commit of a live update, then applies the update to the resource.
+    /// If we miss earlier commits of the log, we sync with the peer instead.
+    fn handle_live_update(
+        &mut self,
+        peer: PeerId,
+        message: GossipsubMessage,
+    ) -> Result<Option<NodeEvent>, Error> {
+        if !self.is_member_peer(&peer)? {
+            return Ok(None);
+        }
+        // a message we can not read is dropped, the next sync brings what we missed
+        let live_update: LiveUpdate = match decode(&message.data) {
+            Ok(live_update) => live_update,
+            Err(_) => return Ok(None),
+        };
+        let log = live_update.log;
+        let head = match Oid::from_str(&live_update.head) {
+            Ok(head) => head,
+            Err(_) => return Ok(None),
         };
+        let own_logs_suffix = DocumentUtils::own_logs_suffix(&self.document)?;
+        if log.ends_with(&own_logs_suffix) || self.document.repository.find_commit(head).is_ok() {
+            return Ok(None);
+        }
+
+        DocumentUtils::write_pack(&self.document, &live_update.pack)?;
+        let repository = &self.document.repository;
+        let parents_known = match repository.find_commit(head) {
+            Ok(commit) => commit
+                .parent_ids()
+                .all(|parent| repository.find_commit(parent).is_ok()),
+            Err(_) => false,
+        };
+        if !parents_known {
+            self.sync_with(&peer)?;
+            return Ok(None);
+        }
+
+        let verified = if DocumentUtils::read_update(&self.document, head)? != live_update.update {
+            Err(Error::DcoreError(
+                "The update does not match the one in the commit.".to_string(),
+            ))
+        } else {
+            CommitVerifier::new(&self.document)
+                .and_then(|mut verifier| DocumentUtils::merge_remote_log(&self.document, &mut verifier, &log, head))
+        };
+        if let Err(reason) = verified {
+            return Ok(Some(NodeEvent::UpdateRejected {
+                peer,
+                log,
+                reason: reason.to_string(),
+            }));
+        }
 
-        let result = future::select(Box::pin(peer1), Box::pin(peer2));
-        let ((p1, p2, rtt), _) = async_std::task::block_on(result).factor_first();
-        assert!(p1 == peer1_id && p2 == peer2_id || p1 == peer2_id && p2 == peer1_id);
-        assert!(rtt < Duration::from_millis(50));
+        let resource_name = log.split('/').nth(2).unwrap_or_default().to_string();
+        // validated, indexed and reported to the hooks like updates of a sync
+        match Log::from_reference(&log.replacen("refs/heads/", "refs/origin/", 1)) {
+            Some(origin_log) => self.document.apply_remote_update(&origin_log, &live_update.update)?,
+            None => self.document.load()?,
+        }
+        Ok(Some(NodeEvent::Updated {
+            peer,
+            resource: resource_name,
+            log,
+        }))
     }
 
-    //QuickCheck::new().tests(10).quickcheck(prop as fn(_, _))
+    fn handle_sync_event(
+        &mut self,
+        event: RequestResponseEvent<SyncRequest, SyncResponse>,
+    ) -> Result<Option<NodeEvent>, Error> {
+        match event {
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Request { request, channel, .. },
+            } => {
+                // a request can arrive before the connection of a stranger is closed
+                if !self.is_member_peer(&peer)? {
+                    return Ok(None);
+                }
+                let response = Self::sync_response(&self.document, &request)?;
+                // if the peer is already gone it asks again on the next connection
+                let _ = self.swarm.behaviour_mut().sync.send_response(channel, response);
+                Ok(None)
+            }
+            RequestResponseEvent::Message {
+                peer,
+                message: RequestResponseMessage::Response { response, .. },
+            } => {
+                let (merged, rejected) = Self::apply_response(&mut self.document, response)?;
+                // the first sync of a new clone brings the document id
+                self.subscribe_updates()?;
+                Ok(Some(NodeEvent::Synced { peer, merged, rejected }))
+            }
+            RequestResponseEvent::OutboundFailure { peer, error, .. } => {
+                Ok(Some(NodeEvent::SyncFailed {
+                    peer,
+                    reason: error.to_string(),
+                }))
+            }
+            RequestResponseEvent::InboundFailure { .. } | RequestResponseEvent::ResponseSent { .. } => {
+                Ok(None)
+            }
+        }
+    }
+
+    pub fn sync_request(doc: &Document) -> Result<SyncRequest, Error> {
+        let state_vectors = doc
+            .resources
+            .iter()
+            .map(|(name, resource)| {
+                let state_vector = resource.store.transact().state_vector();
+                (name.clone(), state_vector.encode_v1())
+            })
+            .collect();
+        let heads = DocumentUtils::log_heads(doc)?
+            .into_iter()
+            .map(|(name, head)| (name, head.to_string()))
+            .collect();
+        Ok(SyncRequest { state_vectors, heads })
+    }
+
+    /// Packs the commits of all the resources where we have updates that are not
+    /// covered by the state vector of the requesting peer.
+    pub fn sync_response(doc: &Document, request: &SyncRequest) -> Result<SyncResponse, Error> {
+        let mut outdated_resources = HashSet::new();
+        for (name, resource) in &doc.resources {
+            let ours = resource.store.transact().state_vector();
+            let theirs = match request.state_vectors.get(name) {
+                Some(encoded) => StateVector::decode_v1(encoded)
+                    .map_err(|e| Error::Other(format!("Invalid state vector: {:?}", e)))?,
+                None => StateVector::default(),
+            };
+            if ours.iter().any(|(client, clock)| theirs.get(client) < *clock) {
+                outdated_resources.insert(name.clone());
+            }
+        }
+
+        // refs/heads/{resource}/{fingerprint}/{device}
+        let heads: BTreeMap<String, Oid> = DocumentUtils::log_heads(doc)?
+            .into_iter()
+            .filter(|(name, _)| {
+                name.split('/')
+                    .nth(2)
+                    .map_or(false, |resource| outdated_resources.contains(resource))
+            })
+            .collect();
+        let known: Vec<Oid> = request
+            .heads
+            .values()
+            .filter_map(|head| Oid::from_str(head).ok())
+            .filter(|head| doc.repository.find_commit(*head).is_ok())
+            .collect();
+
+        let (pack, _) = DocumentUtils::pack_logs(doc, &heads, &known)?;
+        Ok(SyncResponse {
+            heads: heads
+                .into_iter()
+                .map(|(name, head)| (name, head.to_string()))
+                .collect(),
+            pack,
+        })
+    }
+
+    /// Verifies and stores the commits a peer sent us, see `DocumentUtils::merge_remote_logs`.
+    pub fn apply_response(
+        doc: &mut Document,
+        response: SyncResponse,
+    ) -> Result<(BTreeMap<String, usize>, BTreeMap<String, String>), Error> {
+        DocumentUtils::write_pack(doc, &response.pack)?;
+        let heads = response
+            .heads
+            .into_iter()
+            .map(|(name, head)| Ok((name, Oid::from_str(&head)?)))
+            .collect::<Result<Vec<(String, Oid)>, Error>>()?;
+        DocumentUtils::merge_remote_logs(doc, heads)
+    }
 }
 
-/// Tests that the connection is closed upon a configurable
-/// number of consecutive ping failures.
-#[test]
-fn max_failures() {
-    fn prop(max_failures: NonZeroU8, muxer: MuxerChoice) {
-        let cfg = ping::Config::new()
-            .with_keep_alive(true)
-            .with_interval(Duration::from_millis(10))
-            .with_timeout(Duration::from_millis(0))
-            .with_max_failures(max_failures.into());
+fn build_transport(
+    keypair: &identity::Keypair,
+) -> Result<transport::Boxed<(PeerId, StreamMuxerBox)>, Error> {
+    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+        .into_authentic(keypair)
+        .map_err(|e| Error::Other(e.to_string()))?;
+    Ok(TcpTransport::new(GenTcpConfig::default().nodelay(true))
+        .upgrade(upgrade::Version::V1)
+        .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+        .multiplex(yamux::YamuxConfig::default())
+        .boxed())
+}
 
-        let (peer1_id, trans) = mk_transport(muxer);
-        let mut swarm1 = Swarm::new(trans, ping::Behaviour::new(cfg.clone()), peer1_id.clone());
+#[cfg(test)]
+mod tests {
 
-        let (peer2_id, trans) = mk_transport(muxer);
-        let mut swarm2 = Swarm::new(trans, ping::Behaviour::new(cfg), peer2_id.clone());
+    use std::path::PathBuf;
+
+    use futures::channel::mpsc;
+    use futures::future::Either;
+    use futures::prelude::*;
+    use libp2p::{identity, Multiaddr};
+
+    use crate::sync_libp2p::{Node, NodeEvent};
+    use crate::test_utils::{create_test_gpg_home, get_test_key, open_test_device};
+    use crate::validation::Validator;
+    use crate::Document;
+
+    /// Registers the peer ids of device-a and device-b, strangers are disconnected.
+    fn register_peers(doc: &mut Document, keypair_a: &identity::Keypair, keypair_b: &identity::Keypair) {
+        let fingerprint = get_test_key().fingerprint;
+        for (device, keypair) in [("device-a", keypair_a), ("device-b", keypair_b)] {
+            let peer_id = keypair.public().to_peer_id().to_base58();
+            doc.config_add_peer(&fingerprint, device, &peer_id).unwrap();
+        }
+    }
+
+    #[test]
+    fn sync_two_nodes() {
+        let test_dir = PathBuf::from("./.test/sync_libp2p/sync_two_nodes/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+
+        let mut doc_a = open_test_device(&gpg_home, &test_dir.join("device-a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+        doc_a.add_resource("test".to_string()).unwrap();
+        doc_a.update_resource_with_key_value("test", "entry", "1234").unwrap();
+        let doc_b = open_test_device(&gpg_home, &test_dir.join("device-b"), "device-b");
+        let keypair_a = identity::Keypair::generate_ed25519();
+        let keypair_b = identity::Keypair::generate_ed25519();
+        register_peers(&mut doc_a, &keypair_a, &keypair_b);
+
+        let mut node_a = Node::new(doc_a, keypair_a).unwrap();
+        let mut node_b = Node::new(doc_b, keypair_b).unwrap();
+        node_a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
 
         let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
 
-        let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
-        swarm1.listen_on(addr).unwrap();
+        let peer_a = async move {
+            loop {
+                if let NodeEvent::Listening(address) = node_a.next_event().await.unwrap() {
+                    tx.send(address).await.unwrap();
+                }
+            }
+        };
+
+        let peer_b = async move {
+            node_b.dial(rx.next().await.unwrap()).unwrap();
+            loop {
+                if let NodeEvent::Synced { merged, rejected, .. } = node_b.next_event().await.unwrap() {
+                    return (node_b, merged, rejected);
+                }
+            }
+        };
 
-        let peer1 = async move {
-            let mut count1: u8 = 0;
+        let result = future::select(Box::pin(peer_a), Box::pin(peer_b));
+        let (node_b, merged, rejected) = match async_std::task::block_on(result) {
+            Either::Right((synced, _)) => synced,
+            Either::Left(_) => unreachable!("node a only stops on an error"),
+        };
 
+        assert!(rejected.is_empty());
+        // init and the two registered peers
+        assert_eq!(merged.get(&format!("refs/heads/config/{}/device-a", fingerprint)), Some(&3));
+        assert_eq!(merged.get(&format!("refs/heads/test/{}/device-a", fingerprint)), Some(&2));
+        let content = node_b.document.resources.get("test").unwrap().get_content();
+        assert_eq!(content, "{entry: 1234}");
+
+        // the updates are persisted like logs fetched from a git hub
+        let mut reloaded = open_test_device(&gpg_home, &test_dir.join("device-b"), "device-b");
+        reloaded.load().unwrap();
+        assert_eq!(reloaded.resources.get("test").unwrap().get_content(), "{entry: 1234}");
+    }
+
+    #[test]
+    fn publish_live_updates() {
+        let test_dir = PathBuf::from("./.test/sync_libp2p/publish_live_updates/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+
+        let mut doc_a = open_test_device(&gpg_home, &test_dir.join("device-a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+        doc_a.add_resource("test".to_string()).unwrap();
+        let doc_b = open_test_device(&gpg_home, &test_dir.join("device-b"), "device-b");
+        let keypair_a = identity::Keypair::generate_ed25519();
+        let keypair_b = identity::Keypair::generate_ed25519();
+        register_peers(&mut doc_a, &keypair_a, &keypair_b);
+
+        let mut node_a = Node::new(doc_a, keypair_a).unwrap();
+        let mut node_b = Node::new(doc_b, keypair_b).unwrap();
+        node_a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
+
+        let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
+
+        // once device-b got the document with the first sync, it subscribes to the updates,
+        // whatever commits to the document is published by the node
+        let peer_a = async move {
             loop {
-                match swarm1.select_next_some().await {
-                    SwarmEvent::NewListenAddr { address, .. } => tx.send(address).await.unwrap(),
-                    SwarmEvent::Behaviour(ping::Event {
-                        result: Ok(ping::Success::Ping { .. }),
-                        ..
-                    }) => {
-                        count1 = 0; // there may be an occasional success
+                match node_a.next_event().await.unwrap() {
+                    NodeEvent::Listening(address) => tx.send(address).await.unwrap(),
+                    NodeEvent::PeerSubscribed(_) => {
+                        let value = serde_json::json!({"state": "live"});
+                        node_a.document.update_resource_with_json("test", "entry", &value).unwrap();
                     }
-                    SwarmEvent::Behaviour(ping::Event { result: Err(_), .. }) => {
-                        count1 += 1;
-                    }
-                    SwarmEvent::ConnectionClosed { .. } => return count1,
                     _ => {}
                 }
             }
         };
 
-        let peer2 = async move {
-            swarm2.dial(rx.next().await.unwrap()).unwrap();
+        let peer_b = async move {
+            node_b.dial(rx.next().await.unwrap()).unwrap();
+            loop {
+                if let NodeEvent::Updated { resource, log, .. } = node_b.next_event().await.unwrap() {
+                    return (node_b, resource, log);
+                }
+            }
+        };
 
-            let mut count2: u8 = 0;
+        let result = future::select(Box::pin(peer_a), Box::pin(peer_b));
+        let (node_b, resource, log) = match async_std::task::block_on(result) {
+            Either::Right((updated, _)) => updated,
+            Either::Left(_) => unreachable!("node a only stops on an error"),
+        };
+        assert_eq!(resource, "test");
+        assert_eq!(log, format!("refs/heads/test/{}/device-a", fingerprint));
+        let content = node_b.document.resources.get("test").unwrap().get_json();
+        assert_eq!(content, serde_json::json!({"entry": {"state": "live"}}));
+        // the update is persisted with its signed commit
+        let persisted = node_b
+            .document
+            .repository
+            .find_reference(&format!("refs/origin/test/{}/device-a", fingerprint))
+            .unwrap()
+            .peel_to_commit()
+            .unwrap();
+        assert_eq!(persisted.parent_count(), 1);
+    }
+
+    /// Refuses resources with empty values.
+    struct NoEmptyValues;
+
+    impl Validator for NoEmptyValues {
+        fn validate(&self, _resource: &str, content: &serde_json::Value) -> Result<(), String> {
+            match content.as_object().map_or(false, |map| map.values().any(|value| value == "")) {
+                true => Err("Empty values are not allowed.".to_string()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn quarantine_invalid_live_updates() {
+        let test_dir = PathBuf::from("./.test/sync_libp2p/quarantine_invalid_live_updates/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
+
+        let mut doc_a = open_test_device(&gpg_home, &test_dir.join("device-a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+        doc_a.add_resource("test".to_string()).unwrap();
+        doc_a.update_resource_with_key_value("test", "entry", "valid").unwrap();
+        let mut doc_b = open_test_device(&gpg_home, &test_dir.join("device-b"), "device-b");
+        doc_b.register_validator("test", NoEmptyValues);
+        let keypair_a = identity::Keypair::generate_ed25519();
+        let keypair_b = identity::Keypair::generate_ed25519();
+        register_peers(&mut doc_a, &keypair_a, &keypair_b);
+
+        let mut node_a = Node::new(doc_a, keypair_a).unwrap();
+        let mut node_b = Node::new(doc_b, keypair_b).unwrap();
+        node_a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
 
+        let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
+
+        // device-a has no validator, so it commits and publishes the empty value
+        let peer_a = async move {
             loop {
-                match swarm2.select_next_some().await {
-                    SwarmEvent::Behaviour(ping::Event {
-                        result: Ok(ping::Success::Ping { .. }),
-                        ..
-                    }) => {
-                        count2 = 0; // there may be an occasional success
-                    }
-                    SwarmEvent::Behaviour(ping::Event { result: Err(_), .. }) => {
-                        count2 += 1;
+                match node_a.next_event().await.unwrap() {
+                    NodeEvent::Listening(address) => tx.send(address).await.unwrap(),
+                    NodeEvent::PeerSubscribed(_) => {
+                        node_a.document.update_resource_with_key_value("test", "entry", "").unwrap();
                     }
-                    SwarmEvent::ConnectionClosed { .. } => return count2,
                     _ => {}
                 }
             }
         };
 
-        let future = future::join(peer1, peer2);
-        let (count1, count2) = async_std::task::block_on(future);
-        assert_eq!(u8::max(count1, count2), max_failures.get() - 1);
+        let peer_b = async move {
+            node_b.dial(rx.next().await.unwrap()).unwrap();
+            loop {
+                if let NodeEvent::Updated { .. } = node_b.next_event().await.unwrap() {
+                    return node_b;
+                }
+            }
+        };
+
+        let result = future::select(Box::pin(peer_a), Box::pin(peer_b));
+        let node_b = match async_std::task::block_on(result) {
+            Either::Right((node_b, _)) => node_b,
+            Either::Left(_) => unreachable!("node a only stops on an error"),
+        };
+        let document = &node_b.document;
+        assert_eq!(document.resources["test"].get_json(), serde_json::json!({"entry": "valid"}));
+        assert_eq!(document.quarantined.len(), 1);
+        assert_eq!(document.quarantined[0].log.device, "device-a");
+        assert_eq!(document.quarantined[0].position, 2);
     }
 
-    // QuickCheck::new().tests(10).quickcheck(prop as fn(_, _))
-}
+    #[test]
+    fn disconnect_strangers() {
+        let test_dir = PathBuf::from("./.test/sync_libp2p/disconnect_strangers/");
+        let gpg_home = create_test_gpg_home(test_dir.to_str().unwrap());
+        let fingerprint = get_test_key().fingerprint;
 
-#[test]
-fn unsupported_doesnt_fail() {
-    let (peer1_id, trans) = mk_transport(MuxerChoice::Mplex);
-    let mut swarm1 = Swarm::new(
-        trans,
-        DummyBehaviour::with_keep_alive(KeepAlive::Yes),
-        peer1_id.clone(),
-    );
+        let mut doc_a = open_test_device(&gpg_home, &test_dir.join("device-a"), "device-a")
+            .init(&fingerprint, &get_test_key().public_key)
+            .unwrap();
+        let keypair_a = identity::Keypair::generate_ed25519();
+        let keypair_b = identity::Keypair::generate_ed25519();
+        register_peers(&mut doc_a, &keypair_a, &keypair_b);
+        let stranger = open_test_device(&gpg_home, &test_dir.join("stranger"), "device-x");
 
-    let (peer2_id, trans) = mk_transport(MuxerChoice::Mplex);
-    let mut swarm2 = Swarm::new(
-        trans,
-        ping::Behaviour::new(ping::Config::new().with_keep_alive(true)),
-        peer2_id.clone(),
-    );
+        let mut node_a = Node::new(doc_a, keypair_a).unwrap();
+        let mut node_stranger = Node::new(stranger, identity::Keypair::generate_ed25519()).unwrap();
+        let stranger_id = node_stranger.peer_id();
+        node_a.listen_on("/ip4/127.0.0.1/tcp/0".parse().unwrap()).unwrap();
 
-    let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
+        let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
 
-    let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
-    swarm1.listen_on(addr).unwrap();
+        let peer_a = async move {
+            loop {
+                match node_a.next_event().await.unwrap() {
+                    NodeEvent::Listening(address) => tx.send(address).await.unwrap(),
+                    NodeEvent::PeerRejected(peer) => return peer,
+                    _ => {}
+                }
+            }
+        };
 
-    async_std::task::spawn(async move {
-        loop {
-            match swarm1.select_next_some().await {
-                SwarmEvent::NewListenAddr { address, .. } => tx.send(address).await.unwrap(),
-                _ => {}
+        let peer_stranger = async move {
+            node_stranger.dial(rx.next().await.unwrap()).unwrap();
+            loop {
+                if let NodeEvent::Synced { .. } = node_stranger.next_event().await.unwrap() {
+                    return node_stranger;
+                }
             }
+        };
+
+        let result = future::select(Box::pin(peer_a), Box::pin(peer_stranger));
+        match async_std::task::block_on(result) {
+            Either::Left((peer, _)) => assert_eq!(peer, stranger_id),
+            Either::Right(_) => panic!("a stranger got the document"),
         }
-    });
+    }
+}
 
-    let result = async_std::task::block_on(async move {
-        swarm2.dial(rx.next().await.unwrap()).unwrap();
+/// The ping tests below are copied from libp2p, they check the transport setup.
+#[cfg(test)]
+mod ping_tests {
+    use futures::{channel::mpsc, prelude::*};
+    use libp2p::{identity, mplex, noise, ping, yamux, Multiaddr, PeerId, Swarm};
+    use libp2p::core::muxing::StreamMuxerBox;
+    use libp2p::core::{transport, upgrade};
+    use libp2p::swarm::{DummyBehaviour, KeepAlive, SwarmEvent};
+    use libp2p::tcp::{GenTcpConfig, TcpTransport};
+    use libp2p::Transport;
+    use std::{num::NonZeroU8, time::Duration};
 
-        loop {
-            match swarm2.select_next_some().await {
-                SwarmEvent::Behaviour(ping::Event {
-                    result: Err(ping::Failure::Unsupported),
-                    ..
-                }) => {
-                    swarm2.disconnect_peer_id(peer1_id).unwrap();
+    /// Both peers see the given number of pings.
+    #[test]
+    fn ping_pong() {
+        fn prop(count: NonZeroU8, muxer: MuxerChoice) {
+            let cfg = ping::Config::new()
+                .with_keep_alive(true)
+                .with_interval(Duration::from_millis(10));
+
+            let (peer1_id, trans) = mk_transport(muxer);
+            let mut swarm1 = Swarm::new(trans, ping::Behaviour::new(cfg.clone()), peer1_id.clone());
+
+            let (peer2_id, trans) = mk_transport(muxer);
+            let mut swarm2 = Swarm::new(trans, ping::Behaviour::new(cfg), peer2_id.clone());
+
+            let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
+
+            let pid1 = peer1_id.clone();
+            let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+            swarm1.listen_on(addr).unwrap();
+
+            let mut count1 = count.get();
+            let mut count2 = count.get();
+
+            let peer1 = async move {
+                loop {
+                    match swarm1.select_next_some().await {
+                        SwarmEvent::NewListenAddr { address, .. } => tx.send(address).await.unwrap(),
+                        SwarmEvent::Behaviour(ping::Event {
+                            peer,
+                            result: Ok(ping::Success::Ping { rtt }),
+                        }) => {
+                            count1 -= 1;
+                            if count1 == 0 {
+                                return (pid1.clone(), peer, rtt);
+                            }
+                        }
+                        SwarmEvent::Behaviour(ping::Event { result: Err(e), .. }) => {
+                            panic!("Ping failure: {:?}", e)
+                        }
+                        _ => {}
+                    }
                 }
-                SwarmEvent::ConnectionClosed { cause: Some(e), .. } => {
-                    break Err(e);
+            };
+
+            let pid2 = peer2_id.clone();
+            let peer2 = async move {
+                swarm2.dial(rx.next().await.unwrap()).unwrap();
+
+                loop {
+                    match swarm2.select_next_some().await {
+                        SwarmEvent::Behaviour(ping::Event {
+                            peer,
+                            result: Ok(ping::Success::Ping { rtt }),
+                        }) => {
+                            count2 -= 1;
+                            if count2 == 0 {
+                                return (pid2.clone(), peer, rtt);
+                            }
+                        }
+                        SwarmEvent::Behaviour(ping::Event { result: Err(e), .. }) => {
+                            panic!("Ping failure: {:?}", e)
+                        }
+                        _ => {}
+                    }
                 }
-                SwarmEvent::ConnectionClosed { cause: None, .. } => {
-                    break Ok(());
+            };
+
+            let result = future::select(Box::pin(peer1), Box::pin(peer2));
+            let ((p1, p2, rtt), _) = async_std::task::block_on(result).factor_first();
+            assert!(p1 == peer1_id && p2 == peer2_id || p1 == peer2_id && p2 == peer1_id);
+            assert!(rtt < Duration::from_millis(50));
+        }
+
+        for muxer in [MuxerChoice::Mplex, MuxerChoice::Yamux] {
+            for count in [1, 5] {
+                prop(NonZeroU8::new(count).unwrap(), muxer);
+            }
+        }
+    }
+
+    /// Tests that the connection is closed upon a configurable
+    /// number of consecutive ping failures.
+    #[test]
+    fn max_failures() {
+        fn prop(max_failures: NonZeroU8, muxer: MuxerChoice) {
+            let cfg = ping::Config::new()
+                .with_keep_alive(true)
+                .with_interval(Duration::from_millis(10))
+                .with_timeout(Duration::from_millis(0))
+                .with_max_failures(max_failures.into());
+
+            let (peer1_id, trans) = mk_transport(muxer);
+            let mut swarm1 = Swarm::new(trans, ping::Behaviour::new(cfg.clone()), peer1_id.clone());
+
+            let (peer2_id, trans) = mk_transport(muxer);
+            let mut swarm2 = Swarm::new(trans, ping::Behaviour::new(cfg), peer2_id.clone());
+
+            let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
+
+            let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+            swarm1.listen_on(addr).unwrap();
+
+            let peer1 = async move {
+                let mut count1: u8 = 0;
+
+                loop {
+                    match swarm1.select_next_some().await {
+                        SwarmEvent::NewListenAddr { address, .. } => tx.send(address).await.unwrap(),
+                        SwarmEvent::Behaviour(ping::Event {
+                            result: Ok(ping::Success::Ping { .. }),
+                            ..
+                        }) => {
+                            count1 = 0; // there may be an occasional success
+                        }
+                        SwarmEvent::Behaviour(ping::Event { result: Err(_), .. }) => {
+                            count1 += 1;
+                        }
+                        SwarmEvent::ConnectionClosed { .. } => return count1,
+                        _ => {}
+                    }
                 }
-                _ => {}
+            };
+
+            let peer2 = async move {
+                swarm2.dial(rx.next().await.unwrap()).unwrap();
+
+                let mut count2: u8 = 0;
+
+                loop {
+                    match swarm2.select_next_some().await {
+                        SwarmEvent::Behaviour(ping::Event {
+                            result: Ok(ping::Success::Ping { .. }),
+                            ..
+                        }) => {
+                            count2 = 0; // there may be an occasional success
+                        }
+                        SwarmEvent::Behaviour(ping::Event { result: Err(_), .. }) => {
+                            count2 += 1;
+                        }
+                        SwarmEvent::ConnectionClosed { .. } => return count2,
+                        _ => {}
+                    }
+                }
+            };
+
+            let future = future::join(peer1, peer2);
+            let (count1, count2) = async_std::task::block_on(future);
+            assert_eq!(u8::max(count1, count2), max_failures.get() - 1);
+        }
+
+        for muxer in [MuxerChoice::Mplex, MuxerChoice::Yamux] {
+            for max_failures in [1, 3] {
+                prop(NonZeroU8::new(max_failures).unwrap(), muxer);
             }
         }
-    });
+    }
 
-    result.expect("node with ping should not fail connection due to unsupported protocol");
-}
+    #[test]
+    fn unsupported_doesnt_fail() {
+        let (peer1_id, trans) = mk_transport(MuxerChoice::Mplex);
+        let mut swarm1 = Swarm::new(
+            trans,
+            DummyBehaviour::with_keep_alive(KeepAlive::Yes),
+            peer1_id.clone(),
+        );
 
-fn mk_transport(muxer: MuxerChoice) -> (PeerId, transport::Boxed<(PeerId, StreamMuxerBox)>) {
-    let id_keys = identity::Keypair::generate_ed25519();
-    let peer_id = id_keys.public().to_peer_id();
-    let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
-        .into_authentic(&id_keys)
-        .unwrap();
-    (
-        peer_id,
-        TcpTransport::new(GenTcpConfig::default().nodelay(true))
-            .upgrade(upgrade::Version::V1)
-            .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
-            .multiplex(match muxer {
-                MuxerChoice::Yamux => upgrade::EitherUpgrade::A(yamux::YamuxConfig::default()),
-                MuxerChoice::Mplex => upgrade::EitherUpgrade::B(mplex::MplexConfig::default()),
-            })
-            .boxed(),
-    )
-}
+        let (peer2_id, trans) = mk_transport(MuxerChoice::Mplex);
+        let mut swarm2 = Swarm::new(
+            trans,
+            ping::Behaviour::new(ping::Config::new().with_keep_alive(true)),
+            peer2_id.clone(),
+        );
 
-#[derive(Debug, Copy, Clone)]
-enum MuxerChoice {
-    Mplex,
-    Yamux,
-}
+        let (mut tx, mut rx) = mpsc::channel::<Multiaddr>(1);
+
+        let addr = "/ip4/127.0.0.1/tcp/0".parse().unwrap();
+        swarm1.listen_on(addr).unwrap();
+
+        async_std::task::spawn(async move {
+            loop {
+                match swarm1.select_next_some().await {
+                    SwarmEvent::NewListenAddr { address, .. } => tx.send(address).await.unwrap(),
+                    _ => {}
+                }
+            }
+        });
+
+        let result = async_std::task::block_on(async move {
+            swarm2.dial(rx.next().await.unwrap()).unwrap();
+
+            loop {
+                match swarm2.select_next_some().await {
+                    SwarmEvent::Behaviour(ping::Event {
+                        result: Err(ping::Failure::Unsupported),
+                        ..
+                    }) => {
+                        swarm2.disconnect_peer_id(peer1_id).unwrap();
+                    }
+                    SwarmEvent::ConnectionClosed { cause: Some(e), .. } => {
+                        break Err(e);
+                    }
+                    SwarmEvent::ConnectionClosed { cause: None, .. } => {
+                        break Ok(());
+                    }
+                    _ => {}
+                }
+            }
+        });
+
+        result.expect("node with ping should not fail connection due to unsupported protocol");
+    }
+
+    fn mk_transport(muxer: MuxerChoice) -> (PeerId, transport::Boxed<(PeerId, StreamMuxerBox)>) {
+        let id_keys = identity::Keypair::generate_ed25519();
+        let peer_id = id_keys.public().to_peer_id();
+        let noise_keys = noise::Keypair::<noise::X25519Spec>::new()
+            .into_authentic(&id_keys)
+            .unwrap();
+        (
+            peer_id,
+            TcpTransport::new(GenTcpConfig::default().nodelay(true))
+                .upgrade(upgrade::Version::V1)
+                .authenticate(noise::NoiseConfig::xx(noise_keys).into_authenticated())
+                .multiplex(match muxer {
+                    MuxerChoice::Yamux => upgrade::EitherUpgrade::A(yamux::YamuxConfig::default()),
+                    MuxerChoice::Mplex => upgrade::EitherUpgrade::B(mplex::MplexConfig::default()),
+                })
+                .boxed(),
+        )
+    }
 
-/*
-impl Arbitrary for MuxerChoice {
-    fn arbitrary<G: Gen>(g: &mut G) -> MuxerChoice {
-        *[MuxerChoice::Mplex, MuxerChoice::Yamux].choose(g).unwrap()
+    #[derive(Debug, Copy, Clone)]
+    enum MuxerChoice {
+        Mplex,
+        Yamux,
     }
 }
-*/
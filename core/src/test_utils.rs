@@ -1,6 +1,6 @@
 use fs_extra::dir::CopyOptions;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
 use gpgme::ExportMode;
 use sequoia_openpgp::parse::Parse;
@@ -8,6 +8,7 @@ use sequoia_openpgp::serialize::MarshalInto;
 use sequoia_openpgp::Cert;
 use sequoia_openpgp::crypto::mpi::SecretKeyMaterial;
 
+use crate::document::{Document, DocumentNewOptions};
 use crate::errors::Error;
 use crate::gpg::{CreateUserArgs, Gpg, Key};
 
@@ -234,6 +235,48 @@ pub fn create_test_env_with_test_gpg_key(test_data_path: String) -> (PathBuf, Ke
     (key_dir, key)
 }
 
+/// Copies the keyring of the test key into `{test_data_path}.key` and returns its path,
+/// `GNUPGHOME` is left alone such that tests with their own keyring can run in parallel.
+#[allow(dead_code)]
+pub fn create_test_gpg_home(test_data_path: &str) -> PathBuf {
+    let doc_dir = PathBuf::from(test_data_path);
+    fs::remove_dir_all(&doc_dir).ok();
+    fs::create_dir_all(&doc_dir).unwrap();
+    let key_dir = concat!(env!("CARGO_MANIFEST_DIR"), "/test/key1/.key");
+    fs_extra::dir::copy(key_dir, &doc_dir, &CopyOptions::new()).unwrap();
+    doc_dir.join(".key")
+}
+
+/// The options of a document in `directory` with the test key from `gpg_home`,
+/// see `create_test_gpg_home`.
+#[allow(dead_code)]
+pub fn test_document_options(gpg_home: &Path, directory: &Path) -> DocumentNewOptions {
+    DocumentNewOptions {
+        directory: directory.to_path_buf(),
+        identity_fingerprint: get_test_key().fingerprint,
+        name: String::from("name"),
+        gpg_home: Some(gpg_home.to_path_buf()),
+    }
+}
+
+/// A document in `directory` on the device `device`, it still has to be initialized or imported.
+#[allow(dead_code)]
+pub fn open_test_device(gpg_home: &Path, directory: &Path, device: &str) -> Document {
+    let doc = Document::new(test_document_options(gpg_home, directory)).unwrap();
+    doc.config_set_local_device(device).unwrap();
+    doc
+}
+
+/// A new document in `test_data_path` whose only member is the test key,
+/// see `create_test_gpg_home`.
+#[allow(dead_code)]
+pub fn create_test_document(test_data_path: &str) -> Document {
+    let gpg_home = create_test_gpg_home(test_data_path);
+    let doc = Document::new(test_document_options(&gpg_home, Path::new(test_data_path))).unwrap();
+    let test_key = get_test_key();
+    doc.init(&test_key.fingerprint, &test_key.public_key).unwrap()
+}
+
 
 #[allow(dead_code)]
 pub struct TestRSAKey {
@@ -0,0 +1,126 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::Error;
+use crate::event_log_store::EventLogStore;
+use crate::resource::Resource;
+
+/// The version of the layout of a log entry: the yrs update (`encode_update_v2`) and its `UpdateMeta`.
+pub const UPDATE_FORMAT_VERSION: u32 = 1;
+
+/// Metadata stored next to each update, as JSON in the blob `meta` of a log commit.
+///
+/// It allows to order, query and audit the updates of a resource without decoding them.
+/// Updates committed before the metadata was introduced have none.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpdateMeta {
+    pub format_version: u32,
+    /// Lamport timestamp, greater than the one of every update of the resource the author knew
+    pub lamport: u64,
+    /// When the update was committed, RFC 3339
+    pub wall_clock: String,
+    /// The state vector of the author's resource after the update, yrs client id -> clock
+    pub state_vector: BTreeMap<u64, u32>,
+    /// The key paths of the `root` map that were added, changed or removed
+    pub touched_key_paths: Vec<String>,
+}
+
+impl UpdateMeta {
+    /// The metadata of a local update that was applied to the resource.
+    pub fn for_local_update(
+        store: &dyn EventLogStore,
+        resource: &Resource,
+        touched_key_paths: Vec<String>,
+    ) -> Result<UpdateMeta, Error> {
+        let state_vector = resource
+            .store
+            .transact()
+            .state_vector()
+            .iter()
+            .map(|(client, clock)| (*client, *clock))
+            .collect();
+        Ok(UpdateMeta {
+            format_version: UPDATE_FORMAT_VERSION,
+            lamport: lamport(store, &resource.name)? + 1,
+            wall_clock: chrono::Utc::now().to_rfc3339(),
+            state_vector,
+            touched_key_paths,
+        })
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, Error> {
+        serde_json::to_vec(self).map_err(|e| Error::DcoreError(format!("Could not encode the update metadata: {}", e)))
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<UpdateMeta, Error> {
+        serde_json::from_slice(bytes).map_err(|e| Error::DcoreError(format!("Invalid update metadata: {}", e)))
+    }
+}
+
+/// The highest Lamport timestamp of the heads of all logs of a resource, 0 if none has metadata.
+pub fn lamport(store: &dyn EventLogStore, resource: &str) -> Result<u64, Error> {
+    let mut lamport = 0;
+    for log in store.logs()?.iter().filter(|log| log.resource == resource) {
+        if let Some(meta) = store.head_meta(log)? {
+            lamport = lamport.max(meta.lamport);
+        }
+    }
+    Ok(lamport)
+}
+
+#[cfg(test)]
+mod tests {
+
+    use yrs::Map;
+
+    use crate::event_log_store::{EventLogStore, Log, LogKind, MemoryEventLogStore};
+    use crate::resource::Resource;
+    use crate::update_meta::{lamport, UpdateMeta, UPDATE_FORMAT_VERSION};
+
+    fn sign(_data: &str) -> Result<String, crate::errors::Error> {
+        Ok("signature\n".to_string())
+    }
+
+    #[test]
+    fn lamport_timestamps_follow_all_logs() {
+        let store = MemoryEventLogStore::new();
+        let mut resource = Resource::new(&"notes".to_string());
+        let log = Log::local("notes", "ALICE", "device-0");
+        assert_eq!(lamport(&store, "notes").unwrap(), 0);
+
+        // an update without metadata, as committed by older versions
+        let update = resource.set_resource_meta(&"notes".to_string()).unwrap();
+        store.append(&log, &update, None, &sign).unwrap();
+        assert_eq!(lamport(&store, "notes").unwrap(), 0);
+
+        let update = resource
+            .add_local_update(|transaction| {
+                let root = transaction.get_map("root");
+                root.insert(transaction, "title".to_owned(), "Meeting");
+                transaction
+            })
+            .unwrap();
+        let meta = UpdateMeta::for_local_update(&store, &resource, vec!["title".to_string()]).unwrap();
+        assert_eq!(meta.format_version, UPDATE_FORMAT_VERSION);
+        assert_eq!(meta.lamport, 1);
+        assert_eq!(meta.state_vector.len(), 1);
+        store.append(&log, &update, Some(&meta), &sign).unwrap();
+
+        let remote = Log {
+            kind: LogKind::Origin,
+            ..Log::local("notes", "BOB", "device-0")
+        };
+        let remote_meta = UpdateMeta {
+            lamport: 7,
+            ..meta.clone()
+        };
+        store.append(&remote, &update, Some(&remote_meta), &sign).unwrap();
+        assert_eq!(lamport(&store, "notes").unwrap(), 7);
+        assert_eq!(lamport(&store, "other").unwrap(), 0);
+
+        let metas: Vec<Option<UpdateMeta>> = store.metas(&log).unwrap().map(Result::unwrap).collect();
+        assert_eq!(metas, vec![None, Some(meta.clone())]);
+        assert_eq!(UpdateMeta::from_bytes(&meta.to_bytes().unwrap()).unwrap(), meta);
+    }
+}
@@ -0,0 +1,297 @@
+use std::collections::HashMap;
+
+use jsonschema::JSONSchema;
+use yrs::updates::decoder::Decode;
+use yrs::{StateVector, Update};
+
+use crate::errors::Error;
+use crate::event_log_store::{EventLogStore, Log};
+use crate::resource::Resource;
+
+/// Checks the content of a resource, i.e. the JSON of its `root` map. Validators have to be `Send`,
+/// documents are moved between threads, e.g. by the http server.
+pub trait Validator: Send {
+    /// Returns why the content is invalid.
+    fn validate(&self, resource: &str, content: &serde_json::Value) -> Result<(), String>;
+}
+
+/// Validates against a JSON Schema, see `Document::set_resource_schema` to store one in a resource.
+pub struct JsonSchemaValidator {
+    schema: JSONSchema,
+}
+
+impl JsonSchemaValidator {
+    pub fn new(schema: &serde_json::Value) -> Result<JsonSchemaValidator, Error> {
+        let schema = JSONSchema::compile(schema)
+            .map_err(|e| Error::ValidationError(format!("Invalid JSON Schema: {}", e)))?;
+        Ok(JsonSchemaValidator { schema })
+    }
+}
+
+impl Validator for JsonSchemaValidator {
+    fn validate(&self, _resource: &str, content: &serde_json::Value) -> Result<(), String> {
+        if let Err(errors) = self.schema.validate(content) {
+            let reasons: Vec<String> = errors
+                .map(|error| format!("{} at '{}'", error, error.instance_path))
+                .collect();
+            return Err(reasons.join(", "));
+        }
+        Ok(())
+    }
+}
+
+/// An update of another log that would have made its resource invalid. Neither it nor the later
+/// updates of the log are applied, but they stay in the log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuarantinedUpdate {
+    pub log: Log,
+    /// Index of the update in the log, starting at 0
+    pub position: usize,
+    pub reason: String,
+}
+
+/// The validators of the resources: the JSON Schema in a resource's `_resource_meta` and the
+/// ones registered with `Document::register_validator`.
+///
+/// Local updates that make a resource invalid are refused. When the logs are loaded, updates that
+/// would make a resource invalid are quarantined, see `QuarantinedUpdate`.
+#[derive(Default)]
+pub struct Validators {
+    registered: HashMap<String, Vec<Box<dyn Validator>>>,
+}
+
+impl Validators {
+    pub(crate) fn register(&mut self, resource: &str, validator: Box<dyn Validator>) {
+        self.registered.entry(resource.to_string()).or_default().push(validator);
+    }
+
+    fn is_active(&self, resource: &Resource) -> bool {
+        self.registered.contains_key(&resource.name) || resource.get_schema().is_some()
+    }
+
+    /// Returns why the content of the resource is invalid.
+    pub fn check(&self, resource: &Resource) -> Result<(), String> {
+        if !self.is_active(resource) {
+            return Ok(());
+        }
+        let content = resource.get_json();
+        if let Some(schema) = resource.get_schema() {
+            JsonSchemaValidator::new(&schema)
+                .map_err(|e| e.to_string())?
+                .validate(&resource.name, &content)?;
+        }
+        for validator in self.registered.get(&resource.name).into_iter().flatten() {
+            validator.validate(&resource.name, &content)?;
+        }
+        Ok(())
+    }
+
+    /// Replays the resources that are invalid once more, without the updates that make them invalid.
+    /// Returns the quarantined updates.
+    pub(crate) fn quarantine_invalid(
+        &self,
+        store: &dyn EventLogStore,
+        resources: &mut HashMap<String, Resource>,
+    ) -> Result<Vec<QuarantinedUpdate>, Error> {
+        let mut quarantined = Vec::new();
+        for (name, resource) in resources.iter_mut() {
+            if self.check(resource).is_ok() {
+                continue;
+            }
+            let (valid, rejected) = self.replay_valid(store, name)?;
+            *resource = valid;
+            quarantined.extend(rejected);
+        }
+        Ok(quarantined)
+    }
+
+    /// Replays the logs of a resource, an update is only applied if the resource stays valid.
+    /// A log is checked once as a whole, its updates are only checked one by one if it makes the
+    /// resource invalid, to find the first update that does.
+    pub(crate) fn replay_valid(
+        &self,
+        store: &dyn EventLogStore,
+        name: &str,
+    ) -> Result<(Resource, Vec<QuarantinedUpdate>), Error> {
+        let mut logs: Vec<Log> = store.logs()?.into_iter().filter(|log| log.resource == name).collect();
+        // our own logs first, their updates were validated when they were committed
+        logs.sort_by_key(|log| log.kind);
+
+        let mut resource = Resource::new(&name.to_string());
+        let mut quarantined = Vec::new();
+        for log in logs {
+            let updates = store.updates(&log)?.collect::<Result<Vec<Vec<u8>>, Error>>()?;
+            let candidate = copy(&resource, &log)?;
+            for update in &updates {
+                apply(&candidate, update, &log)?;
+            }
+            if self.check(&candidate).is_ok() {
+                resource = candidate;
+                continue;
+            }
+
+            let candidate = copy(&resource, &log)?;
+            for (position, update) in updates.iter().enumerate() {
+                apply(&candidate, update, &log)?;
+                if let Err(reason) = self.check(&candidate) {
+                    quarantined.push(QuarantinedUpdate {
+                        log: log.clone(),
+                        position,
+                        reason,
+                    });
+                    break;
+                }
+                apply(&resource, update, &log)?;
+            }
+        }
+        Ok((resource, quarantined))
+    }
+}
+
+/// A resource with the same state, to try updates on.
+fn copy(resource: &Resource, log: &Log) -> Result<Resource, Error> {
+    let copy = Resource::new(&resource.name);
+    let state = resource.store.transact().encode_state_as_update_v2(&StateVector::default());
+    apply(&copy, &state, log)?;
+    Ok(copy)
+}
+
+fn apply(resource: &Resource, update: &[u8], log: &Log) -> Result<(), Error> {
+    resource.store.transact().apply_update(decode(update, log)?);
+    Ok(())
+}
+
+fn decode(update: &[u8], log: &Log) -> Result<Update, Error> {
+    Update::decode_v2(update).map_err(|e| Error::DcoreError(format!("Could not decode an update of {}: {}", log, e)))
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::collections::HashMap;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    use yrs::Map;
+
+    use crate::event_log_store::{EventLogStore, Log, LogKind, MemoryEventLogStore};
+    use crate::resource::Resource;
+    use crate::validation::{JsonSchemaValidator, Validator, Validators};
+
+    struct NoEmptyValues;
+
+    impl Validator for NoEmptyValues {
+        fn validate(&self, _resource: &str, content: &serde_json::Value) -> Result<(), String> {
+            match content.as_object().map_or(false, |map| map.values().any(|value| value == "")) {
+                true => Err("Empty values are not allowed.".to_string()),
+                false => Ok(()),
+            }
+        }
+    }
+
+    /// Counts the validations
+    struct Counting(Arc<AtomicUsize>);
+
+    impl Validator for Counting {
+        fn validate(&self, _resource: &str, _content: &serde_json::Value) -> Result<(), String> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+    }
+
+    fn sign(_data: &str) -> Result<String, crate::errors::Error> {
+        Ok("signature\n".to_string())
+    }
+
+    fn set(resource: &mut Resource, key: &'static str, value: &'static str) -> Vec<u8> {
+        resource
+            .add_local_update(|transaction| {
+                let root = transaction.get_map("root");
+                root.insert(transaction, key.to_owned(), value);
+                transaction
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn json_schema() {
+        let validator = JsonSchemaValidator::new(&serde_json::json!({
+            "type": "object",
+            "properties": {"status": {"enum": ["draft", "done"]}}
+        }))
+        .unwrap();
+        assert!(validator.validate("notes", &serde_json::json!({"status": "done"})).is_ok());
+        assert!(validator.validate("notes", &serde_json::json!({"status": "lost"})).is_err());
+        assert!(JsonSchemaValidator::new(&serde_json::json!({"type": 12})).is_err());
+    }
+
+    #[test]
+    fn quarantine_invalid_remote_updates() {
+        let mut validators = Validators::default();
+        validators.register("notes", Box::new(NoEmptyValues));
+
+        let store = MemoryEventLogStore::new();
+        let mut local = Resource::new(&"notes".to_string());
+        let local_log = Log::local("notes", "ALICE", "device-0");
+        store.append(&local_log, &local.set_resource_meta(&"notes".to_string()).unwrap(), None, &sign).unwrap();
+        store.append(&local_log, &set(&mut local, "title", "Meeting"), None, &sign).unwrap();
+
+        let mut remote = Resource::new(&"notes".to_string());
+        let remote_log = Log {
+            kind: LogKind::Origin,
+            ..Log::local("notes", "BOB", "device-0")
+        };
+        store.append(&remote_log, &set(&mut remote, "place", "Zurich"), None, &sign).unwrap();
+        store.append(&remote_log, &set(&mut remote, "agenda", ""), None, &sign).unwrap();
+        store.append(&remote_log, &set(&mut remote, "time", "10:00"), None, &sign).unwrap();
+
+        let mut resources = crate::event_log_store::load_resources(&store).unwrap();
+        assert!(validators.check(&resources["notes"]).is_err());
+        let quarantined = validators.quarantine_invalid(&store, &mut resources).unwrap();
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].log, remote_log);
+        assert_eq!(quarantined[0].position, 1);
+        assert_eq!(
+            resources["notes"].get_json(),
+            serde_json::json!({"title": "Meeting", "place": "Zurich"})
+        );
+
+        let mut valid = HashMap::new();
+        valid.insert("notes".to_string(), Resource::new(&"notes".to_string()));
+        assert!(validators.quarantine_invalid(&store, &mut valid).unwrap().is_empty());
+    }
+
+    #[test]
+    fn validate_logs_once() {
+        let validations = Arc::new(AtomicUsize::new(0));
+        let mut validators = Validators::default();
+        validators.register("notes", Box::new(NoEmptyValues));
+        validators.register("notes", Box::new(Counting(validations.clone())));
+
+        let store = MemoryEventLogStore::new();
+        let mut local = Resource::new(&"notes".to_string());
+        let local_log = Log::local("notes", "ALICE", "device-0");
+        for key in ["a", "b", "c", "d"] {
+            store.append(&local_log, &set(&mut local, key, "value"), None, &sign).unwrap();
+        }
+        let mut remote = Resource::new(&"notes".to_string());
+        let remote_log = Log {
+            kind: LogKind::Origin,
+            ..Log::local("notes", "BOB", "device-0")
+        };
+        for (key, value) in [("e", "value"), ("f", ""), ("g", "value")] {
+            store.append(&remote_log, &set(&mut remote, key, value), None, &sign).unwrap();
+        }
+
+        // the valid log is checked once, the invalid one once and then update by update until the
+        // first invalid one, the counting validator only runs if the content passed the first one
+        let (resource, quarantined) = validators.replay_valid(&store, "notes").unwrap();
+        assert_eq!(validations.load(Ordering::SeqCst), 2);
+        assert_eq!(quarantined.len(), 1);
+        assert_eq!(quarantined[0].position, 1);
+        assert_eq!(
+            resource.get_json(),
+            serde_json::json!({"a": "value", "b": "value", "c": "value", "d": "value", "e": "value"})
+        );
+    }
+}
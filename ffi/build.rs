@@ -0,0 +1,20 @@
+use std::env;
+use std::path::PathBuf;
+
+/// Generates the C header into `OUT_DIR`. The copy in `include/dcore.h` is checked in for C
+/// projects and must match it, see the test `header_is_up_to_date`. After changing the API run
+/// `cbindgen --config cbindgen.toml --output include/dcore.h` in this directory.
+fn main() {
+    let crate_dir = PathBuf::from(env::var("CARGO_MANIFEST_DIR").unwrap());
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let config = cbindgen::Config::from_file(crate_dir.join("cbindgen.toml"))
+        .expect("Could not read cbindgen.toml");
+    cbindgen::Builder::new()
+        .with_crate(&crate_dir)
+        .with_config(config)
+        .generate()
+        .expect("Could not generate the C header")
+        .write_to_file(out_dir.join("dcore.h"));
+    println!("cargo:rerun-if-changed=src/lib.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+}
@@ -0,0 +1,85 @@
+#ifndef DCORE_H
+#define DCORE_H
+
+/* This file is generated by cbindgen from ffi/src/lib.rs, do not edit it. */
+
+#include <stdarg.h>
+#include <stdbool.h>
+#include <stdint.h>
+#include <stdlib.h>
+
+// Result of every call, details of an error are available with `dcore_last_error`.
+typedef enum DcoreStatus {
+  DCORE_STATUS_OK = 0,
+  DCORE_STATUS_ERROR = 1,
+  DCORE_STATUS_INVALID_ARGUMENT = 2,
+  DCORE_STATUS_NOT_FOUND = 3,
+} DcoreStatus;
+
+// An open document. A document must only be used by one thread at a time.
+typedef struct DcoreDocument DcoreDocument;
+
+// Called with the name of a resource after it changed and the user data given at registration.
+// The name is only valid during the call.
+typedef void (*DcoreUpdateCallback)(const char *resource, void *user_data);
+
+#ifdef __cplusplus
+extern "C" {
+#endif // __cplusplus
+
+// Opens the document in `directory` with the identity `fingerprint` from the gpg keyring in
+// `gpg_home`, the default keyring if it is null. `DCORE_STATUS_NOT_FOUND` if there is no document,
+// use `dcore_document_create` for new ones. The document has to be freed with `dcore_document_free`.
+DcoreStatus dcore_document_open(const char *directory,
+                                const char *fingerprint,
+                                const char *gpg_home,
+                                DcoreDocument **out);
+
+// Creates a new document in `directory`, the identity `fingerprint` is its first member. Its key
+// is taken from the gpg keyring in `gpg_home`, the default keyring if it is null.
+// The document has to be freed with `dcore_document_free`.
+DcoreStatus dcore_document_create(const char *directory,
+                                  const char *fingerprint,
+                                  const char *gpg_home,
+                                  DcoreDocument **out);
+
+void dcore_document_free(DcoreDocument *document);
+
+// Writes the names of the resources as JSON array to `out_json`, free it with `dcore_string_free`.
+DcoreStatus dcore_document_list_resources(DcoreDocument *document, char **out_json);
+
+DcoreStatus dcore_document_add_resource(DcoreDocument *document, const char *resource);
+
+// Writes the content of a resource as JSON object to `out_json`, free it with `dcore_string_free`.
+DcoreStatus dcore_resource_get_json(DcoreDocument *document,
+                                    const char *resource,
+                                    char **out_json);
+
+// Sets the JSON value `json_value` at `key_path` in a resource, nested keys are separated by dots.
+DcoreStatus dcore_resource_set_json(DcoreDocument *document,
+                                    const char *resource,
+                                    const char *key_path,
+                                    const char *json_value);
+
+// Syncs the document with its git remote. If `out_report` is not null, the sync report is
+// written to it, free it with `dcore_string_free`. Rejected logs are reported as error.
+DcoreStatus dcore_document_sync(DcoreDocument *document, char **out_report);
+
+// Registers the callback for changes of resources made through this handle or brought in by
+// `dcore_document_sync`, it replaces an earlier one. Pass NULL to remove it.
+DcoreStatus dcore_document_set_update_callback(DcoreDocument *document,
+                                               DcoreUpdateCallback callback,
+                                               void *user_data);
+
+// Frees a string returned by dcore.
+void dcore_string_free(char *value);
+
+// The message of the last error on this thread, NULL if the last call succeeded.
+// It is valid until the next call on this thread and must not be freed.
+const char *dcore_last_error(void);
+
+#ifdef __cplusplus
+} // extern "C"
+#endif // __cplusplus
+
+#endif /* DCORE_H */
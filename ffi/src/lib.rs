@@ -0,0 +1,449 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_void};
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+use dcore::document::{Document, DocumentNewOptions};
+use dcore::sync_git::GitSync;
+
+/// Result of every call, details of an error are available with `dcore_last_error`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DcoreStatus {
+    Ok = 0,
+    Error = 1,
+    InvalidArgument = 2,
+    NotFound = 3,
+}
+
+/// Called with the name of a resource after it changed and the user data given at registration.
+/// The name is only valid during the call.
+pub type DcoreUpdateCallback = Option<extern "C" fn(resource: *const c_char, user_data: *mut c_void)>;
+
+/// An open document. A document must only be used by one thread at a time.
+pub struct DcoreDocument {
+    document: Document,
+    callback: DcoreUpdateCallback,
+    user_data: *mut c_void,
+}
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+struct Failure {
+    status: DcoreStatus,
+    message: String,
+}
+
+impl Failure {
+    fn new(status: DcoreStatus, message: impl ToString) -> Failure {
+        Failure {
+            status,
+            message: message.to_string(),
+        }
+    }
+}
+
+fn error(e: dcore::errors::Error) -> Failure {
+    Failure::new(DcoreStatus::Error, e)
+}
+
+/// Runs the body of an exported function. Panics must not cross the C boundary,
+/// they are reported like errors.
+fn run<F: FnOnce() -> Result<(), Failure>>(body: F) -> DcoreStatus {
+    let result = catch_unwind(AssertUnwindSafe(body)).unwrap_or_else(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_else(|| "dcore panicked".to_string());
+        Err(Failure::new(DcoreStatus::Error, message))
+    });
+    let (status, message) = match result {
+        Ok(()) => (DcoreStatus::Ok, None),
+        Err(failure) => (failure.status, CString::new(failure.message.replace('\0', " ")).ok()),
+    };
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = message);
+    status
+}
+
+unsafe fn read_str<'a>(value: *const c_char, name: &str) -> Result<&'a str, Failure> {
+    if value.is_null() {
+        return Err(Failure::new(DcoreStatus::InvalidArgument, format!("{} is null.", name)));
+    }
+    CStr::from_ptr(value)
+        .to_str()
+        .map_err(|_| Failure::new(DcoreStatus::InvalidArgument, format!("{} is not UTF-8.", name)))
+}
+
+unsafe fn read_optional_str<'a>(value: *const c_char, name: &str) -> Result<Option<&'a str>, Failure> {
+    if value.is_null() {
+        return Ok(None);
+    }
+    read_str(value, name).map(Some)
+}
+
+unsafe fn write_string(value: String, out: *mut *mut c_char) -> Result<(), Failure> {
+    if out.is_null() {
+        return Err(Failure::new(DcoreStatus::InvalidArgument, "The output pointer is null."));
+    }
+    let value = CString::new(value).map_err(|e| Failure::new(DcoreStatus::Error, e))?;
+    *out = value.into_raw();
+    Ok(())
+}
+
+unsafe fn handle<'a>(document: *mut DcoreDocument) -> Result<&'a mut DcoreDocument, Failure> {
+    document
+        .as_mut()
+        .ok_or_else(|| Failure::new(DcoreStatus::InvalidArgument, "The document is null."))
+}
+
+fn open_document(
+    directory: &str,
+    fingerprint: &str,
+    gpg_home: Option<&str>,
+) -> Result<Document, Failure> {
+    let directory = PathBuf::from(directory);
+    let name = directory
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(|| Failure::new(DcoreStatus::InvalidArgument, "The directory has no name."))?
+        .to_string();
+    Document::new(DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: fingerprint.to_string(),
+        gpg_home: gpg_home.map(PathBuf::from),
+    })
+    .map_err(error)
+}
+
+fn contents(document: &Document) -> BTreeMap<String, serde_json::Value> {
+    document
+        .resources
+        .iter()
+        .map(|(name, resource)| (name.clone(), resource.get_json()))
+        .collect()
+}
+
+impl DcoreDocument {
+    fn new(document: Document) -> *mut DcoreDocument {
+        Box::into_raw(Box::new(DcoreDocument {
+            document,
+            callback: None,
+            user_data: ptr::null_mut(),
+        }))
+    }
+
+    fn notify(&self, resources: impl IntoIterator<Item = String>) {
+        if let Some(callback) = self.callback {
+            for resource in resources {
+                if let Ok(resource) = CString::new(resource) {
+                    callback(resource.as_ptr(), self.user_data);
+                }
+            }
+        }
+    }
+}
+
+/// Opens the document in `directory` with the identity `fingerprint` from the gpg keyring in
+/// `gpg_home`, the default keyring if it is null. `DCORE_STATUS_NOT_FOUND` if there is no document,
+/// use `dcore_document_create` for new ones. The document has to be freed with `dcore_document_free`.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_document_open(
+    directory: *const c_char,
+    fingerprint: *const c_char,
+    gpg_home: *const c_char,
+    out: *mut *mut DcoreDocument,
+) -> DcoreStatus {
+    run(|| {
+        if out.is_null() {
+            return Err(Failure::new(DcoreStatus::InvalidArgument, "The output pointer is null."));
+        }
+        let directory = read_str(directory, "directory")?;
+        if !Path::new(directory).join(".data").is_dir() {
+            return Err(Failure::new(
+                DcoreStatus::NotFound,
+                format!("There is no document in {}.", directory),
+            ));
+        }
+        let mut document = open_document(
+            directory,
+            read_str(fingerprint, "fingerprint")?,
+            read_optional_str(gpg_home, "gpg_home")?,
+        )?;
+        document.load().map_err(error)?;
+        *out = DcoreDocument::new(document);
+        Ok(())
+    })
+}
+
+/// Creates a new document in `directory`, the identity `fingerprint` is its first member. Its key
+/// is taken from the gpg keyring in `gpg_home`, the default keyring if it is null.
+/// The document has to be freed with `dcore_document_free`.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_document_create(
+    directory: *const c_char,
+    fingerprint: *const c_char,
+    gpg_home: *const c_char,
+    out: *mut *mut DcoreDocument,
+) -> DcoreStatus {
+    run(|| {
+        if out.is_null() {
+            return Err(Failure::new(DcoreStatus::InvalidArgument, "The output pointer is null."));
+        }
+        let fingerprint = read_str(fingerprint, "fingerprint")?.to_string();
+        let document = open_document(
+            read_str(directory, "directory")?,
+            &fingerprint,
+            read_optional_str(gpg_home, "gpg_home")?,
+        )?;
+        let public_key = document.identity.get_armored_public_key().map_err(error)?;
+        let document = document.init(&fingerprint, &public_key).map_err(error)?;
+        *out = DcoreDocument::new(document);
+        Ok(())
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcore_document_free(document: *mut DcoreDocument) {
+    if !document.is_null() {
+        drop(Box::from_raw(document));
+    }
+}
+
+/// Writes the names of the resources as JSON array to `out_json`, free it with `dcore_string_free`.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_document_list_resources(
+    document: *mut DcoreDocument,
+    out_json: *mut *mut c_char,
+) -> DcoreStatus {
+    run(|| {
+        let handle = handle(document)?;
+        let mut resources: Vec<&String> = handle.document.resources.keys().collect();
+        resources.sort();
+        write_string(serde_json::json!(resources).to_string(), out_json)
+    })
+}
+
+#[no_mangle]
+pub unsafe extern "C" fn dcore_document_add_resource(
+    document: *mut DcoreDocument,
+    resource: *const c_char,
+) -> DcoreStatus {
+    run(|| {
+        let handle = handle(document)?;
+        let resource = read_str(resource, "resource")?.to_string();
+        handle.document.add_resource(resource.clone()).map_err(error)?;
+        handle.notify([resource]);
+        Ok(())
+    })
+}
+
+/// Writes the content of a resource as JSON object to `out_json`, free it with `dcore_string_free`.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_resource_get_json(
+    document: *mut DcoreDocument,
+    resource: *const c_char,
+    out_json: *mut *mut c_char,
+) -> DcoreStatus {
+    run(|| {
+        let handle = handle(document)?;
+        let resource = read_str(resource, "resource")?;
+        let json = match handle.document.resources.get(resource) {
+            Some(resource) => resource.get_json(),
+            None => return Err(Failure::new(DcoreStatus::NotFound, "Resource not found.")),
+        };
+        write_string(json.to_string(), out_json)
+    })
+}
+
+/// Sets the JSON value `json_value` at `key_path` in a resource, nested keys are separated by dots.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_resource_set_json(
+    document: *mut DcoreDocument,
+    resource: *const c_char,
+    key_path: *const c_char,
+    json_value: *const c_char,
+) -> DcoreStatus {
+    run(|| {
+        let handle = handle(document)?;
+        let resource = read_str(resource, "resource")?.to_string();
+        let key_path = read_str(key_path, "key_path")?;
+        let value: serde_json::Value = serde_json::from_str(read_str(json_value, "json_value")?)
+            .map_err(|e| Failure::new(DcoreStatus::InvalidArgument, e))?;
+        if !handle.document.resources.contains_key(&resource) {
+            return Err(Failure::new(DcoreStatus::NotFound, "Resource not found."));
+        }
+        handle
+            .document
+            .update_resource_with_json(&resource, key_path, &value)
+            .map_err(error)?;
+        handle.notify([resource]);
+        Ok(())
+    })
+}
+
+/// Syncs the document with its git remote. If `out_report` is not null, the sync report is
+/// written to it, free it with `dcore_string_free`. Rejected logs are reported as error.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_document_sync(
+    document: *mut DcoreDocument,
+    out_report: *mut *mut c_char,
+) -> DcoreStatus {
+    run(|| {
+        let handle = handle(document)?;
+        let before = contents(&handle.document);
+        let report = GitSync::sync(&handle.document).map_err(error)?;
+        handle.document.load().map_err(error)?;
+        let changed: Vec<String> = contents(&handle.document)
+            .into_iter()
+            .filter(|(name, content)| before.get(name) != Some(content))
+            .map(|(name, _)| name)
+            .collect();
+        handle.notify(changed);
+
+        if !out_report.is_null() {
+            write_string(report.to_string(), out_report)?;
+        }
+        if !report.is_success() {
+            return Err(Failure::new(DcoreStatus::Error, report));
+        }
+        Ok(())
+    })
+}
+
+/// Registers the callback for changes of resources made through this handle or brought in by
+/// `dcore_document_sync`, it replaces an earlier one. Pass NULL to remove it.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_document_set_update_callback(
+    document: *mut DcoreDocument,
+    callback: DcoreUpdateCallback,
+    user_data: *mut c_void,
+) -> DcoreStatus {
+    run(|| {
+        let handle = handle(document)?;
+        handle.callback = callback;
+        handle.user_data = user_data;
+        Ok(())
+    })
+}
+
+/// Frees a string returned by dcore.
+#[no_mangle]
+pub unsafe extern "C" fn dcore_string_free(value: *mut c_char) {
+    if !value.is_null() {
+        drop(CString::from_raw(value));
+    }
+}
+
+/// The message of the last error on this thread, NULL if the last call succeeded.
+/// It is valid until the next call on this thread and must not be freed.
+#[no_mangle]
+pub extern "C" fn dcore_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(ptr::null(), |message| message.as_ptr())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+
+    use std::ffi::{CStr, CString};
+    use std::os::raw::{c_char, c_void};
+    use std::path::PathBuf;
+    use std::ptr;
+
+    use dcore::test_utils::{create_test_gpg_home, get_test_key};
+
+    use crate::*;
+
+    extern "C" fn count_updates(_resource: *const c_char, user_data: *mut c_void) {
+        unsafe { *(user_data as *mut usize) += 1 };
+    }
+
+    unsafe fn take_string(value: *mut c_char) -> String {
+        let string = CStr::from_ptr(value).to_str().unwrap().to_string();
+        dcore_string_free(value);
+        string
+    }
+
+    #[test]
+    fn header_is_up_to_date() {
+        let generated = include_str!(concat!(env!("OUT_DIR"), "/dcore.h"));
+        assert_eq!(
+            include_str!("../include/dcore.h"),
+            generated,
+            "include/dcore.h is outdated, regenerate it with cbindgen, see build.rs"
+        );
+    }
+
+    #[test]
+    fn document_handles() {
+        let test_dir = "./.test/ffi/document_handles/";
+        let gpg_home = CString::new(create_test_gpg_home(test_dir).to_str().unwrap()).unwrap();
+        let directory = CString::new(format!("{}doc", test_dir)).unwrap();
+        let fingerprint = CString::new(get_test_key().fingerprint).unwrap();
+        let resource = CString::new("test").unwrap();
+
+        unsafe {
+            // a missing document is not created by opening it
+            let mut document = ptr::null_mut();
+            assert_eq!(
+                dcore_document_open(directory.as_ptr(), fingerprint.as_ptr(), gpg_home.as_ptr(), &mut document),
+                DcoreStatus::NotFound
+            );
+            assert!(document.is_null());
+            assert!(!dcore_last_error().is_null());
+            assert!(!PathBuf::from(format!("{}doc", test_dir)).exists());
+
+            assert_eq!(
+                dcore_document_create(directory.as_ptr(), fingerprint.as_ptr(), gpg_home.as_ptr(), &mut document),
+                DcoreStatus::Ok
+            );
+            let mut updates: usize = 0;
+            dcore_document_set_update_callback(document, Some(count_updates), &mut updates as *mut usize as *mut c_void);
+
+            assert_eq!(dcore_document_add_resource(document, resource.as_ptr()), DcoreStatus::Ok);
+            let key_path = CString::new("project.name").unwrap();
+            let value = CString::new(r#"{"short": "dcore"}"#).unwrap();
+            assert_eq!(
+                dcore_resource_set_json(document, resource.as_ptr(), key_path.as_ptr(), value.as_ptr()),
+                DcoreStatus::Ok
+            );
+            assert_eq!(updates, 2);
+
+            let invalid = CString::new("{").unwrap();
+            assert_eq!(
+                dcore_resource_set_json(document, resource.as_ptr(), key_path.as_ptr(), invalid.as_ptr()),
+                DcoreStatus::InvalidArgument
+            );
+            assert!(!dcore_last_error().is_null());
+            dcore_document_free(document);
+
+            // the changes are persisted
+            let mut document = ptr::null_mut();
+            assert_eq!(
+                dcore_document_open(directory.as_ptr(), fingerprint.as_ptr(), gpg_home.as_ptr(), &mut document),
+                DcoreStatus::Ok
+            );
+            assert!(dcore_last_error().is_null());
+            let mut json = ptr::null_mut();
+            assert_eq!(dcore_document_list_resources(document, &mut json), DcoreStatus::Ok);
+            assert_eq!(take_string(json), r#"["config","test"]"#);
+            assert_eq!(dcore_resource_get_json(document, resource.as_ptr(), &mut json), DcoreStatus::Ok);
+            assert_eq!(take_string(json), r#"{"project":{"name":{"short":"dcore"}}}"#);
+
+            let missing = CString::new("missing").unwrap();
+            assert_eq!(dcore_resource_get_json(document, missing.as_ptr(), &mut json), DcoreStatus::NotFound);
+            dcore_document_free(document);
+        }
+    }
+}
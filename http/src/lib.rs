@@ -0,0 +1,302 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::atomic::AtomicU64;
+use std::sync::Arc;
+
+use async_std::channel::{unbounded, Sender};
+use async_std::sync::Mutex;
+use dcore::sync_git::GitSync;
+use dcore::Document;
+use serde::{Deserialize, Serialize};
+use subtle::ConstantTimeEq;
+use tide::http::mime;
+use tide::{Body, Middleware, Next, Request, Response, StatusCode};
+use tide_websockets::WebSocket;
+
+mod y_websocket;
+
+/// Shared by all requests: the document, the token clients have to send, the open
+/// change streams and the y-websocket connections per resource.
+#[derive(Clone)]
+pub struct State {
+    document: Arc<Mutex<Document>>,
+    token: Arc<String>,
+    subscribers: Arc<Mutex<Vec<Sender<Change>>>>,
+    rooms: Arc<Mutex<HashMap<String, y_websocket::Room>>>,
+    next_connection_id: Arc<AtomicU64>,
+}
+
+impl State {
+    pub fn new(document: Document, token: String) -> State {
+        State {
+            document: Arc::new(Mutex::new(document)),
+            token: Arc::new(token),
+            subscribers: Arc::new(Mutex::new(Vec::new())),
+            rooms: Arc::new(Mutex::new(HashMap::new())),
+            next_connection_id: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Sends the change to every open change stream and forgets the closed ones.
+    async fn notify(&self, resources: Vec<String>) {
+        let mut subscribers = self.subscribers.lock().await;
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+        for resource in resources {
+            for subscriber in subscribers.iter() {
+                let _ = subscriber.try_send(Change {
+                    resource: resource.clone(),
+                });
+            }
+        }
+    }
+}
+
+/// Sent as `change` event on `GET /events` whenever a resource changed.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Change {
+    pub resource: String,
+}
+
+#[derive(Deserialize)]
+struct NewResource {
+    name: String,
+}
+
+#[derive(Deserialize)]
+struct NewValue {
+    value: String,
+}
+
+#[derive(Serialize)]
+struct SyncResult {
+    success: bool,
+    report: String,
+}
+
+/// The HTTP/JSON API of a document. Every request needs an `Authorization: Bearer {token}` header.
+/// The y-websocket endpoint also takes a `token` query parameter, browser WebSockets can not set
+/// headers.
+///
+/// - `GET /resources`: the names of the resources
+/// - `POST /resources` with `{"name": ...}`: adds a resource
+/// - `GET /resources/:resource`: the content of a resource
+/// - `PUT /resources/:resource/keys/:key` with `{"value": ...}`: sets a key,
+///   nested keys are separated by a dot like in `Document::update_resource_with_key_value`
+/// - `DELETE /resources/:resource/keys/:key`: removes a key
+/// - `POST /sync`: syncs with the git remote
+/// - `GET /events`: Server-Sent Events, a `change` event with `{"resource": ...}` per change
+/// - `GET /yjs/:resource`: a y-websocket endpoint, the resource is the room name
+pub fn app(state: State) -> tide::Server<State> {
+    let mut app = tide::with_state(state);
+    app.with(BearerAuth);
+    app.at("/resources").get(list_resources).post(add_resource);
+    app.at("/resources/:resource").get(get_resource);
+    app.at("/resources/:resource/keys/:key")
+        .put(set_key)
+        .delete(delete_key);
+    app.at("/sync").post(sync);
+    app.at("/events").get(tide::sse::endpoint(events));
+    app.at("/yjs/:resource").get(WebSocket::new(y_websocket::serve));
+    app
+}
+
+struct BearerAuth;
+
+impl BearerAuth {
+    /// Compares in constant time, such that the response time does not tell how much of a guessed
+    /// token is right.
+    fn is_token(token: &str, candidate: &str) -> bool {
+        token.as_bytes().ct_eq(candidate.as_bytes()).into()
+    }
+}
+
+#[tide::utils::async_trait]
+impl Middleware<State> for BearerAuth {
+    async fn handle(&self, req: Request<State>, next: Next<'_, State>) -> tide::Result {
+        let token = req.state().token.as_str();
+        let authorized = req
+            .header("Authorization")
+            .and_then(|value| value.last().as_str().strip_prefix("Bearer "))
+            .map_or(false, |candidate| Self::is_token(token, candidate))
+            || (req.url().path().starts_with("/yjs/")
+                && req
+                    .url()
+                    .query_pairs()
+                    .any(|(key, value)| key == "token" && Self::is_token(token, &value)));
+        if !authorized {
+            return error_response(StatusCode::Unauthorized, "Missing or invalid bearer token.");
+        }
+        Ok(next.run(req).await)
+    }
+}
+
+fn json_response(status: StatusCode, value: &impl Serialize) -> tide::Result {
+    let mut response = Response::new(status);
+    response.set_body(Body::from_json(value)?);
+    response.set_content_type(mime::JSON);
+    Ok(response)
+}
+
+fn error_response(status: StatusCode, message: &str) -> tide::Result {
+    json_response(status, &serde_json::json!({ "error": message }))
+}
+
+async fn list_resources(req: Request<State>) -> tide::Result {
+    let document = req.state().document.lock().await;
+    let mut resources: Vec<&String> = document.resources.keys().collect();
+    resources.sort();
+    json_response(StatusCode::Ok, &resources)
+}
+
+async fn add_resource(mut req: Request<State>) -> tide::Result {
+    let new_resource: NewResource = req.body_json().await?;
+    {
+        let mut document = req.state().document.lock().await;
+        if document.resources.contains_key(&new_resource.name) {
+            return error_response(StatusCode::Conflict, "The resource already exists.");
+        }
+        document.add_resource(new_resource.name.clone())?;
+    }
+    req.state().notify(vec![new_resource.name]).await;
+    Ok(Response::new(StatusCode::Created))
+}
+
+async fn get_resource(req: Request<State>) -> tide::Result {
+    let document = req.state().document.lock().await;
+    match document.resources.get(req.param("resource")?) {
+        Some(resource) => json_response(StatusCode::Ok, &resource.get_json()),
+        None => error_response(StatusCode::NotFound, "Resource not found."),
+    }
+}
+
+async fn set_key(mut req: Request<State>) -> tide::Result {
+    let new_value: NewValue = req.body_json().await?;
+    let resource = req.param("resource")?.to_string();
+    {
+        let mut document = req.state().document.lock().await;
+        if !document.resources.contains_key(&resource) {
+            return error_response(StatusCode::NotFound, "Resource not found.");
+        }
+        document.update_resource_with_key_value(&resource, req.param("key")?, &new_value.value)?;
+    }
+    req.state().notify(vec![resource]).await;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+async fn delete_key(req: Request<State>) -> tide::Result {
+    let resource = req.param("resource")?.to_string();
+    {
+        let mut document = req.state().document.lock().await;
+        if let Err(e) = document.delete_resource_key(&resource, req.param("key")?) {
+            return error_response(StatusCode::NotFound, &e.to_string());
+        }
+    }
+    req.state().notify(vec![resource]).await;
+    Ok(Response::new(StatusCode::NoContent))
+}
+
+/// The sync talks to the git remote on a thread of its own and the document is only locked to
+/// load what it fetched, such that other requests, change streams and rooms are served meanwhile.
+async fn sync(req: Request<State>) -> tide::Result {
+    let options = req.state().document.lock().await.reopen_options();
+    let report = async_std::task::spawn_blocking(move || GitSync::sync_reopened(options)).await?;
+    let changed = {
+        let mut document = req.state().document.lock().await;
+        let before = contents(&document);
+        document.load()?;
+        contents(&document)
+            .into_iter()
+            .filter(|(name, content)| before.get(name) != Some(content))
+            .map(|(name, _)| name)
+            .collect()
+    };
+    let result = SyncResult {
+        success: report.is_success(),
+        report: report.to_string(),
+    };
+    req.state().notify(changed).await;
+    json_response(StatusCode::Ok, &result)
+}
+
+fn contents(document: &Document) -> BTreeMap<String, serde_json::Value> {
+    document
+        .resources
+        .iter()
+        .map(|(name, resource)| (name.clone(), resource.get_json()))
+        .collect()
+}
+
+async fn events(req: Request<State>, sender: tide::sse::Sender) -> tide::Result<()> {
+    let (subscriber, changes) = unbounded();
+    req.state().subscribers.lock().await.push(subscriber);
+    while let Ok(change) = changes.recv().await {
+        sender
+            .send("change", serde_json::to_string(&change)?, None)
+            .await?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+
+    use dcore::test_utils::create_test_document;
+    use tide::http::{Method, Request, Response, Url};
+    use tide::StatusCode;
+
+    use crate::{app, State};
+
+    fn request(method: Method, path: &str, body: Option<serde_json::Value>) -> Request {
+        let mut request = Request::new(method, Url::parse("http://localhost").unwrap().join(path).unwrap());
+        request.insert_header("Authorization", "Bearer secret");
+        if let Some(body) = body {
+            request.set_body(tide::Body::from_json(&body).unwrap());
+        }
+        request
+    }
+
+    async fn json(mut response: Response) -> serde_json::Value {
+        response.body_json().await.unwrap()
+    }
+
+    #[async_std::test]
+    async fn resources_api() {
+        let document = create_test_document("./.test/http/resources_api/");
+        let app = app(State::new(document, "secret".to_string()));
+
+        let mut unauthorized = request(Method::Get, "/resources", None);
+        unauthorized.insert_header("Authorization", "Bearer wrong");
+        let response: Response = app.respond(unauthorized).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Unauthorized);
+        // the token only counts as query parameter for the y-websocket endpoint
+        let mut unauthorized = request(Method::Get, "/resources?token=secret", None);
+        unauthorized.remove_header("Authorization");
+        let response: Response = app.respond(unauthorized).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Unauthorized);
+
+        let body = serde_json::json!({"name": "test"});
+        let response: Response = app.respond(request(Method::Post, "/resources", Some(body))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Created);
+        let response: Response = app.respond(request(Method::Get, "/resources", None)).await.unwrap();
+        assert_eq!(json(response).await, serde_json::json!(["config", "test"]));
+
+        let body = serde_json::json!({"value": "1234"});
+        let response: Response = app
+            .respond(request(Method::Put, "/resources/test/keys/a.b", Some(body)))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NoContent);
+        let response: Response = app.respond(request(Method::Get, "/resources/test", None)).await.unwrap();
+        assert_eq!(json(response).await, serde_json::json!({"a": {"b": "1234"}}));
+
+        let response: Response = app
+            .respond(request(Method::Delete, "/resources/test/keys/a.b", None))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NoContent);
+        let response: Response = app.respond(request(Method::Get, "/resources/test", None)).await.unwrap();
+        assert_eq!(json(response).await, serde_json::json!({"a": {}}));
+
+        let response: Response = app.respond(request(Method::Get, "/resources/missing", None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::NotFound);
+    }
+}
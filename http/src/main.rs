@@ -0,0 +1,71 @@
+use std::error::Error;
+use std::path::PathBuf;
+
+use clap::Parser;
+use rand::distributions::Alphanumeric;
+use rand::Rng;
+
+use dcore::document::{Document, DocumentNewOptions};
+use dcore::Identity;
+use dcore_http::{app, State};
+
+/// Serve a document over HTTP/JSON
+///
+/// dcore-http -u FINGERPRINT -d ./doc --token secret
+#[derive(clap::Parser)]
+#[clap(
+    author = "Fabrizio Parrillo <fabrizio.parrillo@colomba.link>",
+    version = "v0.0.1"
+)]
+struct Args {
+    /// User identity fingerprint
+    #[clap(short, long)]
+    user_id_fingerprint: String,
+
+    /// Path to the document directory
+    #[clap(short, long)]
+    document_path: String,
+
+    /// Address to listen on, only reachable from this machine by default
+    #[clap(short, long, default_value = "127.0.0.1:8080")]
+    listen: String,
+
+    /// Bearer token clients have to send, a random one is generated and printed if not set
+    #[clap(short, long, env = "DCORE_HTTP_TOKEN")]
+    token: Option<String>,
+}
+
+#[async_std::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    let args = Args::parse();
+    let directory = PathBuf::from(&args.document_path);
+    let name = directory.file_name().unwrap().to_str().unwrap().to_string();
+
+    let identity = Identity::get_identity(dcore::identity::GetIdentityArgs {
+        keyring_home_dir: None,
+        fingerprint: args.user_id_fingerprint,
+    })
+        .expect("Failed to get identity with the provided fingerprint");
+
+    let doc_init_option = DocumentNewOptions {
+        directory,
+        name,
+        identity_fingerprint: identity.fingerprint.clone(),
+        gpg_home: None,
+    };
+    let mut doc = Document::new(doc_init_option).expect("Failed to create document");
+    doc.load().expect("Failed to load document");
+
+    let token = match args.token {
+        Some(token) => token,
+        None => {
+            let token: String = rand::thread_rng().sample_iter(&Alphanumeric).take(32).collect();
+            println!("Bearer token: {}", token);
+            token
+        }
+    };
+
+    println!("Listening on http://{}", args.listen);
+    app(State::new(doc, token)).listen(args.listen).await?;
+    Ok(())
+}
@@ -0,0 +1,376 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+
+use async_std::channel::{unbounded, Sender};
+use futures::{stream, StreamExt};
+use tide::{Request, StatusCode};
+use tide_websockets::{Message, WebSocketConnection};
+use yrs::updates::decoder::Decode;
+use yrs::updates::encoder::Encode;
+use yrs::StateVector;
+
+use crate::{Change, State};
+
+// y-protocols message types
+const MESSAGE_SYNC: u64 = 0;
+const MESSAGE_AWARENESS: u64 = 1;
+const MESSAGE_QUERY_AWARENESS: u64 = 3;
+const SYNC_STEP1: u64 = 0;
+const SYNC_STEP2: u64 = 1;
+const SYNC_UPDATE: u64 = 2;
+
+/// The connections to a resource and the awareness states (cursors, user names, ...) their
+/// Yjs clients announced. Awareness is only relayed, it is not part of the document.
+#[derive(Default)]
+pub(crate) struct Room {
+    connections: HashMap<u64, Sender<Vec<u8>>>,
+    /// Yjs client id -> (clock, JSON state)
+    states: HashMap<u64, (u64, String)>,
+}
+
+impl Room {
+    fn broadcast(&self, from: u64, message: &[u8]) {
+        for (id, connection) in &self.connections {
+            if *id != from {
+                let _ = connection.try_send(message.to_vec());
+            }
+        }
+    }
+
+    fn encode_states(&self) -> Option<Vec<u8>> {
+        if self.states.is_empty() {
+            return None;
+        }
+        let states = self.states.iter().map(|(client, (clock, state))| (*client, *clock, state.as_str()));
+        Some(encode_awareness(states))
+    }
+}
+
+/// What we know about the Yjs client of a connection.
+struct Client {
+    id: u64,
+    resource: String,
+    /// Set once the client sent its state vector, afterwards it gets every change of the
+    /// resource that it misses
+    state_vector: Option<StateVector>,
+    /// The awareness clients announced over this connection with their latest clock
+    awareness_clients: HashMap<u64, u64>,
+}
+
+enum Input {
+    Client(Result<Message, tide_websockets::Error>),
+    Change(Change),
+    Awareness(Vec<u8>),
+}
+
+/// A y-websocket connection to a resource, the resource name is the room name.
+///
+/// Updates of the client are applied with `Document::apply_client_update`, so they end up in
+/// our log as signed commits like any other local update.
+pub(crate) async fn serve(req: Request<State>, connection: WebSocketConnection) -> tide::Result<()> {
+    let state = req.state().clone();
+    let resource = req.param("resource")?.to_string();
+    let step1 = {
+        let document = state.document.lock().await;
+        match document.resources.get(&resource) {
+            Some(resource) => encode_sync(SYNC_STEP1, &resource.store.transact().state_vector().encode_v1()),
+            None => return Err(tide::Error::from_str(StatusCode::NotFound, "Resource not found.")),
+        }
+    };
+
+    let id = state.next_connection_id.fetch_add(1, Ordering::Relaxed);
+    let (change_sender, changes) = unbounded();
+    state.subscribers.lock().await.push(change_sender);
+    let (awareness_sender, awareness_messages) = unbounded();
+    let awareness = {
+        let mut rooms = state.rooms.lock().await;
+        let room = rooms.entry(resource.clone()).or_default();
+        room.connections.insert(id, awareness_sender);
+        room.encode_states()
+    };
+
+    let mut client = Client {
+        id,
+        resource,
+        state_vector: None,
+        awareness_clients: HashMap::new(),
+    };
+    let result = run(&state, &mut client, connection, step1, awareness, changes, awareness_messages).await;
+    leave(&state, &client).await;
+    result
+}
+
+async fn run(
+    state: &State,
+    client: &mut Client,
+    connection: WebSocketConnection,
+    step1: Vec<u8>,
+    awareness: Option<Vec<u8>>,
+    changes: async_std::channel::Receiver<Change>,
+    awareness_messages: async_std::channel::Receiver<Vec<u8>>,
+) -> tide::Result<()> {
+    // like a y-websocket server we start by asking for what we miss
+    connection.send_bytes(step1).await?;
+    if let Some(awareness) = awareness {
+        connection.send_bytes(awareness).await?;
+    }
+
+    let inputs = stream::select(
+        connection.clone().map(Input::Client),
+        stream::select(changes.map(Input::Change), awareness_messages.map(Input::Awareness)),
+    );
+    futures::pin_mut!(inputs);
+    while let Some(input) = inputs.next().await {
+        match input {
+            Input::Client(Ok(Message::Binary(message))) => {
+                if let Some(reply) = handle_message(state, client, &message).await? {
+                    connection.send_bytes(reply).await?;
+                }
+            }
+            Input::Client(Ok(Message::Close(_))) | Input::Client(Err(_)) => break,
+            Input::Client(Ok(_)) => {}
+            Input::Change(change) if change.resource == client.resource => {
+                if let Some(update) = missing_update(state, client).await {
+                    connection.send_bytes(encode_sync(SYNC_UPDATE, &update)).await?;
+                }
+            }
+            Input::Change(_) => {}
+            Input::Awareness(message) => connection.send_bytes(message).await?,
+        }
+    }
+    Ok(())
+}
+
+/// Handles a y-protocols message of the client and returns the reply, if there is one.
+async fn handle_message(state: &State, client: &mut Client, message: &[u8]) -> tide::Result<Option<Vec<u8>>> {
+    let mut decoder = Decoder::new(message);
+    match decoder.read_var()? {
+        MESSAGE_SYNC => match decoder.read_var()? {
+            SYNC_STEP1 => {
+                let state_vector = StateVector::decode_v1(decoder.read_buf()?)
+                    .map_err(|e| bad_request(format!("Invalid state vector: {:?}", e)))?;
+                client.state_vector = Some(state_vector);
+                let update = missing_update(state, client).await.unwrap_or_default();
+                Ok(Some(encode_sync(SYNC_STEP2, &update)))
+            }
+            SYNC_STEP2 | SYNC_UPDATE => {
+                let update = decoder.read_buf()?;
+                let committed = {
+                    let mut document = state.document.lock().await;
+                    document.apply_client_update(&client.resource, update)?
+                };
+                if committed.is_some() {
+                    state.notify(vec![client.resource.clone()]).await;
+                }
+                Ok(None)
+            }
+            _ => Ok(None),
+        },
+        MESSAGE_AWARENESS => {
+            let update = decoder.read_buf()?;
+            let mut update_decoder = Decoder::new(update);
+            let mut rooms = state.rooms.lock().await;
+            let room = rooms.entry(client.resource.clone()).or_default();
+            for _ in 0..update_decoder.read_var()? {
+                let awareness_client = update_decoder.read_var()?;
+                let clock = update_decoder.read_var()?;
+                let awareness_state = update_decoder.read_string()?;
+                client.awareness_clients.insert(awareness_client, clock);
+                if awareness_state == "null" {
+                    room.states.remove(&awareness_client);
+                } else {
+                    room.states.insert(awareness_client, (clock, awareness_state.to_string()));
+                }
+            }
+            room.broadcast(client.id, message);
+            Ok(None)
+        }
+        MESSAGE_QUERY_AWARENESS => {
+            let rooms = state.rooms.lock().await;
+            Ok(rooms.get(&client.resource).and_then(Room::encode_states))
+        }
+        _ => Ok(None),
+    }
+}
+
+/// The update with everything the client misses, None if it did not send its state vector yet.
+/// Afterwards the client is considered up to date.
+async fn missing_update(state: &State, client: &mut Client) -> Option<Vec<u8>> {
+    let state_vector = client.state_vector.as_ref()?;
+    let document = state.document.lock().await;
+    let transaction = document.resources.get(&client.resource)?.store.transact();
+    let current = transaction.state_vector();
+    // deletions do not show in the state vector, so the update is sent even if it is equal
+    let update = transaction.encode_state_as_update_v1(state_vector);
+    client.state_vector = Some(current);
+    Some(update)
+}
+
+/// Removes the connection from its room and tells the others that its awareness states are gone.
+async fn leave(state: &State, client: &Client) {
+    let mut rooms = state.rooms.lock().await;
+    if let Some(room) = rooms.get_mut(&client.resource) {
+        room.connections.remove(&client.id);
+        for awareness_client in client.awareness_clients.keys() {
+            room.states.remove(awareness_client);
+        }
+        if !client.awareness_clients.is_empty() {
+            let removed = client
+                .awareness_clients
+                .iter()
+                .map(|(awareness_client, clock)| (*awareness_client, clock + 1, "null"));
+            room.broadcast(client.id, &encode_awareness(removed));
+        }
+        if room.connections.is_empty() {
+            rooms.remove(&client.resource);
+        }
+    }
+}
+
+fn encode_sync(message_type: u64, payload: &[u8]) -> Vec<u8> {
+    let mut message = Vec::new();
+    write_var(&mut message, MESSAGE_SYNC);
+    write_var(&mut message, message_type);
+    write_buf(&mut message, payload);
+    message
+}
+
+fn encode_awareness<'a>(states: impl ExactSizeIterator<Item = (u64, u64, &'a str)>) -> Vec<u8> {
+    let mut update = Vec::new();
+    write_var(&mut update, states.len() as u64);
+    for (client, clock, state) in states {
+        write_var(&mut update, client);
+        write_var(&mut update, clock);
+        write_buf(&mut update, state.as_bytes());
+    }
+    let mut message = Vec::new();
+    write_var(&mut message, MESSAGE_AWARENESS);
+    write_buf(&mut message, &update);
+    message
+}
+
+fn bad_request(message: String) -> tide::Error {
+    tide::Error::from_str(StatusCode::BadRequest, message)
+}
+
+/// lib0 variable length unsigned integer: 7 bits per byte, the high bit marks that more follow.
+fn write_var(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push((value as u8 & 0x7f) | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// lib0 byte array (and string): the length as variable length integer, then the bytes.
+fn write_buf(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    write_var(buffer, bytes.len() as u64);
+    buffer.extend_from_slice(bytes);
+}
+
+struct Decoder<'a> {
+    buffer: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn new(buffer: &'a [u8]) -> Decoder<'a> {
+        Decoder { buffer, position: 0 }
+    }
+
+    fn read_var(&mut self) -> tide::Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let byte = *self
+                .buffer
+                .get(self.position)
+                .ok_or_else(|| bad_request("Truncated message.".to_string()))?;
+            self.position += 1;
+            if shift > 63 {
+                return Err(bad_request("Invalid variable length integer.".to_string()));
+            }
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte < 0x80 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn read_buf(&mut self) -> tide::Result<&'a [u8]> {
+        let length = self.read_var()? as usize;
+        let end = self
+            .position
+            .checked_add(length)
+            .filter(|end| *end <= self.buffer.len())
+            .ok_or_else(|| bad_request("Truncated message.".to_string()))?;
+        let bytes = &self.buffer[self.position..end];
+        self.position = end;
+        Ok(bytes)
+    }
+
+    fn read_string(&mut self) -> tide::Result<&'a str> {
+        std::str::from_utf8(self.read_buf()?).map_err(|e| bad_request(e.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use dcore::test_utils::create_test_document;
+    use yrs::updates::decoder::Decode;
+    use yrs::updates::encoder::Encode;
+    use yrs::{Doc, Update};
+
+    use crate::y_websocket::{
+        encode_sync, handle_message, Client, Decoder, MESSAGE_SYNC, SYNC_STEP1, SYNC_STEP2, SYNC_UPDATE,
+    };
+    use crate::State;
+
+    #[async_std::test]
+    async fn sync_with_a_yjs_client() {
+        let mut document = create_test_document("./.test/http/sync_with_a_yjs_client/");
+        document.add_resource("test".to_string()).unwrap();
+        document.update_resource_with_key_value("test", "entry", "1234").unwrap();
+        let state = State::new(document, "secret".to_string());
+        let mut client = Client {
+            id: 0,
+            resource: "test".to_string(),
+            state_vector: None,
+            awareness_clients: Default::default(),
+        };
+
+        // the client has nothing yet and gets the whole resource
+        let yjs = Doc::new();
+        let step1 = encode_sync(SYNC_STEP1, &yjs.transact().state_vector().encode_v1());
+        let reply = handle_message(&state, &mut client, &step1).await.unwrap().unwrap();
+        let mut decoder = Decoder::new(&reply);
+        assert_eq!(decoder.read_var().unwrap(), MESSAGE_SYNC);
+        assert_eq!(decoder.read_var().unwrap(), SYNC_STEP2);
+        let mut transaction = yjs.transact();
+        transaction.apply_update(Update::decode_v1(decoder.read_buf().unwrap()).unwrap());
+        transaction.commit();
+        assert_eq!(transaction.get_map("root").to_json().to_string(), "{entry: 1234}");
+
+        // an edit of the client is committed to our log
+        let mut transaction = yjs.transact();
+        let root = transaction.get_map("root");
+        root.insert(&mut transaction, "entry".to_string(), "from yjs".to_string());
+        let update = transaction.encode_update_v1();
+        transaction.commit();
+        let message = encode_sync(SYNC_UPDATE, &update);
+        assert!(handle_message(&state, &mut client, &message).await.unwrap().is_none());
+
+        let mut document = state.document.lock().await;
+        assert_eq!(
+            document.resources.get("test").unwrap().get_json(),
+            serde_json::json!({"entry": "from yjs"})
+        );
+        document.load().unwrap();
+        assert_eq!(
+            document.resources.get("test").unwrap().get_json(),
+            serde_json::json!({"entry": "from yjs"})
+        );
+    }
+}